@@ -1,9 +1,13 @@
-use fuselog_core::statediff::{StateDiffAction, StateDiffLog};
+use fuselog_core::statediff::{self, FidTable, StateDiffAction, StateDiffLog};
+use fuselog_core::{columnar, proto};
+use fuselog_core::wire::{self, Encoding};
 use log::{error, info, warn};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::os::unix::net::{UnixListener};
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::ffi::{CString, OsStr};
 use std::env;
 use std::fs;
 
@@ -26,7 +30,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::process::exit(1);
     }
 
-    let Some(diff_file) = env::args().skip(2).next() else {
+    let Some(diff_file) = env::args().nth(2) else {
         error!("Not enough arguments.");
         std::process::exit(1);
     };
@@ -50,6 +54,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     info!("Listening on socket: {}", sock_file);
 
+    let mut position: Option<StreamPosition> = None;
+    let mut fids = FidTable::default();
+
     // Continuous loop to accept connections
     for stream in listener.incoming() {
         match stream {
@@ -64,7 +71,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
 
                 // Process the data
-                match process_payload(&buffer, target_path) {
+                match process_payload(&buffer, target_path, &mut position, &mut fids) {
                     Ok(_) => {
                         // Optional: Send confirmation back to client
                         if let Err(e) = stream.write_all(b"CONFIRMED") {
@@ -81,7 +88,61 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-fn process_payload(buffer: &[u8], target_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+// How far into the primary's action stream this replica has got
+struct StreamPosition {
+    epoch: u64,
+    diff_seq: u64,
+    next_seq: u64,
+}
+
+// Whether the diff carries on from the last one applied. Ok(false) means it was
+// applied already and should be skipped. A snapshot brings every fid in use.
+fn check_sequence(log: &StateDiffLog, position: &Option<StreamPosition>, snapshot: bool) -> Result<bool, String> {
+    let Some(position) = position else {
+        info!("First diff seen: diff {} of epoch {}, actions {}..{}", log.diff_seq, log.epoch, log.first_seq, log.next_seq);
+        if log.diff_seq > 1 && !snapshot {
+            warn!("Fids introduced by the primary's earlier diffs are unknown here; actions using them will fail. Send the primary 'f' before fetching the next diff.");
+        }
+        return Ok(true);
+    };
+
+    if log.epoch != position.epoch {
+        warn!("Primary restarted or was cleared (epoch {} -> {}); continuing from its diff {}", position.epoch, log.epoch, log.diff_seq);
+        return Ok(true);
+    }
+    if log.diff_seq <= position.diff_seq {
+        warn!("Diff {} was already applied (last applied: {}); skipping it", log.diff_seq, position.diff_seq);
+        return Ok(false);
+    }
+    if log.first_seq != position.next_seq {
+        let problem = if log.first_seq > position.next_seq {
+            format!("actions {}..{} are missing", position.next_seq, log.first_seq)
+        } else {
+            format!("actions {}..{} overlap ones already applied", log.first_seq, position.next_seq)
+        };
+        let allow_gaps = env::var("FUSELOG_ALLOW_GAPS")
+            .is_ok_and(|val| val.to_lowercase() == "true" || val == "1");
+        if !allow_gaps {
+            // Later diffs won't follow on either; the operator has to resync
+            return Err(format!(
+                "Diff {} does not follow diff {}: {}. Bring the target back in line with the primary and send it 'c' to start a new epoch, or set FUSELOG_ALLOW_GAPS",
+                log.diff_seq, position.diff_seq, problem
+            ));
+        }
+        warn!("Diff {} does not follow diff {}: {}; applying it anyway", log.diff_seq, position.diff_seq, problem);
+        if !snapshot {
+            warn!("Fids introduced in the gap are unknown here until the primary is sent 'f'");
+        }
+    }
+    Ok(true)
+}
+
+fn process_payload(
+    buffer: &[u8],
+    target_path: &Path,
+    position: &mut Option<StreamPosition>,
+    fids: &mut FidTable,
+) -> Result<(), Box<dyn std::error::Error>> {
     info!("Received {} bytes of data", buffer.len());
 
     if buffer.is_empty() {
@@ -89,159 +150,219 @@ fn process_payload(buffer: &[u8], target_path: &Path) -> Result<(), Box<dyn std:
         return Ok(());
     }
 
-    let compression_header = buffer[0];
-    let bincode_slice = match compression_header {
-        b'd' => {
-            info!("Detected payload with dictionary.");
-            
-            if buffer.len() < 5 {
-                return Err("Invalid dictionary payload: too short".into());
-            }
-            
-            // Read dictionary length (4 bytes after header)
-            let dict_len = u32::from_le_bytes([
-                buffer[1], buffer[2], buffer[3], buffer[4]
-            ]) as usize;
-            
-            let dict_start = 5;
-            let dict_end = dict_start + dict_len;
-            
-            if buffer.len() < dict_end + 1 {
-                return Err("Invalid dictionary payload: truncated".into());
-            }
-            
-            let dict_data = &buffer[dict_start..dict_end];
-            
-            info!("Received {} byte dictionary", dict_len);
-            
-            // Create directory if it doesn't exist
-            if let Some(parent) = Path::new(CACHE_DICT_PATH).parent() {
-                std::fs::create_dir_all(parent)
-                    .map_err(|e| format!("Failed to create dictionary directory: {}", e))?;
-            }
-            
-            // Save dictionary to persistent location
-            std::fs::write(CACHE_DICT_PATH, dict_data)
-                .map_err(|e| format!("Failed to save dictionary: {}", e))?;
-            
-            info!("Dictionary saved to {}", CACHE_DICT_PATH);
-            
-            // Check for compressed data header
-            if buffer[dict_end] != b'z' {
-                return Err("Expected compressed data after dictionary".into());
-            }
-            
-            // Remaining data is compressed
-            let compressed_data = &buffer[dict_end + 1..];
-            
-            info!("Decompressing with dictionary...");
-            let mut decoder = zstd::stream::read::Decoder::with_dictionary(
-                std::io::Cursor::new(compressed_data),
-                dict_data
-            )?;
-            let mut decompressed = Vec::new();
-            decoder.read_to_end(&mut decompressed)?;
-            decompressed
-        }
-        b'z' => {
-            info!("Detected zstd compressed data.");
-            
-            // Try to load existing dictionary if available
-            match std::fs::read(CACHE_DICT_PATH) {
-                Ok(dict_data) => {
-                    info!("Found cached dictionary at {}, using it for decompression", CACHE_DICT_PATH);
-                    let compressed_data = &buffer[1..];
-                    let mut decoder = zstd::stream::read::Decoder::with_dictionary(
-                        std::io::Cursor::new(compressed_data),
-                        &dict_data
-                    )?;
-                    let mut decompressed = Vec::new();
-                    decoder.read_to_end(&mut decompressed)?;
-                    decompressed
-                }
-                Err(_) => {
-                    info!("No cached dictionary found, using standard decompression");
-                    zstd::decode_all(&buffer[1..])?
-                }
-            }
-        }
-        b'n' => {
-            info!("Detected raw data.");
-            buffer[1..].to_vec()
-        }
-        _ => {
-            return Err(format!("Unknown compression header: '{}'. Expected 'd', 'z', or 'n'.", compression_header as char).into());
-        }
+    let frame = wire::decode_frame(buffer).map_err(|e| format!("Rejected payload: {}", e))?;
+    info!("Frame version {}, flags 0x{:04x}, {} byte body", frame.version, frame.flags, frame.body.len());
+
+    let log = match Encoding::from_flags(frame.flags) {
+        Encoding::Bincode => wire::decode_log(&decompress_body(&frame)?)
+            .map_err(|e| format!("Failed to deserialize bincode data: {}", e))?,
+        Encoding::Protobuf => proto::decode_log(&decompress_body(&frame)?)
+            .map_err(|e| format!("Failed to decode protobuf data: {}", e))?,
+        // Its streams are compressed one by one
+        Encoding::Columnar => columnar::decode_log(frame.body, (frame.flags & wire::FLAG_ZSTD) != 0)
+            .map_err(|e| format!("Failed to decode columnar data: {}", e))?,
     };
+    
+    info!("Deserialized log with {} actions, {} new and {} retired file mappings",
+          log.actions.len(), log.fid_map.len(), log.retired_fids.len());
 
+    let snapshot = (frame.flags & wire::FLAG_FID_SNAPSHOT) != 0;
+    if !check_sequence(&log, position, snapshot)? {
+        return Ok(());
+    }
 
-    let (log, _): (StateDiffLog, usize) = bincode::decode_from_slice(
-        &bincode_slice, 
-        bincode::config::standard()
-    ).map_err(|e| format!("Failed to deserialize bincode data: {}", e))?;
-    
-    info!("Deserialized log with {} actions and {} file mappings", 
-          log.actions.len(), log.fid_map.len());
+    // Fids are only valid within the epoch that assigned them, and a snapshot
+    // holds all that are
+    if snapshot || position.as_ref().is_some_and(|p| p.epoch != log.epoch) {
+        *fids = FidTable::default();
+    }
+    for (fid, path) in &log.fid_map {
+        fids.insert(*fid, path.clone());
+    }
 
-    for (i, action) in log.actions.iter().enumerate() {
-        info!("Applying action {}/{}: {:?}", i + 1, log.actions.len(), action);
+    for (i, entry) in log.actions.iter().enumerate() {
+        let action = &entry.action;
+        info!("Applying action {}/{} (seq {}): {:?}", i + 1, log.actions.len(), entry.seq, action);
         
         match action {
             StateDiffAction::Create { fid, uid, gid, mode } => {
-                apply_create(&log, *fid, *uid, *gid, *mode, target_path)?;
+                apply_create(fids, *fid, *uid, *gid, *mode, target_path)?;
             }
             StateDiffAction::Write { fid, offset, data } => {
-                apply_write(&log, *fid, *offset, data, target_path)?;
+                apply_write(fids, *fid, *offset, data, target_path)?;
             }
             StateDiffAction::Unlink { fid } => {
-                apply_unlink(&log, *fid, target_path)?;
+                apply_unlink(fids, *fid, target_path)?;
             }
             StateDiffAction::Truncate { fid, size } => {
-                apply_truncate(&log, *fid, *size, target_path)?;
+                apply_truncate(fids, *fid, *size, target_path)?;
+            }
+            StateDiffAction::Rename { from_fid, to_fid, noreplace } => {
+                apply_rename(fids, *from_fid, *to_fid, *noreplace, target_path)?;
             }
-            StateDiffAction::Rename { from_fid, to_fid } => {
-                apply_rename(&log, *from_fid, *to_fid, target_path)?;
+            StateDiffAction::Exchange { a_fid, b_fid } => {
+                apply_exchange(fids, *a_fid, *b_fid, target_path)?;
             }
             StateDiffAction::Link { source_fid, new_link_fid } => {
-                apply_link(&log, *source_fid, *new_link_fid, target_path)?;
+                apply_link(fids, *source_fid, *new_link_fid, target_path)?;
             }
             StateDiffAction::Chown { fid, uid, gid } => {
-                apply_chown(&log, *fid, *uid, *gid, target_path)?;
+                apply_chown(fids, *fid, *uid, *gid, target_path)?;
             }
             StateDiffAction::Chmod { fid, mode } => {
-                apply_chmod(&log, *fid, *mode, target_path)?;
+                apply_chmod(fids, *fid, *mode, target_path)?;
             }
             StateDiffAction::Mkdir { fid } => {
-                apply_mkdir(&log, *fid, target_path)?;
+                apply_mkdir(fids, *fid, target_path)?;
             }
             StateDiffAction::Rmdir { fid } => {
-                apply_rmdir(&log, *fid, target_path)?;
+                apply_rmdir(fids, *fid, target_path)?;
+            }
+            StateDiffAction::Symlink { link_fid, target_path: symlink_target, uid, gid } => {
+                apply_symlink(fids, *link_fid, symlink_target, *uid, *gid, target_path)?;
+            }
+            StateDiffAction::SetXattr { fid, name, value } => {
+                apply_setxattr(fids, *fid, name, value, target_path)?;
+            }
+            StateDiffAction::RemoveXattr { fid, name } => {
+                apply_removexattr(fids, *fid, name, target_path)?;
             }
-            StateDiffAction::Symlink { link_fid, target_path: symlink_target_str, uid, gid } => {
-                apply_symlink(&log, *link_fid, symlink_target_str, *uid, *gid, target_path)?;
+            StateDiffAction::SetTimes { fid, atime, mtime } => {
+                apply_set_times(fids, *fid, *atime, *mtime, target_path)?;
+            }
+            StateDiffAction::Fallocate { fid, mode, offset, length } => {
+                apply_fallocate(fids, *fid, *mode, *offset, *length, target_path)?;
+            }
+            StateDiffAction::PunchHole { fid, offset, length } => {
+                apply_fallocate(
+                    fids,
+                    *fid,
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    *offset,
+                    *length,
+                    target_path,
+                )?;
+            }
+            StateDiffAction::CopyRange { src_fid, src_off, dst_fid, dst_off, len } => {
+                apply_copy_range(fids, *src_fid, *src_off, *dst_fid, *dst_off, *len, target_path)?;
+            }
+            StateDiffAction::Mknod { fid, mode, rdev, uid, gid } => {
+                apply_mknod(fids, *fid, *mode, *rdev, *uid, *gid, target_path)?;
+            }
+            StateDiffAction::CopyUp { fid, size } => {
+                apply_copy_up(fids, *fid, *size, target_path)?;
             }
         }
     }
 
     info!("Successfully applied all {} actions", log.actions.len());
+
+    for fid in &log.retired_fids {
+        fids.retire(*fid);
+    }
+    info!("{} file mappings in use", fids.len());
+
+    *position = Some(StreamPosition { epoch: log.epoch, diff_seq: log.diff_seq, next_seq: log.next_seq });
+
+    // Lag relies on the two hosts' clocks agreeing
+    let now = statediff::wall_time_ns();
+    let diff_age_ms = now.saturating_sub(log.sent_wall_time_ns) / 1_000_000;
+    match log.actions.last() {
+        Some(last) => info!(
+            "Diff {} applied {} ms after it was taken; replication lag {} ms",
+            log.diff_seq,
+            diff_age_ms,
+            now.saturating_sub(last.wall_time_ns) / 1_000_000
+        ),
+        None => info!("Diff {} applied {} ms after it was taken", log.diff_seq, diff_age_ms),
+    }
     Ok(())
 }
 
-fn get_full_path(log: &StateDiffLog, fid: u64, target_path: &Path) -> Result<PathBuf, String> {
-    let file_path = log.fid_map.get(&fid)
+// The frame's body with its compression undone
+fn decompress_body(frame: &wire::Frame) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let data = if (frame.flags & wire::FLAG_ZSTD) == 0 {
+        info!("Detected raw data.");
+        frame.body.to_vec()
+    } else if (frame.flags & wire::FLAG_DICT_INCLUDED) != 0 {
+        info!("Detected payload with dictionary.");
+
+        let body = frame.body;
+        if body.len() < 4 {
+            return Err("Invalid dictionary payload: too short".into());
+        }
+
+        // Read dictionary length (4 bytes at the start of the body)
+        let dict_len = u32::from_le_bytes([body[0], body[1], body[2], body[3]]) as usize;
+
+        let dict_start = 4;
+        let dict_end = dict_start + dict_len;
+
+        if body.len() < dict_end {
+            return Err("Invalid dictionary payload: truncated".into());
+        }
+
+        let dict_data = &body[dict_start..dict_end];
+
+        info!("Received {} byte dictionary", dict_len);
+
+        // Create directory if it doesn't exist
+        if let Some(parent) = Path::new(CACHE_DICT_PATH).parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create dictionary directory: {}", e))?;
+        }
+
+        // Save dictionary to persistent location
+        std::fs::write(CACHE_DICT_PATH, dict_data)
+            .map_err(|e| format!("Failed to save dictionary: {}", e))?;
+
+        info!("Dictionary saved to {}", CACHE_DICT_PATH);
+
+        // Remaining data is compressed
+        let compressed_data = &body[dict_end..];
+
+        info!("Decompressing with dictionary...");
+        let mut decoder = zstd::stream::read::Decoder::with_dictionary(
+            std::io::Cursor::new(compressed_data),
+            dict_data
+        )?;
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed)?;
+        decompressed
+    } else if (frame.flags & wire::FLAG_ZSTD_DICT) != 0 {
+        info!("Detected zstd data compressed with a dictionary.");
+
+        let dict_data = std::fs::read(CACHE_DICT_PATH)
+            .map_err(|e| format!("Payload needs the dictionary at {}, which can't be read: {}", CACHE_DICT_PATH, e))?;
+        let mut decoder = zstd::stream::read::Decoder::with_dictionary(
+            std::io::Cursor::new(frame.body),
+            &dict_data
+        )?;
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed)?;
+        decompressed
+    } else {
+        info!("Detected zstd compressed data.");
+        zstd::decode_all(frame.body)?
+    };
+    Ok(data)
+}
+
+fn get_full_path(fids: &FidTable, fid: u64, target_path: &Path) -> Result<PathBuf, String> {
+    let file_path = fids.path(fid)
         .ok_or_else(|| format!("Unknown file ID: {}", fid))?;
-    Ok(target_path.join(file_path))
+    Ok(target_path.join(OsStr::from_bytes(file_path)))
 }
 
 fn apply_create(
-    log: &StateDiffLog,
+    fids: &FidTable,
     fid: u64,
     uid: u32,
     gid: u32,
     mode: u32,
     target_path: &Path,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let full_path = get_full_path(log, fid, target_path)?;
+    let full_path = get_full_path(fids, fid, target_path)?;
 
     info!("Creating file {:?} with mode {:o} and owner {}:{}", full_path, mode, uid, gid);
 
@@ -260,13 +381,13 @@ fn apply_create(
 }
 
 fn apply_write(
-    log: &StateDiffLog, 
+    fids: &FidTable, 
     fid: u64, 
     offset: u64, 
     data: &[u8], 
     target_path: &Path
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let full_path = get_full_path(log, fid, target_path)?;
+    let full_path = get_full_path(fids, fid, target_path)?;
     
     if let Some(parent) = full_path.parent() {
         std::fs::create_dir_all(parent)?;
@@ -277,6 +398,7 @@ fn apply_write(
     let mut file = std::fs::OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(false)
         .open(&full_path)?;
     
     use std::io::Seek;
@@ -287,11 +409,11 @@ fn apply_write(
 }
 
 fn apply_unlink(
-    log: &StateDiffLog, 
+    fids: &FidTable, 
     fid: u64, 
     target_path: &Path
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let full_path = get_full_path(log, fid, target_path)?;
+    let full_path = get_full_path(fids, fid, target_path)?;
     info!("Removing file: {:?}", full_path);
     
     match std::fs::remove_file(&full_path) {
@@ -305,12 +427,12 @@ fn apply_unlink(
 }
 
 fn apply_truncate(
-    log: &StateDiffLog, 
+    fids: &FidTable, 
     fid: u64, 
     size: u64, 
     target_path: &Path
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let full_path = get_full_path(log, fid, target_path)?;
+    let full_path = get_full_path(fids, fid, target_path)?;
     
     if let Some(parent) = full_path.parent() {
         std::fs::create_dir_all(parent)?;
@@ -321,6 +443,7 @@ fn apply_truncate(
     let file = std::fs::OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(false)
         .open(&full_path)?;
     
     file.set_len(size)?;
@@ -329,32 +452,62 @@ fn apply_truncate(
 
 
 fn apply_rename(
-    log: &StateDiffLog, 
+    fids: &FidTable, 
     from_fid: u64, 
     to_fid: u64, 
+    noreplace: bool,
     target_path: &Path
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let full_from_path = get_full_path(log, from_fid, target_path)?;
-    let full_to_path = get_full_path(log, to_fid, target_path)?;
+    let full_from_path = get_full_path(fids, from_fid, target_path)?;
+    let full_to_path = get_full_path(fids, to_fid, target_path)?;
     
-    info!("Renaming {:?} to {:?}", full_from_path, full_to_path);
+    info!("Renaming {:?} to {:?} (noreplace: {})", full_from_path, full_to_path, noreplace);
     
     if let Some(parent) = full_to_path.parent() {
         std::fs::create_dir_all(parent)?;
     }
     
-    std::fs::rename(full_from_path, full_to_path)?;
+    if noreplace {
+        renameat2(&full_from_path, &full_to_path, libc::RENAME_NOREPLACE)?;
+    } else {
+        std::fs::rename(full_from_path, full_to_path)?;
+    }
+    Ok(())
+}
+
+fn apply_exchange(
+    fids: &FidTable,
+    a_fid: u64,
+    b_fid: u64,
+    target_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let full_a_path = get_full_path(fids, a_fid, target_path)?;
+    let full_b_path = get_full_path(fids, b_fid, target_path)?;
+
+    info!("Exchanging {:?} and {:?}", full_a_path, full_b_path);
+
+    renameat2(&full_a_path, &full_b_path, libc::RENAME_EXCHANGE)?;
+    Ok(())
+}
+
+fn renameat2(from: &Path, to: &Path, flags: u32) -> Result<(), Box<dyn std::error::Error>> {
+    let c_from = CString::new(from.as_os_str().as_bytes())?;
+    let c_to = CString::new(to.as_os_str().as_bytes())?;
+    let res = unsafe { libc::renameat2(libc::AT_FDCWD, c_from.as_ptr(), libc::AT_FDCWD, c_to.as_ptr(), flags) };
+    if res != 0 {
+        return Err(Box::new(std::io::Error::last_os_error()));
+    }
     Ok(())
 }
 
 fn apply_link(
-    log: &StateDiffLog,
+    fids: &FidTable,
     source_fid: u64,
     new_link_fid: u64,
     target_path: &Path,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let full_source_path = get_full_path(log, source_fid, target_path)?;
-    let full_new_link_path = get_full_path(log, new_link_fid, target_path)?;
+    let full_source_path = get_full_path(fids, source_fid, target_path)?;
+    let full_new_link_path = get_full_path(fids, new_link_fid, target_path)?;
 
     info!("Creating hard link from {:?} to {:?}", full_source_path, full_new_link_path);
 
@@ -367,13 +520,13 @@ fn apply_link(
 }
 
 fn apply_chown(
-    log: &StateDiffLog,
+    fids: &FidTable,
     fid: u64,
     uid: u32,
     gid: u32,
     target_path: &Path,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let full_path = get_full_path(log, fid, target_path)?;
+    let full_path = get_full_path(fids, fid, target_path)?;
 
     info!("Changing ownership of {:?} to {}:{}", full_path, uid, gid);
 
@@ -388,12 +541,12 @@ fn apply_chown(
 }
 
 fn apply_chmod(
-    log: &StateDiffLog,
+    fids: &FidTable,
     fid: u64,
     mode: u32,
     target_path: &Path,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let full_path = get_full_path(log, fid, target_path)?;
+    let full_path = get_full_path(fids, fid, target_path)?;
 
     info!("Changing mode of {:?} to {:o}", full_path, mode);
 
@@ -409,22 +562,22 @@ fn apply_chmod(
 }
 
 fn apply_mkdir(
-    log: &StateDiffLog,
+    fids: &FidTable,
     fid: u64,
     target_path: &Path,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let full_path = get_full_path(log, fid, target_path)?;
+    let full_path = get_full_path(fids, fid, target_path)?;
     info!("Creating directory: {:?}", full_path);
     std::fs::create_dir_all(&full_path)?;
     Ok(())
 }
 
 fn apply_rmdir(
-    log: &StateDiffLog,
+    fids: &FidTable,
     fid: u64,
     target_path: &Path,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let full_path = get_full_path(log, fid, target_path)?;
+    let full_path = get_full_path(fids, fid, target_path)?;
     info!("Removing directory: {:?}", full_path);
     match std::fs::remove_dir(&full_path) {
         Ok(_) => Ok(()),
@@ -437,23 +590,308 @@ fn apply_rmdir(
 }
 
 fn apply_symlink(
-    log: &StateDiffLog,
+    fids: &FidTable,
     link_fid: u64,
-    target_path_str: &str,
+    target_path_bytes: &[u8],
     uid: u32,
     gid: u32,
     base_target_path: &Path,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let full_link_path = get_full_path(log, link_fid, base_target_path)?;
+    let full_link_path = get_full_path(fids, link_fid, base_target_path)?;
+
+    let link_target = Path::new(OsStr::from_bytes(target_path_bytes));
 
-    info!("Creating symlink {:?} -> {} with owner {}:{}", full_link_path, target_path_str, uid, gid);
+    info!("Creating symlink {:?} -> {:?} with owner {}:{}", full_link_path, link_target, uid, gid);
 
     if let Some(parent) = full_link_path.parent() {
         std::fs::create_dir_all(parent)?;
     }
 
-    std::os::unix::fs::symlink(target_path_str, &full_link_path)?;
+    std::os::unix::fs::symlink(link_target, &full_link_path)?;
     std::os::unix::fs::lchown(&full_link_path, Some(uid), Some(gid))?;
     
     Ok(())
-}
\ No newline at end of file
+}
+
+fn apply_setxattr(
+    fids: &FidTable,
+    fid: u64,
+    name: &[u8],
+    value: &[u8],
+    target_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let full_path = get_full_path(fids, fid, target_path)?;
+
+    info!("Setting xattr {} ({} bytes) on {:?}", String::from_utf8_lossy(name), value.len(), full_path);
+
+    let c_path = CString::new(full_path.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+
+    // Flags are not replayed: the primary already enforced create/replace semantics
+    let res = unsafe {
+        libc::lsetxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0)
+    };
+    if res != 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() == std::io::ErrorKind::NotFound {
+            warn!("Cannot set xattr, file/dir does not exist: {:?}. This can be normal if it was deleted.", full_path);
+            return Ok(());
+        }
+        return Err(Box::new(e));
+    }
+    Ok(())
+}
+
+fn apply_removexattr(
+    fids: &FidTable,
+    fid: u64,
+    name: &[u8],
+    target_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let full_path = get_full_path(fids, fid, target_path)?;
+
+    info!("Removing xattr {} from {:?}", String::from_utf8_lossy(name), full_path);
+
+    let c_path = CString::new(full_path.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+
+    let res = unsafe { libc::lremovexattr(c_path.as_ptr(), c_name.as_ptr()) };
+    if res != 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() == std::io::ErrorKind::NotFound || e.raw_os_error() == Some(libc::ENODATA) {
+            warn!("Xattr {} to remove already doesn't exist on {:?}", String::from_utf8_lossy(name), full_path);
+            return Ok(());
+        }
+        return Err(Box::new(e));
+    }
+    Ok(())
+}
+
+fn apply_set_times(
+    fids: &FidTable,
+    fid: u64,
+    atime: Option<(i64, u32)>,
+    mtime: Option<(i64, u32)>,
+    target_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let full_path = get_full_path(fids, fid, target_path)?;
+
+    info!("Setting times of {:?} to atime={:?}, mtime={:?}", full_path, atime, mtime);
+
+    let to_timespec = |t: Option<(i64, u32)>| match t {
+        Some((secs, nsecs)) => libc::timespec { tv_sec: secs, tv_nsec: nsecs as i64 },
+        None => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+    };
+    let times = [to_timespec(atime), to_timespec(mtime)];
+
+    let c_path = CString::new(full_path.as_os_str().as_bytes())?;
+    let res = unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) };
+    if res != 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() == std::io::ErrorKind::NotFound {
+            warn!("Cannot set times, file/dir does not exist: {:?}. This can be normal if it was deleted.", full_path);
+            return Ok(());
+        }
+        return Err(Box::new(e));
+    }
+    Ok(())
+}
+
+fn apply_fallocate(
+    fids: &FidTable,
+    fid: u64,
+    mode: i32,
+    offset: u64,
+    length: u64,
+    target_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let full_path = get_full_path(fids, fid, target_path)?;
+
+    info!("Fallocating {:?}: offset={}, length={}, mode=0x{:x}", full_path, offset, length, mode);
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&full_path)?;
+
+    use std::os::fd::AsRawFd;
+    let res = unsafe { libc::fallocate(file.as_raw_fd(), mode, offset as i64, length as i64) };
+    if res == 0 {
+        return Ok(());
+    }
+
+    let e = std::io::Error::last_os_error();
+    let zeroing = (mode & (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_ZERO_RANGE)) != 0;
+    if e.raw_os_error() != Some(libc::EOPNOTSUPP) || !zeroing {
+        return Err(Box::new(e));
+    }
+
+    // The replica filesystem can't punch holes or zero ranges, so fall back to writing zeros.
+    // With KEEP_SIZE the range is clipped to the current end of file.
+    warn!("fallocate mode 0x{:x} unsupported on {:?}, writing zeros instead", mode, full_path);
+    let end = if (mode & libc::FALLOC_FL_KEEP_SIZE) != 0 {
+        (offset + length).min(file.metadata()?.len())
+    } else {
+        offset + length
+    };
+    if end > offset {
+        use std::io::Seek;
+        file.seek(std::io::SeekFrom::Start(offset))?;
+        let zeros = vec![0u8; 64 * 1024];
+        let mut remaining = end - offset;
+        while remaining > 0 {
+            let chunk = remaining.min(zeros.len() as u64) as usize;
+            file.write_all(&zeros[..chunk])?;
+            remaining -= chunk as u64;
+        }
+    }
+    Ok(())
+}
+
+fn apply_copy_range(
+    fids: &FidTable,
+    src_fid: u64,
+    src_off: u64,
+    dst_fid: u64,
+    dst_off: u64,
+    len: u64,
+    target_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let full_src_path = get_full_path(fids, src_fid, target_path)?;
+    let full_dst_path = get_full_path(fids, dst_fid, target_path)?;
+
+    info!("Copying {} bytes from {:?}@{} to {:?}@{}", len, full_src_path, src_off, full_dst_path, dst_off);
+
+    let src_file = std::fs::File::open(&full_src_path)?;
+    let mut dst_file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&full_dst_path)?;
+
+    use std::os::fd::AsRawFd;
+    let mut off_in = src_off as i64;
+    let mut off_out = dst_off as i64;
+    let mut remaining = len;
+    while remaining > 0 {
+        let copied = unsafe {
+            libc::copy_file_range(src_file.as_raw_fd(), &mut off_in, dst_file.as_raw_fd(), &mut off_out, remaining as usize, 0)
+        };
+        if copied < 0 {
+            let e = std::io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EXDEV) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) => break,
+                _ => return Err(Box::new(e)),
+            }
+        }
+        if copied == 0 {
+            break;
+        }
+        remaining -= copied as u64;
+    }
+
+    if remaining > 0 {
+        // Kernel copy unavailable or source shorter than expected; finish with read + write
+        use std::io::{Seek, SeekFrom};
+        let mut src_file = src_file;
+        src_file.seek(SeekFrom::Start(off_in as u64))?;
+        dst_file.seek(SeekFrom::Start(off_out as u64))?;
+        let copied = std::io::copy(&mut src_file.take(remaining), &mut dst_file)?;
+        if copied < remaining {
+            warn!("Source {:?} ended {} bytes short of the logged copy length", full_src_path, remaining - copied);
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_mknod(
+    fids: &FidTable,
+    fid: u64,
+    mode: u32,
+    rdev: u32,
+    uid: u32,
+    gid: u32,
+    target_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let full_path = get_full_path(fids, fid, target_path)?;
+
+    let file_type = mode & libc::S_IFMT;
+    if file_type == libc::S_IFCHR || file_type == libc::S_IFBLK {
+        // Device nodes are refused by default
+        let allow_devices = env::var("FUSELOG_ALLOW_DEVICE_NODES")
+            .is_ok_and(|val| val.to_lowercase() == "true" || val == "1");
+        if !allow_devices {
+            warn!("Refusing to create device node {:?} (rdev {}); set FUSELOG_ALLOW_DEVICE_NODES to allow it", full_path, rdev);
+            return Ok(());
+        }
+    }
+
+    info!("Creating node {:?} with mode {:o}, rdev {} and owner {}:{}", full_path, mode, rdev, uid, gid);
+
+    if let Some(parent) = full_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let c_path = CString::new(full_path.as_os_str().as_bytes())?;
+    let res = unsafe { libc::mknod(c_path.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) };
+    if res != 0 {
+        return Err(Box::new(std::io::Error::last_os_error()));
+    }
+
+    std::fs::set_permissions(&full_path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+    std::os::unix::fs::lchown(&full_path, Some(uid), Some(gid))?;
+
+    Ok(())
+}
+
+// The target normally starts as a copy of the base, in which case the file is
+// already there. Otherwise it is taken from FUSELOG_BASE_DIR, the replica's copy
+// of the lower directory.
+fn apply_copy_up(
+    fids: &FidTable,
+    fid: u64,
+    size: u64,
+    target_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let full_path = get_full_path(fids, fid, target_path)?;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(&full_path) {
+        if metadata.is_file() && metadata.len() != size {
+            warn!("Base copy {:?} is {} bytes, the primary's was {}", full_path, metadata.len(), size);
+        }
+        return Ok(());
+    }
+
+    let Some(base_dir) = env::var_os("FUSELOG_BASE_DIR") else {
+        return Err(format!("{:?} is not in the target and FUSELOG_BASE_DIR is not set", full_path).into());
+    };
+    let relative_path = fids.path(fid).ok_or_else(|| format!("Unknown file ID: {}", fid))?;
+    let base_path = Path::new(&base_dir).join(OsStr::from_bytes(relative_path));
+
+    let base = std::fs::metadata(&base_path)?;
+    if base.len() != size {
+        return Err(format!("Base file {:?} is {} bytes, the primary's was {}", base_path, base.len(), size).into());
+    }
+
+    info!("Copying base file {:?} to {:?}", base_path, full_path);
+
+    if let Some(parent) = full_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::copy(&base_path, &full_path)?;
+
+    // The primary's copy-up keeps the base file's owner and times as well as its mode
+    if let Err(e) = std::os::unix::fs::lchown(&full_path, Some(base.uid()), Some(base.gid())) {
+        warn!("Copy of {:?} could not keep its owner: {}", full_path, e);
+    }
+    let times = [
+        libc::timespec { tv_sec: base.atime(), tv_nsec: base.atime_nsec() },
+        libc::timespec { tv_sec: base.mtime(), tv_nsec: base.mtime_nsec() },
+    ];
+    let c_path = CString::new(full_path.as_os_str().as_bytes())?;
+    if unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+        return Err(Box::new(std::io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
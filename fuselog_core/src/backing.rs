@@ -0,0 +1,203 @@
+use crate::to_cstring;
+use fuser::FileType;
+use std::ffi::{CStr, OsStr, OsString};
+use std::fs::{File, Metadata};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+// The source directory, reached through a descriptor opened before the mount so
+// that it stays usable even when FUSE is mounted on top of it. Every path handed
+// to these methods is relative to it; lookups go through the *at syscalls rather
+// than the process's working directory.
+pub(crate) struct BackingDir {
+    fd: OwnedFd,
+}
+
+pub(crate) struct BackingDirEntry {
+    pub(crate) name: OsString,
+    // st_dev of the directory read and the entry's d_ino
+    pub(crate) dev: u64,
+    pub(crate) ino: u64,
+    pub(crate) kind: FileType,
+}
+
+fn c_path(path: &Path) -> io::Result<std::ffi::CString> {
+    to_cstring(path.as_os_str()).ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+fn check(res: libc::c_int) -> io::Result<()> {
+    if res < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
+
+impl BackingDir {
+    pub(crate) fn open(dir: &Path) -> io::Result<Self> {
+        let c_dir = c_path(dir)?;
+        let fd = unsafe { libc::open(c_dir.as_ptr(), libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC) };
+        check(fd)?;
+        Ok(Self { fd: unsafe { OwnedFd::from_raw_fd(fd) } })
+    }
+
+    fn dirfd(&self) -> libc::c_int {
+        self.fd.as_raw_fd()
+    }
+
+    // Opens path with raw open(2) flags; mode only matters with O_CREAT
+    pub(crate) fn open_file(&self, path: &Path, flags: i32, mode: u32) -> io::Result<File> {
+        let c_path = c_path(path)?;
+        let fd = unsafe { libc::openat(self.dirfd(), c_path.as_ptr(), flags | libc::O_CLOEXEC, mode as libc::c_uint) };
+        check(fd)?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    pub(crate) fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.open_file(path, libc::O_PATH | libc::O_NOFOLLOW, 0)?.metadata()
+    }
+
+    pub(crate) fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.open_file(path, libc::O_PATH, 0)?.metadata()
+    }
+
+    // The generation the filesystem gives the inode at path (FS_IOC_GETVERSION),
+    // which changes when an inode number is reused. Only asked of regular files
+    // and directories, as opening anything else may have side effects.
+    pub(crate) fn generation(&self, path: &Path, is_dir: bool) -> Option<u32> {
+        let flags = libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_NOCTTY;
+        let file = self.open_file(path, if is_dir { flags | libc::O_DIRECTORY } else { flags }, 0).ok()?;
+        let mut version: u32 = 0;
+        let res = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETVERSION, &mut version) };
+        (res == 0).then_some(version)
+    }
+
+    pub(crate) fn read_dir(&self, path: &Path) -> io::Result<Vec<BackingDirEntry>> {
+        let dir = self.open_file(path, libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+        let dev = dir.metadata()?.dev();
+        let stream = unsafe { libc::fdopendir(dir.as_raw_fd()) };
+        if stream.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        // The stream owns the descriptor from here on
+        std::mem::forget(dir);
+
+        let mut entries = Vec::new();
+        loop {
+            let entry = unsafe { libc::readdir64(stream) };
+            if entry.is_null() {
+                break;
+            }
+            let entry = unsafe { &*entry };
+            let name = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) }.to_bytes();
+            if name == b"." || name == b".." {
+                continue;
+            }
+            let name = OsStr::from_bytes(name).to_os_string();
+            let kind = match entry.d_type {
+                libc::DT_DIR => FileType::Directory,
+                libc::DT_REG => FileType::RegularFile,
+                libc::DT_LNK => FileType::Symlink,
+                libc::DT_FIFO => FileType::NamedPipe,
+                libc::DT_SOCK => FileType::Socket,
+                libc::DT_CHR => FileType::CharDevice,
+                libc::DT_BLK => FileType::BlockDevice,
+                // Not every filesystem fills in d_type
+                _ => self
+                    .symlink_metadata(&path.join(&name))
+                    .map_or(FileType::RegularFile, |m| crate::to_fuse_file_type(m.file_type())),
+            };
+            entries.push(BackingDirEntry { name, dev, ino: entry.d_ino, kind });
+        }
+        unsafe { libc::closedir(stream) };
+        Ok(entries)
+    }
+
+    pub(crate) fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        let c_path = c_path(path)?;
+        let mut buffer = vec![0u8; libc::PATH_MAX as usize];
+        loop {
+            let len = unsafe {
+                libc::readlinkat(self.dirfd(), c_path.as_ptr(), buffer.as_mut_ptr() as *mut libc::c_char, buffer.len())
+            };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let len = len as usize;
+            if len < buffer.len() {
+                buffer.truncate(len);
+                return Ok(PathBuf::from(OsStr::from_bytes(&buffer)));
+            }
+            // Possibly truncated; try again with more room
+            buffer.resize(buffer.len() * 2, 0);
+        }
+    }
+
+    pub(crate) fn create_dir(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let c_path = c_path(path)?;
+        check(unsafe { libc::mkdirat(self.dirfd(), c_path.as_ptr(), mode as libc::mode_t) })
+    }
+
+    pub(crate) fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let c_path = c_path(path)?;
+        check(unsafe { libc::unlinkat(self.dirfd(), c_path.as_ptr(), 0) })
+    }
+
+    pub(crate) fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let c_path = c_path(path)?;
+        check(unsafe { libc::unlinkat(self.dirfd(), c_path.as_ptr(), libc::AT_REMOVEDIR) })
+    }
+
+    pub(crate) fn mknod(&self, path: &Path, mode: u32, rdev: u32) -> io::Result<()> {
+        let c_path = c_path(path)?;
+        check(unsafe { libc::mknodat(self.dirfd(), c_path.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) })
+    }
+
+    pub(crate) fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        let (c_target, c_path) = (c_path(target)?, c_path(path)?);
+        check(unsafe { libc::symlinkat(c_target.as_ptr(), self.dirfd(), c_path.as_ptr()) })
+    }
+
+    pub(crate) fn hard_link(&self, source: &Path, dest: &Path) -> io::Result<()> {
+        let (c_source, c_dest) = (c_path(source)?, c_path(dest)?);
+        check(unsafe { libc::linkat(self.dirfd(), c_source.as_ptr(), self.dirfd(), c_dest.as_ptr(), 0) })
+    }
+
+    // renameat2(2); flags are RENAME_* bits
+    pub(crate) fn rename(&self, from: &Path, to: &Path, flags: u32) -> io::Result<()> {
+        let (c_from, c_to) = (c_path(from)?, c_path(to)?);
+        check(unsafe { libc::renameat2(self.dirfd(), c_from.as_ptr(), self.dirfd(), c_to.as_ptr(), flags) })
+    }
+
+    pub(crate) fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let c_path = c_path(path)?;
+        check(unsafe { libc::fchmodat(self.dirfd(), c_path.as_ptr(), mode as libc::mode_t, 0) })
+    }
+
+    // chown(2) or, with follow unset, lchown(2); None leaves that id unchanged
+    pub(crate) fn chown(&self, path: &Path, uid: Option<u32>, gid: Option<u32>, follow: bool) -> io::Result<()> {
+        let c_path = c_path(path)?;
+        let flags = if follow { 0 } else { libc::AT_SYMLINK_NOFOLLOW };
+        let uid = uid.unwrap_or(u32::MAX) as libc::uid_t;
+        let gid = gid.unwrap_or(u32::MAX) as libc::gid_t;
+        check(unsafe { libc::fchownat(self.dirfd(), c_path.as_ptr(), uid, gid, flags) })
+    }
+
+    // utimensat(2) on the entry itself, never a symlink's target
+    pub(crate) fn set_times(&self, path: &Path, times: &[libc::timespec; 2]) -> io::Result<()> {
+        let c_path = c_path(path)?;
+        check(unsafe { libc::utimensat(self.dirfd(), c_path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) })
+    }
+
+    pub(crate) fn statvfs(&self, path: &Path) -> io::Result<libc::statvfs> {
+        let file = self.open_file(path, libc::O_PATH, 0)?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        check(unsafe { libc::fstatvfs(file.as_raw_fd(), &mut stat) })?;
+        Ok(stat)
+    }
+
+    // A path that resolves to the entry through our descriptor, for the calls that
+    // have no *at variant (the l*xattr family)
+    pub(crate) fn proc_path(&self, path: &Path) -> PathBuf {
+        PathBuf::from(format!("/proc/self/fd/{}", self.dirfd())).join(path)
+    }
+}
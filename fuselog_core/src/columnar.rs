@@ -0,0 +1,501 @@
+use crate::proto::put_varint;
+use crate::statediff::{LoggedAction, StateDiffAction, StateDiffLog};
+use std::fmt;
+use std::io;
+
+// Columnar encoding of StateDiffLog. Rather than interleaving each action's small
+// fields with its write data, like bincode does, every kind of value goes to its
+// own stream, so each compresses well on its own:
+//
+//   header   varints: epoch, diff_seq, first_seq, next_seq, sent_wall_time_ns,
+//            then the number of fid_map entries, retired fids and actions
+//   opcodes  one byte per action, numbered as in proto/statediff.proto's oneof
+//   times    per action, zigzag varint deltas of seq, wall_time_ns and
+//            mono_time_ns from the previous action's (seq starts from first_seq)
+//   fids     varints: fid_map's fids in ascending order, the retired fids, then
+//            each action's fids
+//   offsets  varints: offsets, sizes and lengths
+//   attrs    varints: ids, modes, flags and times; signed ones zigzag encoded
+//   lengths  varints: the length of each entry in names and data
+//   names    fid_map's paths, symlink targets and xattr names, back to back
+//   data     write data and xattr values, back to back
+//
+// The body is a varint stream count, a varint length per stream, then the
+// streams in that order. With FLAG_ZSTD each non-empty stream is compressed on
+// its own, and the lengths are the compressed ones.
+
+const STREAMS: usize = 9;
+
+#[derive(Debug)]
+pub enum ColumnarError {
+    TooFewStreams(usize),
+    // A stream ran out, or had bytes left over, while decoding
+    Truncated(&'static str),
+    TrailingBytes(&'static str),
+    UnknownOpcode(u8),
+    Decompress(io::Error),
+}
+
+impl fmt::Display for ColumnarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooFewStreams(count) => write!(f, "columnar body has {} streams, {} expected", count, STREAMS),
+            Self::Truncated(stream) => write!(f, "columnar {} stream ended early", stream),
+            Self::TrailingBytes(stream) => write!(f, "columnar {} stream has bytes left over", stream),
+            Self::UnknownOpcode(opcode) => write!(f, "unknown action opcode {}", opcode),
+            Self::Decompress(e) => write!(f, "failed to decompress a stream: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ColumnarError {}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+#[derive(Default)]
+struct Writer {
+    header: Vec<u8>,
+    opcodes: Vec<u8>,
+    times: Vec<u8>,
+    fids: Vec<u8>,
+    offsets: Vec<u8>,
+    attrs: Vec<u8>,
+    lengths: Vec<u8>,
+    names: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn fid(&mut self, fid: u64) {
+        put_varint(&mut self.fids, fid);
+    }
+
+    fn offset(&mut self, value: u64) {
+        put_varint(&mut self.offsets, value);
+    }
+
+    fn attr(&mut self, value: u64) {
+        put_varint(&mut self.attrs, value);
+    }
+
+    fn name(&mut self, name: &[u8]) {
+        put_varint(&mut self.lengths, name.len() as u64);
+        self.names.extend_from_slice(name);
+    }
+
+    fn data(&mut self, data: &[u8]) {
+        put_varint(&mut self.lengths, data.len() as u64);
+        self.data.extend_from_slice(data);
+    }
+
+    fn action(&mut self, action: &StateDiffAction) {
+        let opcode = match action {
+            StateDiffAction::Create { fid, uid, gid, mode } => {
+                self.fid(*fid);
+                self.attr(*uid as u64);
+                self.attr(*gid as u64);
+                self.attr(*mode as u64);
+                1
+            }
+            StateDiffAction::Write { fid, offset, data } => {
+                self.fid(*fid);
+                self.offset(*offset);
+                self.data(data);
+                2
+            }
+            StateDiffAction::Unlink { fid } => {
+                self.fid(*fid);
+                3
+            }
+            StateDiffAction::Rename { from_fid, to_fid, noreplace } => {
+                self.fid(*from_fid);
+                self.fid(*to_fid);
+                self.attr(*noreplace as u64);
+                4
+            }
+            StateDiffAction::Exchange { a_fid, b_fid } => {
+                self.fid(*a_fid);
+                self.fid(*b_fid);
+                5
+            }
+            StateDiffAction::Truncate { fid, size } => {
+                self.fid(*fid);
+                self.offset(*size);
+                6
+            }
+            StateDiffAction::Link { source_fid, new_link_fid } => {
+                self.fid(*source_fid);
+                self.fid(*new_link_fid);
+                7
+            }
+            StateDiffAction::Chown { fid, uid, gid } => {
+                self.fid(*fid);
+                self.attr(*uid as u64);
+                self.attr(*gid as u64);
+                8
+            }
+            StateDiffAction::Chmod { fid, mode } => {
+                self.fid(*fid);
+                self.attr(*mode as u64);
+                9
+            }
+            StateDiffAction::Mkdir { fid } => {
+                self.fid(*fid);
+                10
+            }
+            StateDiffAction::Rmdir { fid } => {
+                self.fid(*fid);
+                11
+            }
+            StateDiffAction::Symlink { link_fid, target_path, uid, gid } => {
+                self.fid(*link_fid);
+                self.name(target_path);
+                self.attr(*uid as u64);
+                self.attr(*gid as u64);
+                12
+            }
+            StateDiffAction::SetXattr { fid, name, value } => {
+                self.fid(*fid);
+                self.name(name);
+                self.data(value);
+                13
+            }
+            StateDiffAction::RemoveXattr { fid, name } => {
+                self.fid(*fid);
+                self.name(name);
+                14
+            }
+            StateDiffAction::SetTimes { fid, atime, mtime } => {
+                self.fid(*fid);
+                // Each time is a presence flag, then seconds and nanoseconds if set
+                for time in [atime, mtime] {
+                    self.attr(time.is_some() as u64);
+                    if let Some((sec, nsec)) = time {
+                        self.attr(zigzag(*sec));
+                        self.attr(*nsec as u64);
+                    }
+                }
+                15
+            }
+            StateDiffAction::Fallocate { fid, mode, offset, length } => {
+                self.fid(*fid);
+                self.attr(zigzag(*mode as i64));
+                self.offset(*offset);
+                self.offset(*length);
+                16
+            }
+            StateDiffAction::PunchHole { fid, offset, length } => {
+                self.fid(*fid);
+                self.offset(*offset);
+                self.offset(*length);
+                17
+            }
+            StateDiffAction::CopyRange { src_fid, src_off, dst_fid, dst_off, len } => {
+                self.fid(*src_fid);
+                self.offset(*src_off);
+                self.fid(*dst_fid);
+                self.offset(*dst_off);
+                self.offset(*len);
+                18
+            }
+            StateDiffAction::Mknod { fid, mode, rdev, uid, gid } => {
+                self.fid(*fid);
+                self.attr(*mode as u64);
+                self.attr(*rdev as u64);
+                self.attr(*uid as u64);
+                self.attr(*gid as u64);
+                19
+            }
+            StateDiffAction::CopyUp { fid, size } => {
+                self.fid(*fid);
+                self.offset(*size);
+                20
+            }
+        };
+        self.opcodes.push(opcode);
+    }
+
+    fn streams(self) -> [Vec<u8>; STREAMS] {
+        [
+            self.header,
+            self.opcodes,
+            self.times,
+            self.fids,
+            self.offsets,
+            self.attrs,
+            self.lengths,
+            self.names,
+            self.data,
+        ]
+    }
+}
+
+// Encodes the log, compressing each stream at the given zstd level if one is set
+pub fn encode_log(log: &StateDiffLog, zstd_level: Option<i32>) -> io::Result<Vec<u8>> {
+    let mut w = Writer::default();
+    for value in [log.epoch, log.diff_seq, log.first_seq, log.next_seq, log.sent_wall_time_ns] {
+        put_varint(&mut w.header, value);
+    }
+    put_varint(&mut w.header, log.fid_map.len() as u64);
+    put_varint(&mut w.header, log.retired_fids.len() as u64);
+    put_varint(&mut w.header, log.actions.len() as u64);
+
+    let mut fids: Vec<_> = log.fid_map.iter().collect();
+    fids.sort_unstable_by_key(|(fid, _)| **fid);
+    for (fid, path) in fids {
+        w.fid(*fid);
+        w.name(path);
+    }
+    for fid in &log.retired_fids {
+        w.fid(*fid);
+    }
+
+    let (mut seq, mut wall, mut mono) = (log.first_seq, 0u64, 0u64);
+    for entry in &log.actions {
+        for (prev, value) in [(&mut seq, entry.seq), (&mut wall, entry.wall_time_ns), (&mut mono, entry.mono_time_ns)] {
+            put_varint(&mut w.times, zigzag(value.wrapping_sub(*prev) as i64));
+            *prev = value;
+        }
+        w.action(&entry.action);
+    }
+
+    let streams = w.streams();
+    let mut packed = Vec::with_capacity(STREAMS);
+    for stream in streams {
+        packed.push(match zstd_level {
+            Some(level) if !stream.is_empty() => zstd::encode_all(&stream[..], level)?,
+            _ => stream,
+        });
+    }
+
+    let mut body = Vec::new();
+    put_varint(&mut body, STREAMS as u64);
+    for stream in &packed {
+        put_varint(&mut body, stream.len() as u64);
+    }
+    for stream in &packed {
+        body.extend_from_slice(stream);
+    }
+    Ok(body)
+}
+
+struct Reader<'a> {
+    name: &'static str,
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: u64) -> Result<&'a [u8], ColumnarError> {
+        let len = usize::try_from(len).map_err(|_| ColumnarError::Truncated(self.name))?;
+        if self.buf.len() < len {
+            return Err(ColumnarError::Truncated(self.name));
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn varint(&mut self) -> Result<u64, ColumnarError> {
+        let mut value = 0u64;
+        for i in 0..10 {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(ColumnarError::Truncated(self.name))
+    }
+
+    fn u32(&mut self) -> Result<u32, ColumnarError> {
+        Ok(self.varint()? as u32)
+    }
+
+    fn finish(&self) -> Result<(), ColumnarError> {
+        if self.buf.is_empty() { Ok(()) } else { Err(ColumnarError::TrailingBytes(self.name)) }
+    }
+}
+
+struct Readers<'a> {
+    header: Reader<'a>,
+    opcodes: Reader<'a>,
+    times: Reader<'a>,
+    fids: Reader<'a>,
+    offsets: Reader<'a>,
+    attrs: Reader<'a>,
+    lengths: Reader<'a>,
+    names: Reader<'a>,
+    data: Reader<'a>,
+}
+
+impl<'a> Readers<'a> {
+    fn fid(&mut self) -> Result<u64, ColumnarError> {
+        self.fids.varint()
+    }
+
+    fn offset(&mut self) -> Result<u64, ColumnarError> {
+        self.offsets.varint()
+    }
+
+    fn name(&mut self) -> Result<&'a [u8], ColumnarError> {
+        let len = self.lengths.varint()?;
+        self.names.take(len)
+    }
+
+    fn data(&mut self) -> Result<&'a [u8], ColumnarError> {
+        let len = self.lengths.varint()?;
+        self.data.take(len)
+    }
+
+    fn time(&mut self) -> Result<Option<(i64, u32)>, ColumnarError> {
+        if self.attrs.varint()? == 0 {
+            return Ok(None);
+        }
+        Ok(Some((unzigzag(self.attrs.varint()?), self.attrs.u32()?)))
+    }
+
+    fn action(&mut self) -> Result<StateDiffAction, ColumnarError> {
+        let opcode = self.opcodes.take(1)?[0];
+        Ok(match opcode {
+            1 => StateDiffAction::Create {
+                fid: self.fid()?,
+                uid: self.attrs.u32()?,
+                gid: self.attrs.u32()?,
+                mode: self.attrs.u32()?,
+            },
+            2 => StateDiffAction::Write { fid: self.fid()?, offset: self.offset()?, data: self.data()?.to_vec() },
+            3 => StateDiffAction::Unlink { fid: self.fid()? },
+            4 => StateDiffAction::Rename { from_fid: self.fid()?, to_fid: self.fid()?, noreplace: self.attrs.varint()? != 0 },
+            5 => StateDiffAction::Exchange { a_fid: self.fid()?, b_fid: self.fid()? },
+            6 => StateDiffAction::Truncate { fid: self.fid()?, size: self.offset()? },
+            7 => StateDiffAction::Link { source_fid: self.fid()?, new_link_fid: self.fid()? },
+            8 => StateDiffAction::Chown { fid: self.fid()?, uid: self.attrs.u32()?, gid: self.attrs.u32()? },
+            9 => StateDiffAction::Chmod { fid: self.fid()?, mode: self.attrs.u32()? },
+            10 => StateDiffAction::Mkdir { fid: self.fid()? },
+            11 => StateDiffAction::Rmdir { fid: self.fid()? },
+            12 => StateDiffAction::Symlink {
+                link_fid: self.fid()?,
+                target_path: self.name()?.to_vec(),
+                uid: self.attrs.u32()?,
+                gid: self.attrs.u32()?,
+            },
+            13 => StateDiffAction::SetXattr { fid: self.fid()?, name: self.name()?.to_vec(), value: self.data()?.to_vec() },
+            14 => StateDiffAction::RemoveXattr { fid: self.fid()?, name: self.name()?.to_vec() },
+            15 => StateDiffAction::SetTimes { fid: self.fid()?, atime: self.time()?, mtime: self.time()? },
+            16 => StateDiffAction::Fallocate {
+                fid: self.fid()?,
+                mode: unzigzag(self.attrs.varint()?) as i32,
+                offset: self.offset()?,
+                length: self.offset()?,
+            },
+            17 => StateDiffAction::PunchHole { fid: self.fid()?, offset: self.offset()?, length: self.offset()? },
+            18 => StateDiffAction::CopyRange {
+                src_fid: self.fid()?,
+                src_off: self.offset()?,
+                dst_fid: self.fid()?,
+                dst_off: self.offset()?,
+                len: self.offset()?,
+            },
+            19 => StateDiffAction::Mknod {
+                fid: self.fid()?,
+                mode: self.attrs.u32()?,
+                rdev: self.attrs.u32()?,
+                uid: self.attrs.u32()?,
+                gid: self.attrs.u32()?,
+            },
+            20 => StateDiffAction::CopyUp { fid: self.fid()?, size: self.offset()? },
+            _ => return Err(ColumnarError::UnknownOpcode(opcode)),
+        })
+    }
+
+    fn finish(&self) -> Result<(), ColumnarError> {
+        for reader in [
+            &self.header,
+            &self.opcodes,
+            &self.times,
+            &self.fids,
+            &self.offsets,
+            &self.attrs,
+            &self.lengths,
+            &self.names,
+            &self.data,
+        ] {
+            reader.finish()?;
+        }
+        Ok(())
+    }
+}
+
+// Decodes a body, decompressing its streams first if it was sent with FLAG_ZSTD
+pub fn decode_log(body: &[u8], compressed: bool) -> Result<StateDiffLog, ColumnarError> {
+    let mut container = Reader { name: "container", buf: body };
+    let count = container.varint()?;
+    if count < STREAMS as u64 {
+        return Err(ColumnarError::TooFewStreams(count as usize));
+    }
+    let lengths = (0..count).map(|_| container.varint()).collect::<Result<Vec<_>, _>>()?;
+    // Streams past the ones this build knows are skipped
+    let mut streams = Vec::with_capacity(STREAMS);
+    for len in lengths {
+        let stream = container.take(len)?;
+        if streams.len() < STREAMS {
+            streams.push(if compressed && !stream.is_empty() {
+                zstd::decode_all(stream).map_err(ColumnarError::Decompress)?
+            } else {
+                stream.to_vec()
+            });
+        }
+    }
+    container.finish()?;
+
+    let reader = |i: usize, name: &'static str| Reader { name, buf: &streams[i] };
+    let mut r = Readers {
+        header: reader(0, "header"),
+        opcodes: reader(1, "opcodes"),
+        times: reader(2, "times"),
+        fids: reader(3, "fids"),
+        offsets: reader(4, "offsets"),
+        attrs: reader(5, "attrs"),
+        lengths: reader(6, "lengths"),
+        names: reader(7, "names"),
+        data: reader(8, "data"),
+    };
+
+    let mut log = StateDiffLog {
+        epoch: r.header.varint()?,
+        diff_seq: r.header.varint()?,
+        first_seq: r.header.varint()?,
+        next_seq: r.header.varint()?,
+        sent_wall_time_ns: r.header.varint()?,
+        ..Default::default()
+    };
+    let fid_count = r.header.varint()?;
+    let retired_count = r.header.varint()?;
+    let action_count = r.header.varint()?;
+
+    for _ in 0..fid_count {
+        let fid = r.fid()?;
+        log.fid_map.insert(fid, r.name()?.to_vec());
+    }
+    for _ in 0..retired_count {
+        log.retired_fids.push(r.fid()?);
+    }
+
+    let (mut seq, mut wall, mut mono) = (log.first_seq, 0u64, 0u64);
+    for _ in 0..action_count {
+        for prev in [&mut seq, &mut wall, &mut mono] {
+            *prev = prev.wrapping_add(unzigzag(r.times.varint()?) as u64);
+        }
+        let action = r.action()?;
+        log.actions.push(LoggedAction { seq, wall_time_ns: wall, mono_time_ns: mono, action });
+    }
+
+    r.finish()?;
+    Ok(log)
+}
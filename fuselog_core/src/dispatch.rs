@@ -0,0 +1,297 @@
+use crate::FuseLogFS;
+use fuser::{
+    Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyLock,
+    ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+};
+use log::{debug, error};
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+// fuser reads and dispatches requests on a single thread. The dispatcher answers
+// that thread right away and hands each request to a pool of workers, which call
+// into FuseLogFS and send the reply themselves. forget and init stay on the
+// session thread since they are cheap and have no reply to defer.
+//
+// fuser answers FUSE_INTERRUPT itself with ENOSYS, after which the kernel stops
+// sending them. A process killed while parked in F_SETLKW then waits for our
+// answer uninterruptibly, so a thread polls for such callers and fails their
+// requests with EINTR instead.
+
+const LOCK_INTERRUPT_POLL: Duration = Duration::from_millis(500);
+
+// What handlers need from a Request, which borrows the session's buffer
+pub(crate) struct Caller {
+    uid: u32,
+    gid: u32,
+}
+
+impl Caller {
+    pub(crate) fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub(crate) fn gid(&self) -> u32 {
+        self.gid
+    }
+}
+
+impl From<&Request<'_>> for Caller {
+    fn from(req: &Request<'_>) -> Self {
+        Self { uid: req.uid(), gid: req.gid() }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct WorkerPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..threads)
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                std::thread::Builder::new()
+                    .name(format!("fuselog-worker-{}", i))
+                    .spawn(move || loop {
+                        let job = receiver.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    })
+                    .expect("Failed to spawn FUSE worker thread")
+            })
+            .collect();
+
+        Self { sender: Some(sender), workers }
+    }
+
+    fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        if let Some(sender) = &self.sender
+            && sender.send(Box::new(job)).is_err()
+        {
+            error!("FUSE worker pool is gone; dropping request");
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Closing the channel lets each worker finish its queue and exit
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+pub struct Dispatcher {
+    fs: Arc<FuseLogFS>,
+    pool: WorkerPool,
+}
+
+impl Dispatcher {
+    pub fn new(fs: FuseLogFS, threads: usize) -> Self {
+        let threads = threads.max(1);
+        debug!("Dispatching FUSE requests to {} worker thread(s)", threads);
+        let fs = Arc::new(fs);
+        let weak = Arc::downgrade(&fs);
+        thread::spawn(move || {
+            while let Some(fs) = weak.upgrade() {
+                fs.interrupt_lock_waiters();
+                drop(fs);
+                thread::sleep(LOCK_INTERRUPT_POLL);
+            }
+        });
+        Self { fs, pool: WorkerPool::new(threads) }
+    }
+
+    fn run(&self, job: impl FnOnce(&FuseLogFS) + Send + 'static) {
+        let fs = Arc::clone(&self.fs);
+        self.pool.execute(move || job(&fs));
+    }
+}
+
+impl Filesystem for Dispatcher {
+    fn init(&mut self, req: &Request<'_>, config: &mut KernelConfig) -> Result<(), libc::c_int> {
+        self.fs.init(&Caller::from(req), config)
+    }
+
+    fn forget(&mut self, req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.fs.forget(&Caller::from(req), ino, nlookup);
+    }
+
+    fn batch_forget(&mut self, req: &Request<'_>, nodes: &[fuser::fuse_forget_one]) {
+        self.fs.batch_forget(&Caller::from(req), nodes);
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let (caller, name) = (Caller::from(req), name.to_owned());
+        self.run(move |fs| fs.lookup(&caller, parent, &name, reply));
+    }
+
+    fn getattr(&mut self, req: &Request<'_>, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.getattr(&caller, ino, fh, reply));
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.readlink(&caller, ino, reply));
+    }
+
+    fn readdir(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.readdir(&caller, ino, fh, offset, reply));
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.opendir(&caller, ino, flags, reply));
+    }
+
+    fn releasedir(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.releasedir(&caller, ino, fh, flags, reply));
+    }
+
+    fn fsyncdir(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.fsyncdir(&caller, ino, fh, datasync, reply));
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.statfs(&caller, ino, reply));
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.access(&caller, ino, mask, reply));
+    }
+
+    fn mknod(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, rdev: u32, reply: ReplyEntry) {
+        let (caller, name) = (Caller::from(req), name.to_owned());
+        self.run(move |fs| fs.mknod(&caller, parent, &name, mode, umask, rdev, reply));
+    }
+
+    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+        let (caller, name) = (Caller::from(req), name.to_owned());
+        self.run(move |fs| fs.mkdir(&caller, parent, &name, mode, umask, reply));
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (caller, name) = (Caller::from(req), name.to_owned());
+        self.run(move |fs| fs.rmdir(&caller, parent, &name, reply));
+    }
+
+    fn symlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+        let (caller, name, link) = (Caller::from(req), name.to_owned(), link.to_owned());
+        self.run(move |fs| fs.symlink(&caller, parent, &name, &link, reply));
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.open(&caller, ino, flags, reply));
+    }
+
+    fn create(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, flags: i32, reply: ReplyCreate) {
+        let (caller, name) = (Caller::from(req), name.to_owned());
+        self.run(move |fs| fs.create(&caller, parent, &name, mode, umask, flags, reply));
+    }
+
+    fn read(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, size: u32, flags: i32, lock_owner: Option<u64>, reply: ReplyData) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.read(&caller, ino, fh, offset, size, flags, lock_owner, reply));
+    }
+
+    fn write(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, data: &[u8], write_flags: u32, flags: i32, lock_owner: Option<u64>, reply: ReplyWrite) {
+        let (caller, data) = (Caller::from(req), data.to_owned());
+        self.run(move |fs| fs.write(&caller, ino, fh, offset, &data, write_flags, flags, lock_owner, reply));
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (caller, name) = (Caller::from(req), name.to_owned());
+        self.run(move |fs| fs.unlink(&caller, parent, &name, reply));
+    }
+
+    fn setattr(&mut self, req: &Request<'_>, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, ctime: Option<SystemTime>, fh: Option<u64>, crtime: Option<SystemTime>, chgtime: Option<SystemTime>, bkuptime: Option<SystemTime>, flags: Option<u32>, reply: ReplyAttr) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.setattr(&caller, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime, flags, reply));
+    }
+
+    fn release(&mut self, req: &Request<'_>, ino: u64, fh: u64, flags: i32, lock_owner: Option<u64>, flush: bool, reply: ReplyEmpty) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.release(&caller, ino, fh, flags, lock_owner, flush, reply));
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.flush(&caller, ino, fh, lock_owner, reply));
+    }
+
+    fn getlk(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, reply: ReplyLock) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.getlk(&caller, ino, fh, lock_owner, start, end, typ, pid, reply));
+    }
+
+    fn setlk(&mut self, req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.setlk(&caller, ino, fh, lock_owner, start, end, typ, pid, sleep, reply));
+    }
+
+    fn rename(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32, reply: ReplyEmpty) {
+        let (caller, name, newname) = (Caller::from(req), name.to_owned(), newname.to_owned());
+        self.run(move |fs| fs.rename(&caller, parent, &name, newparent, &newname, flags, reply));
+    }
+
+    fn link(&mut self, req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        let (caller, newname) = (Caller::from(req), newname.to_owned());
+        self.run(move |fs| fs.link(&caller, ino, newparent, &newname, reply));
+    }
+
+    fn fsync(&mut self, req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.fsync(&caller, ino, fh, datasync, reply));
+    }
+
+    fn setxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, value: &[u8], flags: i32, position: u32, reply: ReplyEmpty) {
+        let (caller, name, value) = (Caller::from(req), name.to_owned(), value.to_owned());
+        self.run(move |fs| fs.setxattr(&caller, ino, &name, &value, flags, position, reply));
+    }
+
+    fn getxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let (caller, name) = (Caller::from(req), name.to_owned());
+        self.run(move |fs| fs.getxattr(&caller, ino, &name, size, reply));
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.listxattr(&caller, ino, size, reply));
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (caller, name) = (Caller::from(req), name.to_owned());
+        self.run(move |fs| fs.removexattr(&caller, ino, &name, reply));
+    }
+
+    fn fallocate(&mut self, req: &Request<'_>, ino: u64, fh: u64, offset: i64, length: i64, mode: i32, reply: ReplyEmpty) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.fallocate(&caller, ino, fh, offset, length, mode, reply));
+    }
+
+    fn copy_file_range(&mut self, req: &Request<'_>, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u32, reply: ReplyWrite) {
+        let caller = Caller::from(req);
+        self.run(move |fs| fs.copy_file_range(&caller, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, reply));
+    }
+}
@@ -1,47 +1,99 @@
+mod backing;
+pub mod columnar;
+pub mod dispatch;
+mod locks;
+mod overlay;
+mod ownership;
+pub mod proto;
 pub mod socket;
+mod state;
 pub mod statediff;
+pub mod wire;
 
+use backing::{BackingDir, BackingDirEntry};
+use dispatch::Caller;
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyOpen, ReplyWrite, ReplyCreate, Request, TimeOrNow,
+    FileAttr, FileType, KernelConfig, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyCreate, ReplyXattr, TimeOrNow,
 };
 use libc::{ENOENT, EIO, EEXIST};
 use log::{debug, info, error, warn, trace};
 use bincode::{config, encode_to_vec};
-use statediff::{StateDiffAction, StateDiffLog};
+use locks::LockTable;
+use overlay::{Overlay, StagedCopy};
+use ownership::OwnershipStore;
+use state::{Journal, Record};
+use statediff::{FidTable, StateDiffAction, StateDiffLog};
 use std::collections::HashMap;
-use std::ffi::OsStr;
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::ffi::{CString, OsStr, OsString};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use std::time::{Duration, UNIX_EPOCH, SystemTime};
-use std::io::{Read, Seek, SeekFrom, Write, ErrorKind};
-use std::fs::{File, OpenOptions};
+use std::io::{Seek, Write, ErrorKind};
+use std::fs::File;
 
-const TTL: Duration = Duration::from_secs(1);
+const DEFAULT_TTL: Duration = Duration::from_secs(1);
 
-static STATEDIFF_LOG: once_cell::sync::Lazy<Arc<Mutex<StateDiffLog>>> = once_cell::sync::Lazy::new(|| Arc::new(Mutex::new(StateDiffLog::default())));
+static STATEDIFF_LOG: once_cell::sync::Lazy<Arc<Mutex<StateDiffLog>>> = once_cell::sync::Lazy::new(|| Arc::new(Mutex::new(StateDiffLog::new())));
 
-fn get_fid(log: &mut StateDiffLog, path: &str) -> u64 {
-    if let Some((fid, _)) = log.fid_map.iter().find(|(_, p)| p == &path) {
-        return *fid;
+// Only taken while holding STATEDIFF_LOG
+static FID_TABLE: once_cell::sync::Lazy<Mutex<FidTable>> = once_cell::sync::Lazy::new(|| Mutex::new(FidTable::default()));
+
+static LIVE_INODE_COUNT: AtomicU64 = AtomicU64::new(1);
+
+/// Number of inodes the mount currently keeps in memory, root included.
+pub fn live_inode_count() -> u64 {
+    LIVE_INODE_COUNT.load(Ordering::Relaxed)
+}
+
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Whether mutating calls are currently rejected with EROFS.
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+/// Switches read-only mode on or off; takes effect for the next request.
+pub fn set_read_only(read_only: bool) {
+    READ_ONLY.store(read_only, Ordering::Relaxed);
+    info!("Mount is now {}", if read_only { "read-only" } else { "read-write" });
+}
+
+fn get_fid(log: &mut StateDiffLog, path: &Path) -> u64 {
+    let path = path.as_os_str().as_bytes();
+    let (fid, is_new) = FID_TABLE.lock().unwrap().intern(path);
+    if is_new {
+        log.fid_map.insert(fid, path.to_vec());
     }
-    
-    let new_fid = log.fid_map.len() as u64 + 1;
-    log.fid_map.insert(new_fid, path.to_string());
-    new_fid
+    fid
 }
 
-fn metadata_to_file_attr(ino: u64, metadata: &std::fs::Metadata) -> FileAttr {
-    let file_type = if metadata.is_dir() {
+pub(crate) fn to_fuse_file_type(file_type: std::fs::FileType) -> FileType {
+    if file_type.is_dir() {
         FileType::Directory
-    } else if metadata.is_file() {
+    } else if file_type.is_file() {
         FileType::RegularFile
-    } else if metadata.file_type().is_symlink() {
+    } else if file_type.is_symlink() {
         FileType::Symlink
+    } else if file_type.is_fifo() {
+        FileType::NamedPipe
+    } else if file_type.is_socket() {
+        FileType::Socket
+    } else if file_type.is_char_device() {
+        FileType::CharDevice
+    } else if file_type.is_block_device() {
+        FileType::BlockDevice
     } else {
-        FileType::RegularFile 
-    };
+        FileType::RegularFile
+    }
+}
+
+fn metadata_to_file_attr(ino: u64, metadata: &std::fs::Metadata) -> FileAttr {
+    let file_type = to_fuse_file_type(metadata.file_type());
 
     FileAttr {
         ino,
@@ -62,87 +114,909 @@ fn metadata_to_file_attr(ino: u64, metadata: &std::fs::Metadata) -> FileAttr {
     }
 }
 
+pub(crate) fn to_cstring(s: &OsStr) -> Option<CString> {
+    CString::new(s.as_bytes()).ok()
+}
+
+fn last_errno() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(EIO)
+}
+
+fn time_or_now_to_timespec(time: Option<TimeOrNow>) -> libc::timespec {
+    match time {
+        None => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+        Some(TimeOrNow::Now) => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW },
+        Some(TimeOrNow::SpecificTime(t)) => {
+            let (secs, nsecs) = match t.duration_since(UNIX_EPOCH) {
+                Ok(d) => (d.as_secs() as i64, d.subsec_nanos() as i64),
+                Err(e) => {
+                    // Pre-epoch times are negative; keep tv_nsec in [0, 1e9)
+                    let d = e.duration();
+                    if d.subsec_nanos() == 0 {
+                        (-(d.as_secs() as i64), 0)
+                    } else {
+                        (-(d.as_secs() as i64) - 1, 1_000_000_000 - d.subsec_nanos() as i64)
+                    }
+                }
+            };
+            libc::timespec { tv_sec: secs, tv_nsec: nsecs }
+        }
+    }
+}
+
+// How inode numbers are assigned to backing files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InodeMode {
+    // Sequential numbers from 2 upwards; they change across remounts
+    Sequential,
+    // The backing st_ino, so numbers are stable and match the backing store
+    Backing,
+}
+
+// Backing mode hands out numbers from here when st_ino can't be used as is
+// (other device, already taken, or out of range)
+const FALLBACK_INO_BASE: u64 = 1 << 63;
+
+// Backing mode keeps its generations and fallback numbers in this journal, as
+// [RECORD_GENERATION, ino, generation, 0] and [RECORD_FALLBACK, st_dev, st_ino, ino]
+const INODE_JOURNAL: &str = "inodes";
+const RECORD_GENERATION: u64 = 1;
+const RECORD_FALLBACK: u64 = 2;
+
+// What readdir reports for an entry whose inode isn't known yet, as libfuse's
+// FUSE_UNKNOWN_INO. 0 would make glibc skip the entry.
+const UNKNOWN_INO: u64 = 0xffff_ffff;
+
+struct InodeEntry {
+    // Every name this inode is known under; the first one is used for I/O
+    paths: Vec<PathBuf>,
+    // (st_dev, st_ino) of the backing file, so further hard links resolve to the same inode
+    backing: Option<(u64, u64)>,
+    // The backing filesystem's generation for the file in backing mode, 0 if it has none
+    backing_generation: u32,
+}
+
 struct InodeManager {
-    ino_to_path: HashMap<u64, PathBuf>,
+    entries: HashMap<u64, InodeEntry>,
     path_to_ino: HashMap<PathBuf, u64>,
+    backing_to_ino: HashMap<(u64, u64), u64>,
+    // How many times each inode has been handed to the kernel without a matching forget
+    lookup_counts: HashMap<u64, u64>,
+    // Bumped whenever a backing inode number is freed, so a reused number is
+    // distinguishable from the file that had it before
+    generations: HashMap<u64, u64>,
+    // Fallback numbers by backing (st_dev, st_ino), so a file keeps its number
+    fallbacks: HashMap<(u64, u64), u64>,
+    // Where backing mode persists generations and fallbacks across remounts
+    journal: Option<Journal>,
+    backing_dir: Arc<BackingDir>,
+    overlay: Option<Arc<Overlay>>,
+    mode: InodeMode,
+    root_dev: u64,
     next_ino: u64,
 }
 
 impl InodeManager {
-    fn new() -> Self {
+    fn new(backing_dir: Arc<BackingDir>, overlay: Option<Arc<Overlay>>, mode: InodeMode, state_dir: &Path) -> Self {
+        let root_dev = backing_dir.symlink_metadata(Path::new(".")).map(|m| m.dev()).unwrap_or(0);
+        let next_ino = match mode {
+            InodeMode::Sequential => 2,
+            InodeMode::Backing => FALLBACK_INO_BASE,
+        };
         let mut manager = Self {
-            ino_to_path: HashMap::new(),
+            entries: HashMap::new(),
             path_to_ino: HashMap::new(),
-            next_ino: 2,
+            backing_to_ino: HashMap::new(),
+            lookup_counts: HashMap::new(),
+            generations: HashMap::new(),
+            fallbacks: HashMap::new(),
+            journal: None,
+            backing_dir,
+            overlay,
+            mode,
+            root_dev,
+            next_ino,
         };
         
         let root_path = PathBuf::from(".");
-        manager.ino_to_path.insert(1, root_path.clone());
+        manager.entries.insert(1, InodeEntry { paths: vec![root_path.clone()], backing: None, backing_generation: 0 });
         manager.path_to_ino.insert(root_path, 1);
-        
+
+        if mode == InodeMode::Backing {
+            manager.load_state(state_dir);
+        }
         manager
     }
+
+    // Picks up the generations and fallback numbers of earlier mounts
+    fn load_state(&mut self, state_dir: &Path) {
+        let (journal, records) = match Journal::open(state_dir, INODE_JOURNAL) {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!("Can't open inode state in {:?}, so numbers and generations won't survive a remount: {}", state_dir, e);
+                return;
+            }
+        };
+        for [kind, a, b, c] in records {
+            match kind {
+                RECORD_GENERATION => {
+                    self.generations.insert(a, b);
+                }
+                RECORD_FALLBACK => {
+                    self.fallbacks.insert((a, b), c);
+                }
+                _ => warn!("Skipping inode state record of unknown kind {}", kind),
+            }
+        }
+        // Fallbacks are never dropped, so numbers above them were never handed out
+        self.next_ino = self.fallbacks.values().max().map_or(FALLBACK_INO_BASE, |&ino| ino + 1);
+        info!("Loaded {} generations and {} fallback inode numbers from {:?}", self.generations.len(), self.fallbacks.len(), state_dir);
+
+        let live: Vec<Record> = self.generations.iter()
+            .map(|(&ino, &generation)| [RECORD_GENERATION, ino, generation, 0])
+            .chain(self.fallbacks.iter().map(|(&(dev, st_ino), &ino)| [RECORD_FALLBACK, dev, st_ino, ino]))
+            .collect();
+        if let Err(e) = journal.compact(&live) {
+            warn!("Failed to compact inode state in {:?}: {}", state_dir, e);
+        }
+        self.journal = Some(journal);
+    }
+
+    fn record(&self, record: Record) {
+        if let Some(journal) = &self.journal {
+            journal.append(record);
+        }
+    }
     
     fn get_path(&self, ino: u64) -> Option<&PathBuf> {
-        self.ino_to_path.get(&ino)
+        self.entries.get(&ino)?.paths.first()
+    }
+
+    fn live_count(&self) -> usize {
+        self.entries.len()
     }
     
     fn get_or_create_ino(&mut self, path: &Path) -> u64 {
         if let Some(&ino) = self.path_to_ino.get(path) {
             return ino;
         }
+
+        let layer = match &self.overlay {
+            Some(overlay) => overlay.layer(path),
+            None => &self.backing_dir,
+        };
+        let metadata = layer.symlink_metadata(path).ok();
+        let identity = metadata.as_ref().map(|m| (m.dev(), m.ino()));
+        let backing_generation = match &metadata {
+            Some(m) if self.mode == InodeMode::Backing && (m.is_file() || m.is_dir()) => {
+                layer.generation(path, m.is_dir()).unwrap_or(0)
+            }
+            _ => 0,
+        };
+
+        // Directories cannot be hard linked, so only other file types are keyed by backing identity
+        let backing = identity.filter(|_| metadata.as_ref().is_some_and(|m| !m.is_dir()));
+
+        if let Some(&ino) = backing.as_ref().and_then(|key| self.backing_to_ino.get(key))
+            && self.add_link(ino, path)
+        {
+            return ino;
+        }
         
+        let ino = self.allocate_ino(identity);
+        self.entries.insert(ino, InodeEntry { paths: vec![path.to_path_buf()], backing, backing_generation });
+        self.path_to_ino.insert(path.to_path_buf(), ino);
+        if let Some(key) = backing {
+            self.backing_to_ino.insert(key, ino);
+        }
+        self.publish_live_count();
+        ino
+    }
+
+    fn allocate_ino(&mut self, identity: Option<(u64, u64)>) -> u64 {
+        if self.mode == InodeMode::Backing
+            && let Some((dev, st_ino)) = identity
+        {
+            if let Some(ino) = self.predicted_ino(dev, st_ino) {
+                return ino;
+            }
+            debug!("Backing inode ({}, {}) can't be exposed as is; using a fallback number", dev, st_ino);
+            let ino = self.next_ino;
+            self.next_ino += 1;
+            self.fallbacks.insert((dev, st_ino), ino);
+            self.record([RECORD_FALLBACK, dev, st_ino, ino]);
+            return ino;
+        }
+
         let ino = self.next_ino;
         self.next_ino += 1;
-        self.ino_to_path.insert(ino, path.to_path_buf());
+        ino
+    }
+
+    // The number backing mode gives (st_dev, st_ino) without allocating one: the
+    // fallback it had before, or st_ino itself
+    fn predicted_ino(&self, dev: u64, st_ino: u64) -> Option<u64> {
+        if let Some(&ino) = self.fallbacks.get(&(dev, st_ino))
+            && !self.entries.contains_key(&ino)
+        {
+            return Some(ino);
+        }
+        self.can_expose(dev, st_ino).then_some(st_ino)
+    }
+
+    // Whether backing mode may hand out st_ino itself for a new inode
+    fn can_expose(&self, dev: u64, st_ino: u64) -> bool {
+        dev == self.root_dev && st_ino > 1 && st_ino < FALLBACK_INO_BASE && !self.entries.contains_key(&st_ino)
+    }
+
+    // The number readdir reports for an entry lookup hasn't cached: the inode it
+    // would resolve to where that is known without allocating, UNKNOWN_INO otherwise
+    fn listed_ino(&self, entry: &BackingDirEntry) -> u64 {
+        let key = (entry.dev, entry.ino);
+        if entry.kind != FileType::Directory
+            && let Some(&ino) = self.backing_to_ino.get(&key)
+        {
+            return ino;
+        }
+        if self.mode == InodeMode::Backing
+            && let Some(ino) = self.predicted_ino(entry.dev, entry.ino)
+        {
+            return ino;
+        }
+        UNKNOWN_INO
+    }
+
+    // How often the number was freed, over the backing filesystem's own generation
+    fn generation(&self, ino: u64) -> u64 {
+        let reuses = self.generations.get(&ino).copied().unwrap_or(0);
+        let backing = self.entries.get(&ino).map_or(0, |e| e.backing_generation);
+        (reuses << 32) | backing as u64
+    }
+
+    // For every entry replied to the kernel; each one is later balanced by a forget
+    fn lookup_ino(&mut self, path: &Path) -> u64 {
+        let ino = self.get_or_create_ino(path);
+        self.add_lookup(ino);
+        ino
+    }
+
+    fn add_lookup(&mut self, ino: u64) {
+        *self.lookup_counts.entry(ino).or_insert(0) += 1;
+    }
+
+    // Records another name for an existing inode. Returns false if the inode is unknown.
+    fn add_link(&mut self, ino: u64, path: &Path) -> bool {
+        let Some(entry) = self.entries.get_mut(&ino) else {
+            return false;
+        };
+        if !entry.paths.iter().any(|p| p == path) {
+            entry.paths.push(path.to_path_buf());
+        }
         self.path_to_ino.insert(path.to_path_buf(), ino);
+        true
+    }
+
+    // Drops nlookup references and evicts the inode once the kernel holds none.
+    // Returns true if the inode was evicted.
+    fn forget(&mut self, ino: u64, nlookup: u64) -> bool {
+        if ino == 1 {
+            return false;
+        }
+        let Some(count) = self.lookup_counts.get_mut(&ino) else {
+            return false;
+        };
+        *count = count.saturating_sub(nlookup);
+        if *count > 0 {
+            return false;
+        }
+
+        self.lookup_counts.remove(&ino);
+        if let Some(entry) = self.entries.remove(&ino) {
+            // With no names and no backing key left the file is gone, and in backing
+            // mode its number may be handed to a new file
+            if self.mode == InodeMode::Backing && entry.paths.is_empty() && entry.backing.is_none() {
+                let generation = self.generations.entry(ino).or_insert(0);
+                *generation += 1;
+                let record = [RECORD_GENERATION, ino, *generation, 0];
+                self.record(record);
+            }
+            for path in entry.paths {
+                if self.path_to_ino.get(&path) == Some(&ino) {
+                    self.path_to_ino.remove(&path);
+                }
+            }
+            if let Some(key) = entry.backing
+                && self.backing_to_ino.get(&key) == Some(&ino)
+            {
+                self.backing_to_ino.remove(&key);
+            }
+        }
+        self.publish_live_count();
+        true
+    }
+
+    fn publish_live_count(&self) {
+        LIVE_INODE_COUNT.store(self.live_count() as u64, Ordering::Relaxed);
+    }
+
+    // Removes a single name, leaving the inode and any other names in place
+    fn remove_name(&mut self, path: &Path) -> Option<u64> {
+        let ino = self.path_to_ino.remove(path)?;
+        if let Some(entry) = self.entries.get_mut(&ino) {
+            entry.paths.retain(|p| p != path);
+        }
+        Some(ino)
+    }
+
+    // Once an inode has no names left and the backing file is gone, its
+    // (st_dev, st_ino) may be reused by an unrelated file
+    fn release_backing_if_unnamed(&mut self, ino: u64) {
+        let Some(entry) = self.entries.get_mut(&ino) else {
+            return;
+        };
+        if !entry.paths.is_empty() {
+            return;
+        }
+        if let Some(key) = entry.backing.take()
+            && self.backing_to_ino.get(&key) == Some(&ino)
+        {
+            self.backing_to_ino.remove(&key);
+        }
+    }
+
+    // Called after a name was removed from the backing directory. still_linked
+    // says the backing file had other names, possibly not looked up yet.
+    fn unlink_path(&mut self, path: &Path, still_linked: bool) -> Option<u64> {
+        let ino = self.remove_name(path)?;
+        if !still_linked {
+            self.release_backing_if_unnamed(ino);
+        }
+        Some(ino)
+    }
+
+    // Takes the names at path and everything cached beneath it out of the maps,
+    // returning each inode with its path relative to the given one
+    fn detach_subtree(&mut self, path: &Path) -> Vec<(PathBuf, u64)> {
+        let paths: Vec<PathBuf> = self.path_to_ino
+            .keys()
+            .filter(|p| p.starts_with(path))
+            .cloned()
+            .collect();
+
+        let mut detached = Vec::with_capacity(paths.len());
+        for p in paths {
+            if let Some(ino) = self.remove_name(&p) {
+                let rel = p.strip_prefix(path).map(Path::to_path_buf).unwrap_or_default();
+                detached.push((rel, ino));
+            }
+        }
+        detached
+    }
+
+    fn attach_subtree(&mut self, path: &Path, subtree: Vec<(PathBuf, u64)>) {
+        for (rel, ino) in subtree {
+            let new_path = if rel.as_os_str().is_empty() { path.to_path_buf() } else { path.join(rel) };
+            self.add_link(ino, &new_path);
+        }
+    }
+
+    // Called after from was renamed over to. to_still_linked says the file it
+    // replaced has other names, as for unlink_path.
+    fn rename_path(&mut self, from: &Path, to: &Path, to_still_linked: bool) -> Option<u64> {
+        if from == to {
+            return self.path_to_ino.get(from).copied();
+        }
+        // Whatever was at the destination has been replaced
+        for (rel, ino) in self.detach_subtree(to) {
+            if !(to_still_linked && rel.as_os_str().is_empty()) {
+                self.release_backing_if_unnamed(ino);
+            }
+        }
+        let ino = self.path_to_ino.get(from).copied();
+        let subtree = self.detach_subtree(from);
+        self.attach_subtree(to, subtree);
         ino
     }
-    
-    fn remove_path(&mut self, path: &Path) -> Option<u64> {
-        if let Some(ino) = self.path_to_ino.remove(path) {
-            self.ino_to_path.remove(&ino);
-            Some(ino)
-        } else {
-            None
+
+    fn exchange_paths(&mut self, a: &Path, b: &Path) {
+        if a == b {
+            return;
         }
+        let a_subtree = self.detach_subtree(a);
+        let b_subtree = self.detach_subtree(b);
+        self.attach_subtree(b, a_subtree);
+        self.attach_subtree(a, b_subtree);
     }
 }
 
+struct OpenHandle {
+    // Shared so I/O can run without holding the handle table
+    file: Arc<File>,
+    flags: i32,
+}
+
+// Reads a directory into the form readdir replies with. Entries cached in inodes
+// report their inode; the rest report what listed_ino predicts, since readdir
+// doesn't take lookup references and shouldn't allocate inodes.
+fn list_directory(dir_entries: Vec<BackingDirEntry>, inodes: &InodeManager, ino: u64, path: &Path) -> DirListing {
+    let mut entries = vec![];
+
+    entries.push((ino, FileType::Directory, OsString::from(".")));
+    let parent_ino = if path == Path::new(".") {
+        1
+    } else {
+        path.parent()
+            .and_then(|p| inodes.path_to_ino.get(p))
+            .copied()
+            .unwrap_or(1)
+    };
+    entries.push((parent_ino, FileType::Directory, OsString::from("..")));
+
+    for entry in dir_entries {
+        // Same form as lookup builds, so both resolve to one inode
+        let entry_path = path.join(&entry.name);
+        let entry_ino = inodes.path_to_ino.get(&entry_path).copied().unwrap_or_else(|| inodes.listed_ino(&entry));
+        entries.push((entry_ino, entry.kind, entry.name));
+    }
+    entries
+}
+
+// Opens the backing file the way the caller asked for it. Creation and truncation are
+// handled by create/setattr, and O_DIRECT is left to the kernel side since our
+// buffers aren't aligned for it.
+fn open_backing_file(backing: &BackingDir, path: &Path, flags: i32) -> std::io::Result<File> {
+    let passed = libc::O_ACCMODE | libc::O_APPEND | libc::O_SYNC | libc::O_DSYNC | libc::O_NOFOLLOW;
+    backing.open_file(path, flags & passed, 0)
+}
+
+// Writes data at offset and returns where it actually landed. Files opened with
+// O_APPEND always write at the end, whatever offset the kernel passed along.
+fn write_backing(file: &File, append: bool, offset: u64, data: &[u8]) -> std::io::Result<u64> {
+    if append {
+        let mut writer = file;
+        writer.write_all(data)?;
+        let end = writer.stream_position()?;
+        Ok(end - data.len() as u64)
+    } else {
+        file.write_all_at(data, offset)?;
+        Ok(offset)
+    }
+}
+
+struct HandleTable {
+    handles: HashMap<u64, OpenHandle>,
+    next_fh: u64,
+}
+
+impl HandleTable {
+    fn new() -> Self {
+        Self {
+            handles: HashMap::new(),
+            next_fh: 1,
+        }
+    }
+
+    fn insert(&mut self, file: File, flags: i32) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, OpenHandle { file: Arc::new(file), flags });
+        fh
+    }
+
+    fn get(&self, fh: u64) -> Option<&OpenHandle> {
+        self.handles.get(&fh)
+    }
+
+    // The handle's file and open flags, for use after the table lock is dropped
+    fn file(&self, fh: u64) -> Option<(Arc<File>, i32)> {
+        self.handles.get(&fh).map(|h| (Arc::clone(&h.file), h.flags))
+    }
+
+    fn remove(&mut self, fh: u64) -> Option<OpenHandle> {
+        self.handles.remove(&fh)
+    }
+}
+
+// (ino, type, name) for each entry, "." and ".." included
+type DirListing = Vec<(u64, FileType, OsString)>;
+
+// Open directory handles. Each keeps the listing taken when reading started, so the
+// offsets handed to the kernel stay valid while the directory changes underneath.
+struct DirHandleTable {
+    listings: HashMap<u64, Option<Arc<DirListing>>>,
+    next_fh: u64,
+}
+
+impl DirHandleTable {
+    fn new() -> Self {
+        Self {
+            listings: HashMap::new(),
+            next_fh: 1,
+        }
+    }
+
+    fn open(&mut self) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.listings.insert(fh, None);
+        fh
+    }
+
+    fn listing(&self, fh: u64) -> Option<Arc<DirListing>> {
+        self.listings.get(&fh).cloned().flatten()
+    }
+
+    fn set_listing(&mut self, fh: u64, listing: Arc<DirListing>) {
+        if let Some(slot) = self.listings.get_mut(&fh) {
+            *slot = Some(listing);
+        }
+    }
+
+    fn remove(&mut self, fh: u64) -> bool {
+        self.listings.remove(&fh).is_some()
+    }
+}
+
+// How much the kernel may cache. Writes still reach us (and the log) either way;
+// with writeback they arrive when the kernel flushes dirty pages, at the latest on
+// close or fsync.
+struct CacheConfig {
+    // Validity of name lookups, and of the attributes returned with them
+    entry_ttl: Duration,
+    // Validity of attributes returned by getattr/setattr
+    attr_ttl: Duration,
+    // Keep a file's page cache across opens while the backing file is unchanged
+    keep_cache: bool,
+    // Ask for writeback caching; whether the kernel granted it is known after init
+    writeback: bool,
+}
+
+impl CacheConfig {
+    fn from_env() -> Self {
+        // try_from_secs_f64 also turns away values too large for a Duration
+        let ttl = |name: &str| {
+            let Ok(val) = std::env::var(name) else {
+                return DEFAULT_TTL;
+            };
+            val.parse::<f64>().ok().and_then(|secs| Duration::try_from_secs_f64(secs).ok()).unwrap_or_else(|| {
+                warn!("Ignoring {}={:?}; using {:?}", name, val, DEFAULT_TTL);
+                DEFAULT_TTL
+            })
+        };
+        let flag = |name: &str| std::env::var(name).is_ok_and(|val| val.to_lowercase() == "true" || val == "1");
+
+        Self {
+            entry_ttl: ttl("FUSELOG_ENTRY_TTL"),
+            attr_ttl: ttl("FUSELOG_ATTR_TTL"),
+            keep_cache: flag("FUSELOG_KEEP_CACHE"),
+            writeback: flag("FUSELOG_WRITEBACK_CACHE"),
+        }
+    }
+}
+
+// (mtime seconds, mtime nanoseconds, size) of a backing file, to tell whether the
+// kernel's cached pages for it are still current
+type CacheStamp = (i64, i64, u64);
+
+fn cache_stamp(metadata: &std::fs::Metadata) -> CacheStamp {
+    (metadata.mtime(), metadata.mtime_nsec(), metadata.size())
+}
+
+// Number of striped locks serializing data operations per inode
+const DATA_LOCK_STRIPES: usize = 64;
+
+// Handlers run concurrently on the dispatcher's worker threads. Locks are taken in
+// this order: inodes, a data stripe, STATEDIFF_LOG, then FID_TABLE; handles and
+// locks are only held briefly on their own.
+//
+// Namespace operations (create, unlink, rename, ...) hold inodes for writing across
+// the backing syscall and the log append. Data operations (write, truncate, xattrs,
+// ...) hold it for reading plus their inode's data stripe, so operations that touch
+// the same file are appended to the log in the order they took effect, while
+// unrelated files proceed in parallel.
 pub struct FuseLogFS {
-    inodes: Mutex<InodeManager>,
+    backing: Arc<BackingDir>,
+    // Set in overlay mode, where backing is the upper directory
+    overlay: Option<Arc<Overlay>>,
+    ownership: OwnershipStore,
+    inodes: RwLock<InodeManager>,
+    handles: Mutex<HandleTable>,
+    dir_handles: Mutex<DirHandleTable>,
+    locks: Mutex<LockTable>,
+    data_locks: Vec<Mutex<()>>,
     write_coalescing: bool,
+    cache: CacheConfig,
+    // Set in init once the kernel has agreed to writeback caching
+    writeback_active: AtomicBool,
+    // Backing file state as of the last open or release of each inode
+    cache_stamps: Mutex<HashMap<u64, CacheStamp>>,
+}
+
+impl FuseLogFS {
+    // source is the directory holding the real files; it is opened right away, so
+    // it may be mounted over afterwards
+    pub fn new(source: PathBuf) -> std::io::Result<Self> {
+        // Sequential inode numbers unless backing ones are asked for
+        let inode_mode = match std::env::var("FUSELOG_INODE_MODE").map(|val| val.to_lowercase()) {
+            Ok(val) if val == "backing" => InodeMode::Backing,
+            Ok(val) if val != "sequential" => {
+                warn!("Unknown FUSELOG_INODE_MODE '{}', using sequential inode numbers", val);
+                InodeMode::Sequential
+            }
+            _ => InodeMode::Sequential,
+        };
+        Self::with_inode_mode(source, inode_mode)
+    }
+
+    pub fn with_inode_mode(source: PathBuf, inode_mode: InodeMode) -> std::io::Result<Self> {
+        let backing = Arc::new(BackingDir::open(&source)?);
+        info!("Backing store: {:?}, inode mode: {:?}", source, inode_mode);
+        let state_dir = state::state_dir(&source);
+
+        // With a lower directory the source becomes the writable upper layer over it
+        let overlay = match std::env::var_os("FUSELOG_LOWER_DIR") {
+            Some(lower) => {
+                info!("Overlay mode: lower directory {:?}", lower);
+                Some(Arc::new(Overlay::new(Arc::clone(&backing), BackingDir::open(Path::new(&lower))?)))
+            }
+            None => None,
+        };
+
+        // Write coalesing is disabled by default
+        let coalescing_enabled = std::env::var("WRITE_COALESCING")
+            .is_ok_and(|val| val.to_lowercase() == "true" || val == "1");
+
+        if coalescing_enabled {
+            info!("Write coalescing is enabled.");
+        } else {
+            info!("Write coalescing is disabled.");
+        }
+
+        // Standbys start read-only and are switched over the socket when promoted
+        if std::env::var("FUSELOG_READ_ONLY").is_ok_and(|val| val.to_lowercase() == "true" || val == "1") {
+            set_read_only(true);
+        }
+
+        // Without root, ownership that can't be applied is recorded rather than refused
+        let rootless = std::env::var("FUSELOG_ROOTLESS").is_ok_and(|val| val.to_lowercase() == "true" || val == "1")
+            || unsafe { libc::geteuid() } != 0;
+        if rootless {
+            info!("Rootless mode: ownership and modes that can't be applied are kept in xattrs");
+        }
+
+        let cache = CacheConfig::from_env();
+        info!(
+            "Kernel caching: entry TTL {:?}, attr TTL {:?}, keep_cache {}, writeback {}",
+            cache.entry_ttl, cache.attr_ttl, cache.keep_cache, cache.writeback
+        );
+
+        Ok(Self {
+            inodes: RwLock::new(InodeManager::new(Arc::clone(&backing), overlay.clone(), inode_mode, &state_dir)),
+            backing,
+            overlay,
+            ownership: OwnershipStore::new(rootless, &state_dir),
+            handles: Mutex::new(HandleTable::new()),
+            dir_handles: Mutex::new(DirHandleTable::new()),
+            locks: Mutex::new(LockTable::default()),
+            data_locks: (0..DATA_LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+            write_coalescing : coalescing_enabled,
+            cache,
+            writeback_active: AtomicBool::new(false),
+            cache_stamps: Mutex::new(HashMap::new()),
+        })
+    }
+    
+    fn data_lock(&self, ino: u64) -> MutexGuard<'_, ()> {
+        self.data_locks[(ino % DATA_LOCK_STRIPES as u64) as usize].lock().unwrap()
+    }
+
+    // Locks the stripes of two inodes in a fixed order, taking a shared stripe once
+    fn data_lock_pair(&self, a: u64, b: u64) -> (MutexGuard<'_, ()>, Option<MutexGuard<'_, ()>>) {
+        let (sa, sb) = (a % DATA_LOCK_STRIPES as u64, b % DATA_LOCK_STRIPES as u64);
+        if sa == sb {
+            return (self.data_lock(a), None);
+        }
+        let (first, second) = if sa < sb { (a, b) } else { (b, a) };
+        let first = self.data_lock(first);
+        (first, Some(self.data_lock(second)))
+    }
+
+    // With writeback caching the kernel reads pages of files opened write-only and
+    // decides append offsets itself, so the backing file is opened accordingly
+    fn backing_open_flags(&self, flags: i32) -> i32 {
+        if !self.writeback_active.load(Ordering::Relaxed) {
+            return flags;
+        }
+        let flags = flags & !libc::O_APPEND;
+        if (flags & libc::O_ACCMODE) == libc::O_WRONLY {
+            (flags & !libc::O_ACCMODE) | libc::O_RDWR
+        } else {
+            flags
+        }
+    }
+
+    // Flags kept with an open handle: the caller's access mode, for lock checks, but
+    // not O_APPEND when the kernel is the one placing appended writes
+    fn handle_flags(&self, flags: i32) -> i32 {
+        if self.writeback_active.load(Ordering::Relaxed) {
+            flags & !libc::O_APPEND
+        } else {
+            flags
+        }
+    }
+
+    // Records the backing file's current state and says whether it matches what the
+    // kernel saw last time, i.e. whether cached pages may be kept
+    fn refresh_cache_stamp(&self, ino: u64, file: &File) -> bool {
+        let Ok(metadata) = file.metadata() else {
+            return false;
+        };
+        let stamp = cache_stamp(&metadata);
+        self.cache_stamps.lock().unwrap().insert(ino, stamp) == Some(stamp)
+    }
+
+    // Fails parked lock requests whose process has exited or is being killed; see
+    // dispatch.rs for why this is polled
+    pub(crate) fn interrupt_lock_waiters(&self) {
+        let mut locks = self.locks.lock().unwrap();
+        if locks.has_waiters() {
+            locks.interrupt_waiters(locks::caller_interrupted);
+        }
+    }
+
+    fn get_relative_path(&self, full_path: &Path) -> PathBuf {
+        full_path.strip_prefix("./").unwrap_or(full_path).to_path_buf()
+    }
+
+    pub fn is_rootless(&self) -> bool {
+        self.ownership.rootless()
+    }
+
+    fn file_attr(&self, ino: u64, path: &Path, metadata: &std::fs::Metadata) -> FileAttr {
+        let mut attrs = metadata_to_file_attr(ino, metadata);
+        self.ownership.apply(self.layer(path), path, metadata, &mut attrs);
+        attrs
+    }
+
+    // Where reads of path are served from; in overlay mode that is lower until the
+    // entry has been copied up
+    fn layer(&self, path: &Path) -> &BackingDir {
+        match &self.overlay {
+            Some(overlay) => overlay.layer(path),
+            None => &self.backing,
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> std::io::Result<Vec<BackingDirEntry>> {
+        match &self.overlay {
+            Some(overlay) => overlay.read_dir(path),
+            None => self.backing.read_dir(path),
+        }
+    }
+
+    // Overlay bookkeeping names never show through the mount
+    fn is_hidden_name(&self, name: &OsStr) -> bool {
+        self.overlay.is_some() && overlay::is_marker(name.as_bytes())
+    }
+
+    // Copies a lower file's data into upper before the caller takes its locks, so
+    // copy_up under them only has to move it into place. Failures are left for
+    // copy_up to report.
+    fn stage_copy_up(&self, path: &Path) -> Option<StagedCopy> {
+        let overlay = self.overlay.as_ref()?;
+        overlay.stage_copy_up(path).unwrap_or_else(|e| {
+            debug!("Staging copy-up of {:?} failed: {}", path, e);
+            None
+        })
+    }
+
+    // stage_copy_up for whatever ino is named right now
+    fn stage_copy_up_ino(&self, ino: u64) -> Option<StagedCopy> {
+        self.overlay.as_ref()?;
+        let path = self.inodes.read().unwrap().get_path(ino)?.clone();
+        self.stage_copy_up(&path)
+    }
+
+    // stage_copy_up for name under parent
+    fn stage_copy_up_child(&self, parent: u64, name: &OsStr) -> Option<StagedCopy> {
+        self.overlay.as_ref()?;
+        let path = self.inodes.read().unwrap().get_path(parent)?.join(name);
+        self.stage_copy_up(&path)
+    }
+
+    // Brings path up from lower before it is modified, using staged if the caller
+    // copied the data beforehand. The log gets a reference to the base file rather
+    // than its data, since the replica has the base too. Callers hold the inode's
+    // data stripe, or inodes for writing.
+    fn copy_up(&self, path: &Path, staged: Option<StagedCopy>) -> std::io::Result<()> {
+        let Some(overlay) = &self.overlay else {
+            return Ok(());
+        };
+        if let Some(size) = overlay.copy_up(path, staged)? {
+            let relative_path = self.get_relative_path(path);
+            let mut log = STATEDIFF_LOG.lock().unwrap();
+            let fid = get_fid(&mut log, &relative_path);
+            log.push(StateDiffAction::CopyUp { fid, size });
+            info!("Copied up and logged {:?} ({} bytes)", path, size);
+        }
+        Ok(())
+    }
+
+    fn prepare_create(&self, path: &Path) -> std::io::Result<()> {
+        match &self.overlay {
+            Some(overlay) => overlay.prepare_create(path),
+            None => Ok(()),
+        }
+    }
+
+    // The new entry already shadows lower, so a failure here is only logged
+    fn finish_create(&self, path: &Path, is_dir: bool) {
+        if let Some(overlay) = &self.overlay
+            && let Err(e) = overlay.finish_create(path, is_dir)
+        {
+            warn!("Failed to clear the whiteout for {:?}: {}", path, e);
+        }
+    }
+
+    fn remove_entry(&self, path: &Path, is_dir: bool) -> std::io::Result<()> {
+        match &self.overlay {
+            Some(overlay) => overlay.remove(path, is_dir),
+            None if is_dir => self.backing.remove_dir(path),
+            None => self.backing.remove_file(path),
+        }
+    }
 }
 
+// Request handlers. They take &self so the dispatcher can run them on worker threads;
+// see dispatch.rs for how fuser's callbacks are routed here. They mirror
+// fuser::Filesystem, argument lists included.
+#[allow(clippy::too_many_arguments)]
 impl FuseLogFS {
-    pub fn new(_root: PathBuf) -> Self {
-
-        // Write coalesing is disabled by default
-        let coalescing_enabled = std::env::var("WRITE_COALESCING")
-            .map_or(false, |val| val.to_lowercase() == "true" || val == "1");
+    fn init(&self, _req: &Caller, config: &mut KernelConfig) -> Result<(), libc::c_int> {
+        // Ask the kernel to forward fcntl locks so they are arbitrated here. flock
+        // stays with the kernel, see locks.rs.
+        if config.add_capabilities(fuser::consts::FUSE_POSIX_LOCKS).is_err() {
+            warn!("Kernel does not support forwarding POSIX locks; it will handle them itself");
+        }
 
-        if coalescing_enabled {
-            info!("Write coalescing is enabled.");
-        } else {
-            info!("Write coalescing is disabled.");
+        if self.cache.writeback {
+            match config.add_capabilities(fuser::consts::FUSE_WRITEBACK_CACHE) {
+                Ok(()) => {
+                    self.writeback_active.store(true, Ordering::Relaxed);
+                    info!("Writeback caching enabled");
+                }
+                Err(_) => warn!("Kernel does not support writeback caching; writes stay write-through"),
+            }
         }
+        Ok(())
+    }
 
-        Self {
-            inodes: Mutex::new(InodeManager::new()),
-            write_coalescing : coalescing_enabled,
+    fn forget(&self, _req: &Caller, ino: u64, nlookup: u64) {
+        debug!("forget(ino={}, nlookup={})", ino, nlookup);
+
+        let mut inodes = self.inodes.write().unwrap();
+        if inodes.forget(ino, nlookup) {
+            self.cache_stamps.lock().unwrap().remove(&ino);
+            trace!("Evicted inode {} ({} live)", ino, inodes.live_count());
         }
     }
-    
-    fn get_relative_path(&self, full_path: &Path) -> String {
-        full_path.strip_prefix("./").unwrap_or(full_path).to_string_lossy().to_string()
+
+    fn batch_forget(&self, _req: &Caller, nodes: &[fuser::fuse_forget_one]) {
+        debug!("batch_forget(count={})", nodes.len());
+
+        let mut inodes = self.inodes.write().unwrap();
+        for node in nodes {
+            if inodes.forget(node.nodeid, node.nlookup) {
+                self.cache_stamps.lock().unwrap().remove(&node.nodeid);
+            }
+        }
+        trace!("{} inodes live after batch forget", inodes.live_count());
     }
-}
 
-impl Filesystem for FuseLogFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+    fn lookup(&self, _req: &Caller, parent: u64, name: &OsStr, reply: ReplyEntry) {
         debug!("lookup(parent={}, name={:?})", parent, name);
         
-        let mut inodes = self.inodes.lock().unwrap();
+        let mut inodes = self.inodes.write().unwrap();
         
         let parent_path = match inodes.get_path(parent) {
             Some(p) => p.clone(),
@@ -152,23 +1026,27 @@ impl Filesystem for FuseLogFS {
             }
         };
         
+        if self.is_hidden_name(name) {
+            reply.error(ENOENT);
+            return;
+        }
         let child_path = parent_path.join(name);
         
         // Use symlink_metadata to avoid following symlinks
-        match std::fs::symlink_metadata(&child_path) {
+        match self.layer(&child_path).symlink_metadata(&child_path) {
             Ok(metadata) => {
-                let ino = inodes.get_or_create_ino(&child_path);
-                let attrs = metadata_to_file_attr(ino, &metadata);
-                reply.entry(&TTL, &attrs, 0);
+                let ino = inodes.lookup_ino(&child_path);
+                let attrs = self.file_attr(ino, &child_path, &metadata);
+                reply.entry(&self.cache.entry_ttl, &attrs, inodes.generation(ino));
             }
             Err(_) => reply.error(ENOENT),
         }
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+    fn getattr(&self, _req: &Caller, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
         debug!("getattr(ino={})", ino);
         
-        let inodes = self.inodes.lock().unwrap();
+        let inodes = self.inodes.read().unwrap();
         
         let path = match inodes.get_path(ino) {
             Some(p) => p.clone(),
@@ -179,18 +1057,18 @@ impl Filesystem for FuseLogFS {
         };
         
         // Use symlink_metadata to avoid following symlinks
-        match std::fs::symlink_metadata(&path) {
+        match self.layer(&path).symlink_metadata(&path) {
             Ok(metadata) => {
-                let attrs = metadata_to_file_attr(ino, &metadata);
-                reply.attr(&TTL, &attrs);
+                let attrs = self.file_attr(ino, &path, &metadata);
+                reply.attr(&self.cache.attr_ttl, &attrs);
             }
             Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
         }
     }
 
-    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+    fn readlink(&self, _req: &Caller, ino: u64, reply: ReplyData) {
         debug!("readlink(ino={})", ino);
-        let inodes = self.inodes.lock().unwrap();
+        let inodes = self.inodes.read().unwrap();
         let path = match inodes.get_path(ino) {
             Some(p) => p.clone(),
             None => {
@@ -199,7 +1077,7 @@ impl Filesystem for FuseLogFS {
             }
         };
 
-        match std::fs::read_link(&path) {
+        match self.layer(&path).read_link(&path) {
             Ok(target_path) => {
                 reply.data(target_path.as_os_str().as_encoded_bytes());
             }
@@ -207,68 +1085,235 @@ impl Filesystem for FuseLogFS {
         }
     }
 
-    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
-        debug!("readdir(ino={}, offset={})", ino, offset);
-        
-        let mut inodes = self.inodes.lock().unwrap();
-        
-        let path = match inodes.get_path(ino) {
+    fn opendir(&self, _req: &Caller, ino: u64, flags: i32, reply: ReplyOpen) {
+        debug!("opendir(ino={}, flags=0x{:x})", ino, flags);
+
+        let path = match self.inodes.read().unwrap().get_path(ino) {
             Some(p) => p.clone(),
             None => {
                 reply.error(ENOENT);
                 return;
             }
         };
-        
-        let mut entries = vec![];
-        
-        entries.push((ino, FileType::Directory, ".".to_string()));
-        let parent_ino = if path == Path::new(".") {
-            1
-        } else {
-            path.parent()
-                .and_then(|p| inodes.path_to_ino.get(p))
-                .copied()
-                .unwrap_or(1)
-        };
-        entries.push((parent_ino, FileType::Directory, "..".to_string()));
-        
-        if let Ok(dir_iter) = std::fs::read_dir(&path) {
-            for entry in dir_iter.filter_map(Result::ok) {
-                let entry_path = if path == Path::new(".") {
-                    PathBuf::from(entry.file_name())
-                } else {
-                    path.join(entry.file_name())
-                };
-                
-                let entry_ino = inodes.get_or_create_ino(&entry_path);
-                
-                let file_type = if entry.file_type().map_or(false, |ft| ft.is_dir()) {
-                    FileType::Directory
-                } else if entry.file_type().map_or(false, |ft| ft.is_symlink()) {
-                    FileType::Symlink
-                } else {
-                    FileType::RegularFile
+
+        // Fail here rather than on the first readdir if the directory can't be read
+        if let Err(e) = self.read_dir(&path) {
+            reply.error(e.raw_os_error().unwrap_or(EIO));
+            return;
+        }
+
+        let fh = self.dir_handles.lock().unwrap().open();
+        reply.opened(fh, 0);
+    }
+
+    fn readdir(&self, _req: &Caller, ino: u64, fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        debug!("readdir(ino={}, fh={}, offset={})", ino, fh, offset);
+
+        // Reading from the start (including after rewinddir) takes a fresh listing;
+        // later offsets index into that one
+        let snapshot = if offset == 0 { None } else { self.dir_handles.lock().unwrap().listing(fh) };
+        let listing = match snapshot {
+            Some(listing) => listing,
+            None => {
+                let inodes = self.inodes.read().unwrap();
+                let path = match inodes.get_path(ino) {
+                    Some(p) => p.clone(),
+                    None => {
+                        reply.error(ENOENT);
+                        return;
+                    }
                 };
-                
-                if let Some(name) = entry.file_name().to_str() {
-                    entries.push((entry_ino, file_type, name.to_string()));
-                }
+                let listing = Arc::new(list_directory(self.read_dir(&path).unwrap_or_default(), &inodes, ino, &path));
+                self.dir_handles.lock().unwrap().set_listing(fh, Arc::clone(&listing));
+                listing
             }
-        }
-        
-        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
-            if reply.add(ino, (i + 1) as i64, kind, &name) {
+        };
+
+        for (i, (ino, kind, name)) in listing.iter().enumerate().skip(offset as usize) {
+            if reply.add(*ino, (i + 1) as i64, *kind, name) {
                 break;
             }
         }
         reply.ok();
     }
 
-    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, _umask: u32, reply: ReplyEntry) {
+    fn releasedir(&self, _req: &Caller, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        debug!("releasedir(ino={}, fh={})", ino, fh);
+
+        if !self.dir_handles.lock().unwrap().remove(fh) {
+            warn!("releasedir called for unknown fh {} (ino {})", fh, ino);
+        }
+        reply.ok();
+    }
+
+    fn fsyncdir(&self, _req: &Caller, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        debug!("fsyncdir(ino={}, fh={}, datasync={})", ino, fh, datasync);
+
+        let path = match self.inodes.read().unwrap().get_path(ino) {
+            Some(p) => p.clone(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let sync_result = self.layer(&path).open_file(&path, libc::O_RDONLY | libc::O_DIRECTORY, 0).and_then(|dir| if datasync { dir.sync_data() } else { dir.sync_all() });
+        match sync_result {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn statfs(&self, _req: &Caller, ino: u64, reply: ReplyStatfs) {
+        debug!("statfs(ino={})", ino);
+
+        let path = self.inodes.read().unwrap().get_path(ino).cloned().unwrap_or_else(|| PathBuf::from("."));
+        let stat = match self.layer(&path).statvfs(&path) {
+            Ok(stat) => stat,
+            Err(e) => {
+                reply.error(e.raw_os_error().unwrap_or(EIO));
+                return;
+            }
+        };
+
+        reply.statfs(
+            stat.f_blocks,
+            stat.f_bfree,
+            stat.f_bavail,
+            stat.f_files,
+            stat.f_ffree,
+            stat.f_bsize as u32,
+            stat.f_namemax as u32,
+            stat.f_frsize as u32,
+        );
+    }
+
+    // Only consulted when the mount doesn't use default_permissions. The daemon runs
+    // privileged, so the check is made against the caller's uid and primary gid
+    // rather than with access(2).
+    fn access(&self, req: &Caller, ino: u64, mask: i32, reply: ReplyEmpty) {
+        debug!("access(ino={}, mask={:o}, uid={}, gid={})", ino, mask, req.uid(), req.gid());
+
+        let path = match self.inodes.read().unwrap().get_path(ino) {
+            Some(p) => p.clone(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let metadata = match self.layer(&path).symlink_metadata(&path) {
+            Ok(m) => m,
+            Err(e) => {
+                reply.error(e.raw_os_error().unwrap_or(EIO));
+                return;
+            }
+        };
+
+        if mask == libc::F_OK {
+            reply.ok();
+            return;
+        }
+
+        if (mask & libc::W_OK) != 0 && is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        // Ownership as reported to the kernel, which in rootless mode may be recorded
+        let attrs = self.file_attr(ino, &path, &metadata);
+        let mode = attrs.perm as u32;
+        let granted = if req.uid() == 0 {
+            // root may read and write anything, and execute anything executable by someone
+            let exec = metadata.is_dir() || (mode & 0o111) != 0;
+            libc::R_OK | libc::W_OK | if exec { libc::X_OK } else { 0 }
+        } else if req.uid() == attrs.uid {
+            ((mode >> 6) & 0o7) as i32
+        } else if req.gid() == attrs.gid {
+            ((mode >> 3) & 0o7) as i32
+        } else {
+            (mode & 0o7) as i32
+        };
+
+        if (mask & !granted) == 0 {
+            reply.ok();
+        } else {
+            reply.error(libc::EACCES);
+        }
+    }
+
+    fn mknod(&self, req: &Caller, parent: u64, name: &OsStr, mode: u32, _umask: u32, rdev: u32, reply: ReplyEntry) {
+        debug!("mknod(parent={}, name={:?}, mode={:o}, rdev={}, uid={}, gid={})", parent, name, mode, rdev, req.uid(), req.gid());
+
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let mut inodes = self.inodes.write().unwrap();
+
+        let parent_path = match inodes.get_path(parent) {
+            Some(p) => p.clone(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let node_path = parent_path.join(name);
+        let relative_path = self.get_relative_path(&node_path);
+
+        if let Err(e) = self.prepare_create(&node_path).and_then(|_| self.backing.mknod(&node_path, mode, rdev)) {
+            reply.error(e.raw_os_error().unwrap_or(EIO));
+            return;
+        }
+
+        // mknod is subject to our own umask, so apply the requested permission bits explicitly
+        if let Err(e) = self.ownership.chmod(&self.backing, &node_path, mode & 0o7777) {
+            warn!("Warning: failed to set node permissions for {:?}: {}", &node_path, e);
+        }
+
+        if let Err(e) = self.ownership.chown(&self.backing, &node_path, Some(req.uid()), Some(req.gid()), false) {
+            error!("Failed to chown new node {:?}: {}. Cleaning up.", &node_path, e);
+            let _ = self.backing.remove_file(&node_path);
+            reply.error(e.raw_os_error().unwrap_or(EIO));
+            return;
+        }
+        self.finish_create(&node_path, false);
+
+        let ino = inodes.lookup_ino(&node_path);
+
+        {
+            let mut log = STATEDIFF_LOG.lock().unwrap();
+            let fid = get_fid(&mut log, &relative_path);
+            log.push(StateDiffAction::Mknod {
+                fid,
+                mode,
+                rdev,
+                uid: req.uid(),
+                gid: req.gid(),
+            });
+        }
+        info!("Created and logged node: {:?} (mode {:o}, rdev {}) with owner {}:{}", node_path, mode, rdev, req.uid(), req.gid());
+
+        match self.backing.symlink_metadata(&node_path) {
+            Ok(metadata) => {
+                let attrs = self.file_attr(ino, &node_path, &metadata);
+                reply.entry(&self.cache.entry_ttl, &attrs, inodes.generation(ino));
+            }
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn mkdir(&self, req: &Caller, parent: u64, name: &OsStr, mode: u32, _umask: u32, reply: ReplyEntry) {
         debug!("mkdir(parent={}, name={:?}, mode={:o}, uid={}, gid={})", parent, name, mode, req.uid(), req.gid());
+
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
         
-        let mut inodes = self.inodes.lock().unwrap();
+        let mut inodes = self.inodes.write().unwrap();
         
         let parent_path = match inodes.get_path(parent) {
             Some(p) => p.clone(),
@@ -280,39 +1325,36 @@ impl Filesystem for FuseLogFS {
         
         let dir_path = parent_path.join(name);
         let relative_path = self.get_relative_path(&dir_path);
-        
-        if dir_path.exists() {
-            reply.error(EEXIST);
-            return;
-        }
-        
-        match std::fs::create_dir(&dir_path) {
+
+        // mkdirat, or prepare_create for a name only lower has, reports EEXIST
+        match self.prepare_create(&dir_path).and_then(|_| self.backing.create_dir(&dir_path, mode & 0o7777)) {
             Ok(_) => {
-                if let Err(e) = std::fs::set_permissions(&dir_path, std::fs::Permissions::from_mode(mode)) {
+                if let Err(e) = self.ownership.chmod(&self.backing, &dir_path, mode) {
                     warn!("Warning: failed to set directory permissions: {}", e);
                 }
 
-                if let Err(e) = std::os::unix::fs::chown(&dir_path, Some(req.uid()), Some(req.gid())) {
+                if let Err(e) = self.ownership.chown(&self.backing, &dir_path, Some(req.uid()), Some(req.gid()), true) {
                     error!("Failed to chown new directory {:?}: {}. Cleaning up.", &dir_path, e);
-                    let _ = std::fs::remove_dir(&dir_path);
+                    let _ = self.backing.remove_dir(&dir_path);
                     reply.error(e.raw_os_error().unwrap_or(EIO));
                     return;
                 }
+                self.finish_create(&dir_path, true);
                 
-                let ino = inodes.get_or_create_ino(&dir_path);
+                let ino = inodes.lookup_ino(&dir_path);
                 
                 {
                     let mut log = STATEDIFF_LOG.lock().unwrap();
                     let fid = get_fid(&mut log, &relative_path);
-                    log.actions.push(StateDiffAction::Mkdir { fid });
-                    log.actions.push(StateDiffAction::Chown { fid, uid: req.uid(), gid: req.gid() });
+                    log.push(StateDiffAction::Mkdir { fid });
+                    log.push(StateDiffAction::Chown { fid, uid: req.uid(), gid: req.gid() });
                 }
                 info!("Created and logged directory: {:?} with owner {}:{}", dir_path, req.uid(), req.gid());
 
-                match std::fs::metadata(&dir_path) {
+                match self.backing.metadata(&dir_path) {
                     Ok(metadata) => {
-                        let attrs = metadata_to_file_attr(ino, &metadata);
-                        reply.entry(&TTL, &attrs, 0);
+                        let attrs = self.file_attr(ino, &dir_path, &metadata);
+                        reply.entry(&self.cache.entry_ttl, &attrs, inodes.generation(ino));
                     }
                     Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
                 }
@@ -321,10 +1363,15 @@ impl Filesystem for FuseLogFS {
         }
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&self, _req: &Caller, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         debug!("rmdir(parent={}, name={:?})", parent, name);
+
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
         
-        let mut inodes = self.inodes.lock().unwrap();
+        let mut inodes = self.inodes.write().unwrap();
         
         let parent_path = match inodes.get_path(parent) {
             Some(p) => p.clone(),
@@ -337,15 +1384,15 @@ impl Filesystem for FuseLogFS {
         let dir_path = parent_path.join(name);
         let relative_path = self.get_relative_path(&dir_path);
         
-        match std::fs::remove_dir(&dir_path) {
+        match self.remove_entry(&dir_path, true) {
             Ok(_) => {
-                if let Some(ino) = inodes.remove_path(&dir_path) {
+                if let Some(ino) = inodes.unlink_path(&dir_path, false) {
                      debug!("Removed inode {} for path {:?}", ino, dir_path);
                 }
                 {
                     let mut log = STATEDIFF_LOG.lock().unwrap();
                     let fid = get_fid(&mut log, &relative_path);
-                    log.actions.push(StateDiffAction::Rmdir { fid });
+                    log.push(StateDiffAction::Rmdir { fid });
                 }
                 info!("Removed and logged directory: {:?}", dir_path);
                 reply.ok();
@@ -354,10 +1401,15 @@ impl Filesystem for FuseLogFS {
         }
     }
 
-    fn symlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
+    fn symlink(&self, req: &Caller, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
         debug!("symlink(parent={}, name={:?}, target={:?})", parent, name, link);
 
-        let mut inodes = self.inodes.lock().unwrap();
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let mut inodes = self.inodes.write().unwrap();
 
         let parent_path = match inodes.get_path(parent) {
             Some(p) => p.clone(),
@@ -366,26 +1418,27 @@ impl Filesystem for FuseLogFS {
 
         let link_path = parent_path.join(name);
         let relative_link_path = self.get_relative_path(&link_path);
-        let target_path_str = link.to_string_lossy().to_string();
+        let target_path_bytes = link.as_os_str().as_bytes().to_vec();
 
-        match std::os::unix::fs::symlink(link, &link_path) {
+        match self.prepare_create(&link_path).and_then(|_| self.backing.symlink(link, &link_path)) {
             Ok(_) => {
                 // Use lchown to set ownership of the link itself, not the target
-                if let Err(e) = std::os::unix::fs::chown(&link_path, Some(req.uid()), Some(req.gid())) {
+                if let Err(e) = self.ownership.chown(&self.backing, &link_path, Some(req.uid()), Some(req.gid()), false) {
                     error!("Failed to chown new symlink {:?}: {}. Cleaning up.", &link_path, e);
-                    let _ = std::fs::remove_file(&link_path);
+                    let _ = self.backing.remove_file(&link_path);
                     reply.error(e.raw_os_error().unwrap_or(EIO));
                     return;
                 }
+                self.finish_create(&link_path, false);
 
-                let ino = inodes.get_or_create_ino(&link_path);
+                let ino = inodes.lookup_ino(&link_path);
                 
                 {
                     let mut log = STATEDIFF_LOG.lock().unwrap();
                     let link_fid = get_fid(&mut log, &relative_link_path);
-                    log.actions.push(StateDiffAction::Symlink {
+                    log.push(StateDiffAction::Symlink {
                         link_fid,
-                        target_path: target_path_str,
+                        target_path: target_path_bytes,
                         uid: req.uid(),
                         gid: req.gid(),
                     });
@@ -393,10 +1446,10 @@ impl Filesystem for FuseLogFS {
                 info!("Created and logged symlink: {:?} -> {:?}", link_path, link);
 
                 // Use symlink_metadata to get attributes of the link itself
-                match std::fs::symlink_metadata(&link_path) {
+                match self.backing.symlink_metadata(&link_path) {
                     Ok(metadata) => {
-                        let attrs = metadata_to_file_attr(ino, &metadata);
-                        reply.entry(&TTL, &attrs, 0);
+                        let attrs = self.file_attr(ino, &link_path, &metadata);
+                        reply.entry(&self.cache.entry_ttl, &attrs, inodes.generation(ino));
                     }
                     Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
                 }
@@ -405,24 +1458,68 @@ impl Filesystem for FuseLogFS {
         }
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
-        debug!("open(ino={})", ino);
+    fn open(&self, _req: &Caller, ino: u64, flags: i32, reply: ReplyOpen) {
+        debug!("open(ino={}, flags=0x{:x})", ino, flags);
+
+        if is_read_only() && ((flags & libc::O_ACCMODE) != libc::O_RDONLY || (flags & libc::O_TRUNC) != 0) {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let path = match self.inodes.read().unwrap().get_path(ino) {
+            Some(p) => p.clone(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        // Opening for writing is what brings a lower file up
+        let writable = (flags & libc::O_ACCMODE) != libc::O_RDONLY || (flags & libc::O_TRUNC) != 0;
+        if writable && self.overlay.is_some() {
+            let staged = self.stage_copy_up(&path);
+            let _inodes = self.inodes.read().unwrap();
+            let _data_guard = self.data_lock(ino);
+            if let Err(e) = self.copy_up(&path, staged) {
+                reply.error(e.raw_os_error().unwrap_or(EIO));
+                return;
+            }
+        }
+
+        let file = match open_backing_file(self.layer(&path), &path, self.backing_open_flags(flags)) {
+            Ok(f) => f,
+            Err(e) => {
+                reply.error(e.raw_os_error().unwrap_or(EIO));
+                trace!("open(ino={}) - EXIT (error on backing open)", ino);
+                return;
+            }
+        };
 
         let mut open_flag = 0;
 
-        if (flags & libc::O_DIRECT as i32) != 0 {
+        if (flags & libc::O_DIRECT) != 0 {
             info!("O_DIRECT flag detected for ino {}, enabling FOPEN_DIRECT_IO", ino);
             open_flag |= fuser::consts::FOPEN_DIRECT_IO;
+        } else if self.refresh_cache_stamp(ino, &file) && self.cache.keep_cache {
+            open_flag |= fuser::consts::FOPEN_KEEP_CACHE;
         }
 
-        reply.opened(0, open_flag);
-        trace!("open(ino={}) - EXIT (OK)", ino);
+        let fh = self.handles.lock().unwrap().insert(file, self.handle_flags(flags));
+
+        reply.opened(fh, open_flag);
+        trace!("open(ino={}) - EXIT (OK, fh={})", ino, fh);
     }
 
-    fn create(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, _umask: u32, flags: i32, reply: ReplyCreate) {
+    fn create(&self, req: &Caller, parent: u64, name: &OsStr, mode: u32, _umask: u32, flags: i32, reply: ReplyCreate) {
         trace!("create(parent={}, name={:?}, flags=0x{:x}) - ENTER", parent, name, flags);
-        
-        let mut inodes = self.inodes.lock().unwrap();
+
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let staged = if (flags & libc::O_EXCL) == 0 { self.stage_copy_up_child(parent, name) } else { None };
+        let mut inodes = self.inodes.write().unwrap();
         
         let parent_path = match inodes.get_path(parent) {
             Some(p) => p.clone(),
@@ -436,36 +1533,45 @@ impl Filesystem for FuseLogFS {
         let file_path = parent_path.join(name);
         let relative_path = self.get_relative_path(&file_path);
 
-        let mut options = std::fs::OpenOptions::new();
-        options.write(true).create(true);
+        let backing_flags = self.backing_open_flags(flags);
+        let mut open_flags = libc::O_CREAT;
+        open_flags |= if (backing_flags & libc::O_ACCMODE) == libc::O_WRONLY { libc::O_WRONLY } else { libc::O_RDWR };
+        open_flags |= backing_flags & libc::O_APPEND;
 
-        if (flags & libc::O_EXCL as i32) != 0 {
+        if (flags & libc::O_EXCL) != 0 {
             // O_EXCL (fail if file already exists)
-            options.create_new(true); 
-        } else if (flags & libc::O_TRUNC as i32) != 0 {
-            options.truncate(true);
+            open_flags |= libc::O_EXCL;
+        } else if (flags & libc::O_TRUNC) != 0 {
+            open_flags |= libc::O_TRUNC;
         }
 
-        match options.open(&file_path) {
-            Ok(_file) => { 
-                if let Err(e) = std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(mode)) {
+        // Without O_EXCL an existing lower file is opened rather than refused
+        let prepared = match &self.overlay {
+            Some(overlay) if (flags & libc::O_EXCL) == 0 && overlay.lower_has(&file_path) => self.copy_up(&file_path, staged),
+            _ => self.prepare_create(&file_path),
+        };
+
+        match prepared.and_then(|_| self.backing.open_file(&file_path, open_flags, mode & 0o7777)) {
+            Ok(file) => { 
+                if let Err(e) = self.ownership.chmod(&self.backing, &file_path, mode) {
                     warn!("Warning: failed to set file permissions for {:?}: {}", &file_path, e);
                 }
 
-                if let Err(e) = std::os::unix::fs::chown(&file_path, Some(req.uid()), Some(req.gid())) {
+                if let Err(e) = self.ownership.chown(&self.backing, &file_path, Some(req.uid()), Some(req.gid()), true) {
                     error!("Failed to chown new file {:?}: {}. Cleaning up.", &file_path, e);
-                    let _ = std::fs::remove_file(&file_path);
+                    let _ = self.backing.remove_file(&file_path);
                     reply.error(e.raw_os_error().unwrap_or(EIO));
                     trace!("create({:?}) - EXIT (EIO on chown)", name);
                     return;
                 }
+                self.finish_create(&file_path, false);
 
-                let ino = inodes.get_or_create_ino(&file_path);
+                let ino = inodes.lookup_ino(&file_path);
                 
                 {
                     let mut log = STATEDIFF_LOG.lock().unwrap();
                     let fid = get_fid(&mut log, &relative_path);
-                    log.actions.push(StateDiffAction::Create { 
+                    log.push(StateDiffAction::Create { 
                         fid, 
                         uid: req.uid(), 
                         gid: req.gid(),
@@ -474,17 +1580,19 @@ impl Filesystem for FuseLogFS {
                 }
                 info!("Logged create for file: {:?} with owner {}:{}", file_path, req.uid(), req.gid());
 
-                if let Ok(metadata) = std::fs::metadata(&file_path) {
-                    let attrs = metadata_to_file_attr(ino, &metadata);
+                if let Ok(metadata) = self.backing.metadata(&file_path) {
+                    let attrs = self.file_attr(ino, &file_path, &metadata);
                     
                     // FIX : Handle O_DIRECT flag correctly
                     let mut open_flags = 0;
-                    if (flags & libc::O_DIRECT as i32) != 0 {
+                    if (flags & libc::O_DIRECT) != 0 {
                         info!("O_DIRECT flag detected on create for {:?}, enabling FOPEN_DIRECT_IO", file_path);
                         open_flags |= fuser::consts::FOPEN_DIRECT_IO;
                     }
                     
-                    reply.created(&TTL, &attrs, 0, 0, open_flags);
+                    self.refresh_cache_stamp(ino, &file);
+                    let fh = self.handles.lock().unwrap().insert(file, self.handle_flags(flags));
+                    reply.created(&self.cache.entry_ttl, &attrs, inodes.generation(ino), fh, open_flags);
                     trace!("create({:?}) - EXIT (OK)", name);
                 } else {
                     reply.error(EIO);
@@ -502,42 +1610,57 @@ impl Filesystem for FuseLogFS {
         }
     }
 
-    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
-        debug!("read(ino={}, offset={}, size={})", ino, offset, size);
+    fn read(&self, _req: &Caller, ino: u64, fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
+        debug!("read(ino={}, fh={}, offset={}, size={})", ino, fh, offset, size);
         
-        let inodes = self.inodes.lock().unwrap();
-        
-        let path = match inodes.get_path(ino) {
-            Some(p) => p.clone(),
+        let handle = self.handles.lock().unwrap().file(fh);
+        let file = match handle {
+            Some((file, _)) => file,
             None => {
-                reply.error(ENOENT);
-                return;
+                let path = match self.inodes.read().unwrap().get_path(ino) {
+                    Some(p) => p.clone(),
+                    None => {
+                        reply.error(ENOENT);
+                        return;
+                    }
+                };
+                match self.layer(&path).open_file(&path, libc::O_RDONLY, 0) {
+                    Ok(f) => Arc::new(f),
+                    Err(e) => {
+                        reply.error(e.raw_os_error().unwrap_or(EIO));
+                        return;
+                    }
+                }
             }
         };
-        
-        match File::open(&path) {
-            Ok(mut file) => {
-                if let Err(e) = file.seek(SeekFrom::Start(offset as u64)) {
+
+        // FUSE treats a short read as EOF, so keep going until the buffer is full or we hit it
+        let mut buffer = vec![0u8; size as usize];
+        let mut bytes_read = 0;
+        while bytes_read < buffer.len() {
+            match file.read_at(&mut buffer[bytes_read..], offset as u64 + bytes_read as u64) {
+                Ok(0) => break,
+                Ok(n) => bytes_read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
                     reply.error(e.raw_os_error().unwrap_or(EIO));
                     return;
                 }
-                
-                let mut buffer = vec![0u8; size as usize];
-                match file.read(&mut buffer) {
-                    Ok(bytes_read) => {
-                        reply.data(&buffer[..bytes_read]);
-                    }
-                    Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
-                }
             }
-            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
         }
+        reply.data(&buffer[..bytes_read]);
     }
 
-    fn write(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
-        debug!("write(ino={}, offset={}, size={}, coalescing={})", ino, offset, data.len(), self.write_coalescing);
+    fn write(&self, _req: &Caller, ino: u64, fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
+        debug!("write(ino={}, fh={}, offset={}, size={}, coalescing={})", ino, fh, offset, data.len(), self.write_coalescing);
 
-        let inodes = self.inodes.lock().unwrap();
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let inodes = self.inodes.read().unwrap();
+        let _data_guard = self.data_lock(ino);
         let path = match inodes.get_path(ino) {
             Some(p) => p.clone(),
             None => {
@@ -546,22 +1669,29 @@ impl Filesystem for FuseLogFS {
             }
         };
 
+        let handle = self.handles.lock().unwrap().file(fh);
+        let (file, append) = match handle {
+            Some((file, flags)) => (file, (flags & libc::O_APPEND) != 0),
+            None => match self.copy_up(&path, None).and_then(|_| self.backing.open_file(&path, libc::O_WRONLY, 0)) {
+                Ok(f) => (Arc::new(f), false),
+                Err(e) => {
+                    reply.error(e.raw_os_error().unwrap_or(EIO));
+                    return;
+                }
+            },
+        };
+
         if self.write_coalescing {
             // 1. Read the old data
-            let old_data: Vec<u8> = match File::open(&path) {
-                Ok(mut old_file) => {
-                    // Seek the offset
-                    if old_file.seek(SeekFrom::Start(offset as u64)).is_ok() {
-                        let mut buffer = vec![0; data.len()];
-                        match old_file.read(&mut buffer) {
-                            Ok(bytes_read) => {
-                                buffer.truncate(bytes_read);
-                                buffer
-                            }
-                            Err(_) => Vec::new(),
+            let old_data: Vec<u8> = match self.backing.open_file(&path, libc::O_RDONLY, 0) {
+                Ok(old_file) => {
+                    let mut buffer = vec![0; data.len()];
+                    match old_file.read_at(&mut buffer, offset as u64) {
+                        Ok(bytes_read) => {
+                            buffer.truncate(bytes_read);
+                            buffer
                         }
-                    } else {
-                        Vec::new()
+                        Err(_) => Vec::new(),
                     }
                 }
                 Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
@@ -569,149 +1699,136 @@ impl Filesystem for FuseLogFS {
             };
 
             // 2. Perform the actual write to the underlying filesystem.
-            match OpenOptions::new().write(true).create(true).open(&path) {
-                Ok(mut file) => {
-                    if let Err(e) = file.seek(SeekFrom::Start(offset as u64)) {
-                        reply.error(e.raw_os_error().unwrap_or(EIO));
-                        return;
-                    }
+            let offset = match write_backing(&file, append, offset as u64, data) {
+                Ok(actual_offset) => actual_offset,
+                Err(e) => {
+                    reply.error(e.raw_os_error().unwrap_or(EIO));
+                    return;
+                }
+            };
 
-                    match file.write_all(data) {
-                        Ok(_) => {
-
-                            // Compute per action overhead using an empty write action
-                            let fid_for_overhead = {
-                                let mut log = STATEDIFF_LOG.lock().unwrap();
-                                get_fid(&mut log, &self.get_relative_path(&path))
-                            };
-                            let overhead_probe = StateDiffAction::Write {
-                                fid: fid_for_overhead,
-                                offset: 0,
-                                data: Vec::new(),
-                            };
-                            let overhead_bytes = encode_to_vec(&overhead_probe, config::standard())
-                                .map(|v| v.len())
-                                .unwrap_or(0)
-                                .max(1); 
-
-                            // 3. Comparing old and new data to find and log differences.
-                            let mut coalesced_writes = Vec::new();
-                            let mut i = 0;
-                            while i < data.len() {
-                                let old_byte = old_data.get(i);
-                                let new_byte = data[i];
-        
-                                if old_byte.map_or(true, |&b| b != new_byte) {
-                                    let chunk_start_index = i;
-                                    let mut chunk_data = vec![new_byte];
-                                    i += 1;
-
-                                    while i < data.len() {
-                                        let next_old_byte = old_data.get(i);
-                                        let next_new_byte = data[i];
-                                        if next_old_byte.map_or(true, |&b| b != next_new_byte) {
-                                            chunk_data.push(next_new_byte);
-                                            i += 1;
-                                        } else {
-                                            let mut match_run = 1;
-                                            while i + match_run < data.len() {
-                                                let further_old = old_data.get(i + match_run);
-                                                let further_new = data[i + match_run];
-                                                if further_old.map_or(true, |&b| b != further_new) {
-                                                    break;
-                                                }
-                                                match_run += 1;
-                                            }
-
-                                            if match_run < overhead_bytes {
-                                                for k in 0..match_run {
-                                                    chunk_data.push(data[i + k]);
-                                                }
-                                                i += match_run;
-                                                continue;
-                                            } else {
-                                                break;
-                                            }
-                                        }
-                                    }
-                                    
-                                    coalesced_writes.push((
-                                        offset as u64 + chunk_start_index as u64,
-                                        chunk_data,
-                                    ));
-                                } else {
-                                    i += 1;
+            // Compute per action overhead using an empty write action
+            let fid_for_overhead = {
+                let mut log = STATEDIFF_LOG.lock().unwrap();
+                get_fid(&mut log, &self.get_relative_path(&path))
+            };
+            let overhead_probe = StateDiffAction::Write {
+                fid: fid_for_overhead,
+                offset: 0,
+                data: Vec::new(),
+            };
+            let overhead_bytes = encode_to_vec(&overhead_probe, config::standard())
+                .map(|v| v.len())
+                .unwrap_or(0)
+                .max(1); 
+
+            // 3. Comparing old and new data to find and log differences.
+            let mut coalesced_writes = Vec::new();
+            let mut i = 0;
+            while i < data.len() {
+                let old_byte = old_data.get(i);
+                let new_byte = data[i];
+
+                if old_byte.is_none_or(|&b| b != new_byte) {
+                    let chunk_start_index = i;
+                    let mut chunk_data = vec![new_byte];
+                    i += 1;
+
+                    while i < data.len() {
+                        let next_old_byte = old_data.get(i);
+                        let next_new_byte = data[i];
+                        if next_old_byte.is_none_or(|&b| b != next_new_byte) {
+                            chunk_data.push(next_new_byte);
+                            i += 1;
+                        } else {
+                            let mut match_run = 1;
+                            while i + match_run < data.len() {
+                                let further_old = old_data.get(i + match_run);
+                                let further_new = data[i + match_run];
+                                if further_old.is_none_or(|&b| b != further_new) {
+                                    break;
                                 }
+                                match_run += 1;
                             }
 
-                            if !coalesced_writes.is_empty() {
-                                let total_coalesced_bytes = coalesced_writes.iter().map(|(_, d)| d.len()).sum::<usize>();
-                                info!(
-                                    "Coalesced write of {} bytes into {} chunk(s) ({} total bytes) for {:?}",
-                                    data.len(),
-                                    coalesced_writes.len(),
-                                    total_coalesced_bytes,
-                                    &path
-                                );
-
-                                let relative_path = self.get_relative_path(&path);
-                                let mut log = STATEDIFF_LOG.lock().unwrap();
-                                let fid = get_fid(&mut log, &relative_path);
-
-                                for (chunk_offset, chunk_data) in coalesced_writes {
-                                    log.actions.push(StateDiffAction::Write {
-                                        fid,
-                                        offset: chunk_offset,
-                                        data: chunk_data,
-                                    });
+                            if match_run < overhead_bytes {
+                                for k in 0..match_run {
+                                    chunk_data.push(data[i + k]);
                                 }
+                                i += match_run;
+                                continue;
                             } else {
-                                info!("Redundant write to {:?} (no changes detected), not logging.", &path);
+                                break;
                             }
-                            
-                            reply.written(data.len() as u32);
                         }
-                        Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
                     }
+                    
+                    coalesced_writes.push((
+                        offset + chunk_start_index as u64,
+                        chunk_data,
+                    ));
+                } else {
+                    i += 1;
                 }
-                Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
             }
+
+            if !coalesced_writes.is_empty() {
+                let total_coalesced_bytes = coalesced_writes.iter().map(|(_, d)| d.len()).sum::<usize>();
+                info!(
+                    "Coalesced write of {} bytes into {} chunk(s) ({} total bytes) for {:?}",
+                    data.len(),
+                    coalesced_writes.len(),
+                    total_coalesced_bytes,
+                    &path
+                );
+
+                let relative_path = self.get_relative_path(&path);
+                let mut log = STATEDIFF_LOG.lock().unwrap();
+                let fid = get_fid(&mut log, &relative_path);
+
+                for (chunk_offset, chunk_data) in coalesced_writes {
+                    log.push(StateDiffAction::Write {
+                        fid,
+                        offset: chunk_offset,
+                        data: chunk_data,
+                    });
+                }
+            } else {
+                info!("Redundant write to {:?} (no changes detected), not logging.", &path);
+            }
+            
+            reply.written(data.len() as u32);
         } else {
             info!("Write coalescing disabled. Logging full write of {} bytes to {:?}", data.len(), &path);
 
-            match OpenOptions::new().write(true).create(true).open(&path) {
-                Ok(mut file) => {
-                    if let Err(e) = file.seek(SeekFrom::Start(offset as u64)) {
-                        reply.error(e.raw_os_error().unwrap_or(EIO));
-                        return;
-                    }
+            match write_backing(&file, append, offset as u64, data) {
+                Ok(actual_offset) => {
+                    let relative_path = self.get_relative_path(&path);
+                    let mut log = STATEDIFF_LOG.lock().unwrap();
+                    let fid = get_fid(&mut log, &relative_path);
+                    
+                    log.push(StateDiffAction::Write {
+                        fid,
+                        offset: actual_offset,
+                        data: data.to_vec(),
+                    });
 
-                    match file.write_all(data) {
-                        Ok(_) => {
-                            let relative_path = self.get_relative_path(&path);
-                            let mut log = STATEDIFF_LOG.lock().unwrap();
-                            let fid = get_fid(&mut log, &relative_path);
-                            
-                            log.actions.push(StateDiffAction::Write {
-                                fid,
-                                offset: offset as u64,
-                                data: data.to_vec(),
-                            });
-
-                            reply.written(data.len() as u32);
-                        }
-                        Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
-                    }
+                    reply.written(data.len() as u32);
                 }
                 Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
             }
         }
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn unlink(&self, _req: &Caller, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         debug!("unlink(parent={}, name={:?})", parent, name);
+
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
         
-        let mut inodes = self.inodes.lock().unwrap();
+        let mut inodes = self.inodes.write().unwrap();
         
         let parent_path = match inodes.get_path(parent) {
             Some(p) => p.clone(),
@@ -724,14 +1841,21 @@ impl Filesystem for FuseLogFS {
         let file_path = parent_path.join(name);
         let relative_path = self.get_relative_path(&file_path);
         
-        match std::fs::remove_file(&file_path) {
+        // Other names of a hard-linked file keep the inode alive
+        let metadata = self.layer(&file_path).symlink_metadata(&file_path).ok();
+        let still_linked = metadata.as_ref().is_some_and(|m| m.nlink() > 1);
+
+        match self.remove_entry(&file_path, false) {
             Ok(_) => {
-                if let Some(ino) = inodes.remove_path(&file_path) {
+                if !still_linked && let Some(metadata) = &metadata {
+                    self.ownership.forget(metadata);
+                }
+                if let Some(ino) = inodes.unlink_path(&file_path, still_linked) {
                     debug!("Removed inode {} for path {:?}", ino, file_path);
                 }
                 let mut log = STATEDIFF_LOG.lock().unwrap();
                 let fid = get_fid(&mut log, &relative_path);
-                log.actions.push(StateDiffAction::Unlink { fid });
+                log.push(StateDiffAction::Unlink { fid });
                 info!("Unlinked and logged file: {:?}", file_path);
                 reply.ok();
             }
@@ -739,10 +1863,17 @@ impl Filesystem for FuseLogFS {
         }
     }
 
-    fn setattr(&mut self, _req: &Request<'_>, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, _atime: Option<TimeOrNow>, _mtime: Option<TimeOrNow>, _ctime: Option<SystemTime>, _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>, reply: ReplyAttr) {
-        debug!("setattr(ino={}, mode={:?}, uid={:?}, gid={:?}, size={:?})", ino, mode, uid, gid, size);
+    fn setattr(&self, _req: &Caller, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>, size: Option<u64>, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>, _ctime: Option<SystemTime>, fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>, reply: ReplyAttr) {
+        debug!("setattr(ino={}, mode={:?}, uid={:?}, gid={:?}, size={:?}, atime={:?}, mtime={:?})", ino, mode, uid, gid, size, atime, mtime);
+
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
     
-        let inodes = self.inodes.lock().unwrap();
+        let staged = self.stage_copy_up_ino(ino);
+        let inodes = self.inodes.read().unwrap();
+        let _data_guard = self.data_lock(ino);
         let path = match inodes.get_path(ino) {
             Some(p) => p.clone(),
             None => {
@@ -752,12 +1883,17 @@ impl Filesystem for FuseLogFS {
         };
         let relative_path = self.get_relative_path(&path);
 
+        if let Err(e) = self.copy_up(&path, staged) {
+            reply.error(e.raw_os_error().unwrap_or(EIO));
+            return;
+        }
+
         if let Some(new_mode) = mode {
-            match std::fs::set_permissions(&path, std::fs::Permissions::from_mode(new_mode)) {
+            match self.ownership.chmod(&self.backing, &path, new_mode) {
                 Ok(_) => {
                     let mut log = STATEDIFF_LOG.lock().unwrap();
                     let fid = get_fid(&mut log, &relative_path);
-                    log.actions.push(StateDiffAction::Chmod { fid, mode: new_mode });
+                    log.push(StateDiffAction::Chmod { fid, mode: new_mode });
                     info!("Logged chmod for {:?} to {:o}", path, new_mode);
                 }
                 Err(e) => {
@@ -769,15 +1905,20 @@ impl Filesystem for FuseLogFS {
         }
 
         if let Some(new_size) = size {
-            match std::fs::OpenOptions::new().write(true).open(&path) {
-                Ok(file) => {
-                    if let Err(e) = file.set_len(new_size) {
-                        reply.error(e.raw_os_error().unwrap_or(EIO));
-                        return;
-                    }
+            // ftruncate through the caller's handle when it has one open for writing
+            let writable_handle = fh
+                .and_then(|fh| self.handles.lock().unwrap().file(fh))
+                .filter(|(_, flags)| (flags & libc::O_ACCMODE) != libc::O_RDONLY);
+            let truncate_result = match writable_handle {
+                Some((file, _)) => file.set_len(new_size),
+                None => self.backing.open_file(&path, libc::O_WRONLY, 0).and_then(|file| file.set_len(new_size)),
+            };
+
+            match truncate_result {
+                Ok(_) => {
                     let mut log = STATEDIFF_LOG.lock().unwrap();
                     let fid = get_fid(&mut log, &relative_path);
-                    log.actions.push(StateDiffAction::Truncate { fid, size: new_size });
+                    log.push(StateDiffAction::Truncate { fid, size: new_size });
                     info!("Logged truncate for {:?} to {}", path, new_size);
                 }
                 Err(e) => {
@@ -788,28 +1929,26 @@ impl Filesystem for FuseLogFS {
         }
 
         if uid.is_some() || gid.is_some() {
-            let current_meta = match std::fs::symlink_metadata(&path) {
+            let current_meta = match self.backing.symlink_metadata(&path) {
                 Ok(meta) => meta,
                 Err(e) => {
                      reply.error(e.raw_os_error().unwrap_or(EIO));
                      return;
                 }
             };
-            let final_uid = uid.unwrap_or_else(|| current_meta.uid());
-            let final_gid = gid.unwrap_or_else(|| current_meta.gid());
+            let current_attrs = self.file_attr(ino, &path, &current_meta);
+            let final_uid = uid.unwrap_or(current_attrs.uid);
+            let final_gid = gid.unwrap_or(current_attrs.gid);
             
             // Use lchown for symlinks, chown for other file types
-            let chown_result = if current_meta.file_type().is_symlink() {
-                std::os::unix::fs::lchown(&path, Some(final_uid), Some(final_gid))
-            } else {
-                std::os::unix::fs::chown(&path, Some(final_uid), Some(final_gid))
-            };
+            let follow = !current_meta.file_type().is_symlink();
+            let chown_result = self.ownership.chown(&self.backing, &path, Some(final_uid), Some(final_gid), follow);
 
             match chown_result {
                 Ok(_) => {
                     let mut log = STATEDIFF_LOG.lock().unwrap();
                     let fid = get_fid(&mut log, &relative_path);
-                    log.actions.push(StateDiffAction::Chown { fid, uid: final_uid, gid: final_gid });
+                    log.push(StateDiffAction::Chown { fid, uid: final_uid, gid: final_gid });
                     info!("Logged chown for {:?} to {}:{}", path, final_uid, final_gid);
                 }
                 Err(e) => {
@@ -819,30 +1958,113 @@ impl Filesystem for FuseLogFS {
                 }
             }
         }
+
+        // Times go last so that a truncate in the same request doesn't clobber mtime
+        if atime.is_some() || mtime.is_some() {
+            let times = [time_or_now_to_timespec(atime), time_or_now_to_timespec(mtime)];
+            if let Err(e) = self.backing.set_times(&path, &times) {
+                error!("Failed to set times on {:?}: {}", path, e);
+                reply.error(e.raw_os_error().unwrap_or(EIO));
+                return;
+            }
+
+            // Log what actually landed on disk so TimeOrNow::Now replays as the same instant
+            match self.backing.symlink_metadata(&path) {
+                Ok(meta) => {
+                    let logged_atime = atime.map(|_| (meta.atime(), meta.atime_nsec() as u32));
+                    let logged_mtime = mtime.map(|_| (meta.mtime(), meta.mtime_nsec() as u32));
+                    let mut log = STATEDIFF_LOG.lock().unwrap();
+                    let fid = get_fid(&mut log, &relative_path);
+                    log.push(StateDiffAction::SetTimes { fid, atime: logged_atime, mtime: logged_mtime });
+                    info!("Logged set times for {:?}: atime={:?}, mtime={:?}", path, logged_atime, logged_mtime);
+                }
+                Err(e) => {
+                    reply.error(e.raw_os_error().unwrap_or(EIO));
+                    return;
+                }
+            }
+        }
     
-        match std::fs::symlink_metadata(&path) {
+        match self.backing.symlink_metadata(&path) {
             Ok(metadata) => {
-                let attrs = metadata_to_file_attr(ino, &metadata);
-                reply.attr(&TTL, &attrs);
+                let attrs = self.file_attr(ino, &path, &metadata);
+                reply.attr(&self.cache.attr_ttl, &attrs);
             }
             Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
         }
     }    
 
-    fn release(&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
-        debug!("release called");
+    fn release(&self, _req: &Caller, ino: u64, fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
+        debug!("release(ino={}, fh={})", ino, fh);
+
+        let handle = self.handles.lock().unwrap().remove(fh);
+        match handle {
+            // Whatever the kernel cached is current as of this close
+            Some(handle) => {
+                self.refresh_cache_stamp(ino, &handle.file);
+            }
+            None => warn!("release called for unknown fh {} (ino {})", fh, ino),
+        }
         reply.ok();
     }
 
-    fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        debug!("flush called");
+    fn flush(&self, _req: &Caller, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        debug!("flush(ino={}, fh={}, lock_owner={})", ino, fh, lock_owner);
+
+        // POSIX semantics: any close by a process drops all its locks on the file
+        self.locks.lock().unwrap().release_owner(ino, lock_owner);
         reply.ok();
     }
 
-    fn rename(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, _flags: u32, reply: ReplyEmpty) {
-        debug!("rename(parent={}, name={:?}, newparent={}, newname={:?})", parent, name, newparent, newname);
+    fn getlk(&self, _req: &Caller, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, reply: ReplyLock) {
+        debug!("getlk(ino={}, fh={}, lock_owner={}, start={}, end={}, typ={}, pid={})", ino, fh, lock_owner, start, end, typ, pid);
+
+        match self.locks.lock().unwrap().find_conflict(ino, lock_owner, start, end, typ) {
+            Some((l_start, l_end, l_typ, l_pid)) => reply.locked(l_start, l_end, l_typ, l_pid),
+            None => reply.locked(start, end, libc::F_UNLCK, 0),
+        }
+    }
+
+    fn setlk(&self, _req: &Caller, ino: u64, fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        debug!("setlk(ino={}, fh={}, lock_owner={}, start={}, end={}, typ={}, pid={}, sleep={})", ino, fh, lock_owner, start, end, typ, pid, sleep);
+
+        if typ != libc::F_RDLCK && typ != libc::F_WRLCK && typ != libc::F_UNLCK {
+            reply.error(libc::EINVAL);
+            return;
+        }
+
+        if typ != libc::F_UNLCK {
+            // Lock type must match how the file was opened, as with fcntl on a real fd
+            let handles = self.handles.lock().unwrap();
+            if let Some(handle) = handles.get(fh) {
+                let access = handle.flags & libc::O_ACCMODE;
+                if (typ == libc::F_RDLCK && access == libc::O_WRONLY) || (typ == libc::F_WRLCK && access == libc::O_RDONLY) {
+                    reply.error(libc::EBADF);
+                    return;
+                }
+            }
+        }
+
+        self.locks.lock().unwrap().set_lock(ino, lock_owner, start, end, typ, pid, sleep, reply);
+    }
+
+    fn rename(&self, _req: &Caller, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32, reply: ReplyEmpty) {
+        debug!("rename(parent={}, name={:?}, newparent={}, newname={:?}, flags=0x{:x})", parent, name, newparent, newname, flags);
+
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let noreplace = (flags & libc::RENAME_NOREPLACE) != 0;
+        let exchange = (flags & libc::RENAME_EXCHANGE) != 0;
+        if (flags & !(libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE)) != 0 || (noreplace && exchange) {
+            reply.error(libc::EINVAL);
+            return;
+        }
 
-        let mut inodes = self.inodes.lock().unwrap();
+        let staged = self.stage_copy_up_child(parent, name);
+        let mut inodes = self.inodes.write().unwrap();
 
         let from_parent_path = match inodes.get_path(parent) {
             Some(p) => p.clone(),
@@ -855,35 +2077,77 @@ impl Filesystem for FuseLogFS {
             None => { reply.error(ENOENT); return; }
         };
         let to_path = to_parent_path.join(newname);
+        // A file replaced here may live on under other names
+        let replaced = if exchange { None } else { self.layer(&to_path).symlink_metadata(&to_path).ok() };
+        let to_still_linked = replaced.as_ref().is_some_and(|m| !m.is_dir() && m.nlink() > 1);
+        let from_metadata = self.layer(&from_path).symlink_metadata(&from_path).ok();
+        let to_metadata = if exchange { self.layer(&to_path).symlink_metadata(&to_path).ok() } else { None };
+
+        // renameat2 makes NOREPLACE atomic on the backing store instead of check-then-rename
+        let renamed = match &self.overlay {
+            Some(overlay) => overlay
+                .check_rename(&from_path, &to_path, flags)
+                .and_then(|_| self.copy_up(&from_path, staged))
+                .and_then(|_| overlay.rename(&from_path, &to_path, flags)),
+            None => self.backing.rename(&from_path, &to_path, flags),
+        };
+        if let Err(e) = renamed {
+            reply.error(e.raw_os_error().unwrap_or(EIO));
+            return;
+        }
 
-        match std::fs::rename(&from_path, &to_path) {
-            Ok(_) => {
-                if let Some(ino) = inodes.remove_path(&from_path) {
-                    inodes.ino_to_path.insert(ino, to_path.clone());
-                    inodes.path_to_ino.insert(to_path.clone(), ino);
-                    info!("Updated inode mapping: ino {} from {:?} to {:?}", ino, from_path, to_path);
-                }
+        // Ownership recorded by backing inode goes with the entries, across a
+        // copy-up too, and a file that's gone takes its record with it
+        let moved = [(&from_metadata, &to_path), (&to_metadata, &from_path)];
+        for (before, path) in moved {
+            if let Some(before) = before
+                && let Ok(after) = self.backing.symlink_metadata(path)
+            {
+                self.ownership.moved(before, &after);
+            }
+        }
+        if let Some(replaced) = &replaced
+            && !to_still_linked
+            && from_metadata.as_ref().is_none_or(|m| (m.dev(), m.ino()) != (replaced.dev(), replaced.ino()))
+        {
+            self.ownership.forget(replaced);
+        }
 
-                let relative_from_path = self.get_relative_path(&from_path);
-                let relative_to_path = self.get_relative_path(&to_path);
+        if exchange {
+            inodes.exchange_paths(&from_path, &to_path);
+            info!("Exchanged inode mappings of {:?} and {:?}", from_path, to_path);
+        } else if let Some(ino) = inodes.rename_path(&from_path, &to_path, to_still_linked) {
+            info!("Updated inode mapping: ino {} from {:?} to {:?}", ino, from_path, to_path);
+        }
 
-                let mut log = STATEDIFF_LOG.lock().unwrap();
-                let from_fid = get_fid(&mut log, &relative_from_path);
-                let to_fid = get_fid(&mut log, &relative_to_path);
+        let relative_from_path = self.get_relative_path(&from_path);
+        let relative_to_path = self.get_relative_path(&to_path);
 
-                log.actions.push(StateDiffAction::Rename { from_fid, to_fid });
-                info!("Renamed {:?} to {:?}, logging action", from_path, to_path);
+        let mut log = STATEDIFF_LOG.lock().unwrap();
+        let from_fid = get_fid(&mut log, &relative_from_path);
+        let to_fid = get_fid(&mut log, &relative_to_path);
 
-                reply.ok();
-            }
-            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        if exchange {
+            log.push(StateDiffAction::Exchange { a_fid: from_fid, b_fid: to_fid });
+            info!("Exchanged {:?} and {:?}, logging action", from_path, to_path);
+        } else {
+            log.push(StateDiffAction::Rename { from_fid, to_fid, noreplace });
+            info!("Renamed {:?} to {:?}, logging action", from_path, to_path);
         }
+
+        reply.ok();
     }
 
-    fn link(&mut self, _req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+    fn link(&self, _req: &Caller, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
         debug!("link(ino={}, newparent={}, newname={:?})", ino, newparent, newname);
 
-        let mut inodes = self.inodes.lock().unwrap();
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let staged = self.stage_copy_up_ino(ino);
+        let mut inodes = self.inodes.write().unwrap();
 
         let source_path = match inodes.get_path(ino) {
             Some(p) => p.clone(),
@@ -897,11 +2161,17 @@ impl Filesystem for FuseLogFS {
 
         let dest_path = dest_parent_path.join(newname);
 
-        match std::fs::hard_link(&source_path, &dest_path) {
+        let linked = self
+            .copy_up(&source_path, staged)
+            .and_then(|_| self.prepare_create(&dest_path))
+            .and_then(|_| self.backing.hard_link(&source_path, &dest_path));
+        match linked {
             Ok(_) => {
+                self.finish_create(&dest_path, false);
                 info!("Created hard link from {:?} to {:?}", source_path, dest_path);
                 
-                inodes.path_to_ino.insert(dest_path.clone(), ino);
+                inodes.add_link(ino, &dest_path);
+                inodes.add_lookup(ino);
 
                 let relative_source_path = self.get_relative_path(&source_path);
                 let relative_dest_path = self.get_relative_path(&dest_path);
@@ -910,12 +2180,12 @@ impl Filesystem for FuseLogFS {
                 let source_fid = get_fid(&mut log, &relative_source_path);
                 let new_link_fid = get_fid(&mut log, &relative_dest_path);
                 
-                log.actions.push(StateDiffAction::Link { source_fid, new_link_fid });
+                log.push(StateDiffAction::Link { source_fid, new_link_fid });
 
-                match std::fs::metadata(&dest_path) {
+                match self.backing.metadata(&dest_path) {
                     Ok(metadata) => {
-                        let attrs = metadata_to_file_attr(ino, &metadata);
-                        reply.entry(&TTL, &attrs, 0);
+                        let attrs = self.file_attr(ino, &dest_path, &metadata);
+                        reply.entry(&self.cache.entry_ttl, &attrs, inodes.generation(ino));
                     }
                     Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
                 }
@@ -924,13 +2194,13 @@ impl Filesystem for FuseLogFS {
         }
     }
 
-    fn fsync(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, datasync: bool, reply: ReplyEmpty) {
+    fn fsync(&self, _req: &Caller, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         // I think I don't need to log it in the StateDiffLog
         // because fsync doesn't change file content or metadata;
-        debug!("fsync(ino={}, datasync={})", ino, datasync);
+        debug!("fsync(ino={}, fh={}, datasync={})", ino, fh, datasync);
 
-        let inodes = self.inodes.lock().unwrap();
-        let path = match inodes.get_path(ino) {
+        // Nothing is logged, so no lock is held while the sync runs
+        let path = match self.inodes.read().unwrap().get_path(ino) {
             Some(p) => p.clone(),
             None => {
                 reply.error(ENOENT);
@@ -938,18 +2208,22 @@ impl Filesystem for FuseLogFS {
             }
         };
 
-        let file = match std::fs::File::open(&path) {
-            Ok(f) => f,
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                warn!("fsync called on non-existent file (ino {}): {:?}", ino, path);
-                reply.error(ENOENT);
-                return;
-            }
-            Err(e) => {
-                error!("Failed to open file for fsync {:?}: {}", path, e);
-                reply.error(e.raw_os_error().unwrap_or(EIO));
-                return;
-            }
+        let handle = self.handles.lock().unwrap().file(fh);
+        let file = match handle {
+            Some((file, _)) => file,
+            None => match self.layer(&path).open_file(&path, libc::O_RDONLY, 0) {
+                Ok(f) => Arc::new(f),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    warn!("fsync called on non-existent file (ino {}): {:?}", ino, path);
+                    reply.error(ENOENT);
+                    return;
+                }
+                Err(e) => {
+                    error!("Failed to open file for fsync {:?}: {}", path, e);
+                    reply.error(e.raw_os_error().unwrap_or(EIO));
+                    return;
+                }
+            },
         };
 
         let sync_result = if datasync {
@@ -972,4 +2246,374 @@ impl Filesystem for FuseLogFS {
         }
     }
 
+    fn setxattr(&self, _req: &Caller, ino: u64, name: &OsStr, value: &[u8], flags: i32, _position: u32, reply: ReplyEmpty) {
+        debug!("setxattr(ino={}, name={:?}, size={}, flags=0x{:x})", ino, name, value.len(), flags);
+
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        if self.ownership.is_reserved(name.as_bytes()) {
+            reply.error(libc::EPERM);
+            return;
+        }
+
+        let staged = self.stage_copy_up_ino(ino);
+        let inodes = self.inodes.read().unwrap();
+        let _data_guard = self.data_lock(ino);
+        let path = match inodes.get_path(ino) {
+            Some(p) => p.clone(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        if let Err(e) = self.copy_up(&path, staged) {
+            reply.error(e.raw_os_error().unwrap_or(EIO));
+            return;
+        }
+
+        let (c_path, c_name) = match (to_cstring(self.backing.proc_path(&path).as_os_str()), to_cstring(name)) {
+            (Some(p), Some(n)) => (p, n),
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        // Use lsetxattr so attributes land on a symlink itself, not its target
+        let res = unsafe {
+            libc::lsetxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), flags)
+        };
+        if res != 0 {
+            reply.error(last_errno());
+            return;
+        }
+
+        let relative_path = self.get_relative_path(&path);
+        let mut log = STATEDIFF_LOG.lock().unwrap();
+        let fid = get_fid(&mut log, &relative_path);
+        log.push(StateDiffAction::SetXattr {
+            fid,
+            name: name.as_bytes().to_vec(),
+            value: value.to_vec(),
+        });
+        info!("Logged setxattr for {:?}: {:?} ({} bytes)", path, name, value.len());
+
+        reply.ok();
+    }
+
+    fn getxattr(&self, _req: &Caller, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!("getxattr(ino={}, name={:?}, size={})", ino, name, size);
+
+        if self.ownership.is_reserved(name.as_bytes()) {
+            reply.error(libc::ENODATA);
+            return;
+        }
+
+        let inodes = self.inodes.read().unwrap();
+        let path = match inodes.get_path(ino) {
+            Some(p) => p.clone(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let (c_path, c_name) = match (to_cstring(self.layer(&path).proc_path(&path).as_os_str()), to_cstring(name)) {
+            (Some(p), Some(n)) => (p, n),
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        // A zero size is the kernel asking how big the value is
+        if size == 0 {
+            let len = unsafe { libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+            if len < 0 {
+                reply.error(last_errno());
+            } else {
+                reply.size(len as u32);
+            }
+            return;
+        }
+
+        let mut buffer = vec![0u8; size as usize];
+        let len = unsafe {
+            libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buffer.as_mut_ptr() as *mut libc::c_void, buffer.len())
+        };
+        if len < 0 {
+            reply.error(last_errno());
+        } else {
+            reply.data(&buffer[..len as usize]);
+        }
+    }
+
+    fn listxattr(&self, _req: &Caller, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("listxattr(ino={}, size={})", ino, size);
+
+        let inodes = self.inodes.read().unwrap();
+        let path = match inodes.get_path(ino) {
+            Some(p) => p.clone(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let Some(c_path) = to_cstring(self.layer(&path).proc_path(&path).as_os_str()) else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+
+        // Read the whole list so that names of our own can be left out of it
+        let mut buffer = Vec::new();
+        loop {
+            let len = unsafe { libc::llistxattr(c_path.as_ptr(), std::ptr::null_mut(), 0) };
+            if len < 0 {
+                reply.error(last_errno());
+                return;
+            }
+            buffer.resize(len as usize, 0);
+            let len = unsafe {
+                libc::llistxattr(c_path.as_ptr(), buffer.as_mut_ptr() as *mut libc::c_char, buffer.len())
+            };
+            if len >= 0 {
+                buffer.truncate(len as usize);
+                break;
+            }
+            // The list grew in between; ask again
+            if last_errno() != libc::ERANGE {
+                reply.error(last_errno());
+                return;
+            }
+        }
+        let names: Vec<u8> = buffer
+            .split_inclusive(|&b| b == 0)
+            .filter(|name| !self.ownership.is_reserved(name))
+            .flatten()
+            .copied()
+            .collect();
+
+        // A zero size is the kernel asking how big the list is
+        if size == 0 {
+            reply.size(names.len() as u32);
+        } else if names.len() > size as usize {
+            reply.error(libc::ERANGE);
+        } else {
+            reply.data(&names);
+        }
+    }
+
+    fn removexattr(&self, _req: &Caller, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("removexattr(ino={}, name={:?})", ino, name);
+
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        if self.ownership.is_reserved(name.as_bytes()) {
+            reply.error(libc::EPERM);
+            return;
+        }
+
+        let staged = self.stage_copy_up_ino(ino);
+        let inodes = self.inodes.read().unwrap();
+        let _data_guard = self.data_lock(ino);
+        let path = match inodes.get_path(ino) {
+            Some(p) => p.clone(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        if let Err(e) = self.copy_up(&path, staged) {
+            reply.error(e.raw_os_error().unwrap_or(EIO));
+            return;
+        }
+
+        let (c_path, c_name) = match (to_cstring(self.backing.proc_path(&path).as_os_str()), to_cstring(name)) {
+            (Some(p), Some(n)) => (p, n),
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        let res = unsafe { libc::lremovexattr(c_path.as_ptr(), c_name.as_ptr()) };
+        if res != 0 {
+            reply.error(last_errno());
+            return;
+        }
+
+        let relative_path = self.get_relative_path(&path);
+        let mut log = STATEDIFF_LOG.lock().unwrap();
+        let fid = get_fid(&mut log, &relative_path);
+        log.push(StateDiffAction::RemoveXattr {
+            fid,
+            name: name.as_bytes().to_vec(),
+        });
+        info!("Logged removexattr for {:?}: {:?}", path, name);
+
+        reply.ok();
+    }
+
+    fn fallocate(&self, _req: &Caller, ino: u64, fh: u64, offset: i64, length: i64, mode: i32, reply: ReplyEmpty) {
+        debug!("fallocate(ino={}, fh={}, offset={}, length={}, mode=0x{:x})", ino, fh, offset, length, mode);
+
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let inodes = self.inodes.read().unwrap();
+        let _data_guard = self.data_lock(ino);
+        let path = match inodes.get_path(ino) {
+            Some(p) => p.clone(),
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let handle = self.handles.lock().unwrap().file(fh);
+        let file = match handle {
+            Some((file, _)) => file,
+            None => match self.copy_up(&path, None).and_then(|_| self.backing.open_file(&path, libc::O_WRONLY, 0)) {
+                Ok(f) => Arc::new(f),
+                Err(e) => {
+                    reply.error(e.raw_os_error().unwrap_or(EIO));
+                    return;
+                }
+            },
+        };
+
+        let res = unsafe { libc::fallocate(file.as_raw_fd(), mode, offset, length) };
+        if res != 0 {
+            reply.error(last_errno());
+            return;
+        }
+
+        let relative_path = self.get_relative_path(&path);
+        let mut log = STATEDIFF_LOG.lock().unwrap();
+        let fid = get_fid(&mut log, &relative_path);
+        if (mode & libc::FALLOC_FL_PUNCH_HOLE) != 0 {
+            log.push(StateDiffAction::PunchHole { fid, offset: offset as u64, length: length as u64 });
+            info!("Logged punch hole for {:?}: offset={}, length={}", path, offset, length);
+        } else {
+            log.push(StateDiffAction::Fallocate { fid, mode, offset: offset as u64, length: length as u64 });
+            info!("Logged fallocate for {:?}: offset={}, length={}, mode=0x{:x}", path, offset, length, mode);
+        }
+
+        reply.ok();
+    }
+
+    fn copy_file_range(&self, _req: &Caller, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, flags: u32, reply: ReplyWrite) {
+        debug!("copy_file_range(ino_in={}, offset_in={}, ino_out={}, offset_out={}, len={}, flags={})", ino_in, offset_in, ino_out, offset_out, len, flags);
+
+        if is_read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        let inodes = self.inodes.read().unwrap();
+        let _data_guards = self.data_lock_pair(ino_in, ino_out);
+        let (src_path, dst_path) = match (inodes.get_path(ino_in), inodes.get_path(ino_out)) {
+            (Some(src), Some(dst)) => (src.clone(), dst.clone()),
+            _ => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let (src_handle, dst_handle) = {
+            let handles = self.handles.lock().unwrap();
+            (handles.file(fh_in), handles.file(fh_out))
+        };
+        let src_file = match src_handle {
+            Some((file, _)) => file,
+            None => match self.layer(&src_path).open_file(&src_path, libc::O_RDONLY, 0) {
+                Ok(f) => Arc::new(f),
+                Err(e) => {
+                    reply.error(e.raw_os_error().unwrap_or(EIO));
+                    return;
+                }
+            },
+        };
+        let dst_file = match dst_handle {
+            Some((file, _)) => file,
+            None => match self.copy_up(&dst_path, None).and_then(|_| self.backing.open_file(&dst_path, libc::O_WRONLY, 0)) {
+                Ok(f) => Arc::new(f),
+                Err(e) => {
+                    reply.error(e.raw_os_error().unwrap_or(EIO));
+                    return;
+                }
+            },
+        };
+
+        // Let the backing filesystem do the copy (or reflink) itself
+        let mut off_in = offset_in;
+        let mut off_out = offset_out;
+        let copied = unsafe {
+            libc::copy_file_range(src_file.as_raw_fd(), &mut off_in, dst_file.as_raw_fd(), &mut off_out, len as usize, flags)
+        };
+        if copied < 0 {
+            reply.error(last_errno());
+            return;
+        }
+
+        if copied > 0 {
+            let relative_src_path = self.get_relative_path(&src_path);
+            let relative_dst_path = self.get_relative_path(&dst_path);
+
+            let mut log = STATEDIFF_LOG.lock().unwrap();
+            let src_fid = get_fid(&mut log, &relative_src_path);
+            let dst_fid = get_fid(&mut log, &relative_dst_path);
+            log.push(StateDiffAction::CopyRange {
+                src_fid,
+                src_off: offset_in as u64,
+                dst_fid,
+                dst_off: offset_out as u64,
+                len: copied as u64,
+            });
+            info!("Logged copy of {} bytes from {:?}@{} to {:?}@{}", copied, src_path, offset_in, dst_path, offset_out);
+        }
+
+        reply.written(copied as u32);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file renamed over keeps its inode for the names it still has
+    #[test]
+    fn rename_over_a_hard_link_keeps_its_inode() {
+        let root = std::env::temp_dir().join(format!("fuselog-inodes-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a"), b"a").unwrap();
+        std::fs::write(root.join("b"), b"b").unwrap();
+        std::fs::hard_link(root.join("b"), root.join("c")).unwrap();
+
+        let backing = Arc::new(BackingDir::open(&root).unwrap());
+        let mut inodes = InodeManager::new(backing, None, InodeMode::Sequential, &root.join("state"));
+        let (a, b, c) = (Path::new("./a"), Path::new("./b"), Path::new("./c"));
+        let a_ino = inodes.lookup_ino(a);
+        let b_ino = inodes.lookup_ino(b);
+
+        std::fs::rename(root.join("a"), root.join("b")).unwrap();
+        assert_eq!(inodes.rename_path(a, b, true), Some(a_ino));
+        assert_eq!(inodes.lookup_ino(b), a_ino);
+        assert_eq!(inodes.lookup_ino(c), b_ino);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -0,0 +1,384 @@
+use fuser::ReplyEmpty;
+use log::debug;
+use std::collections::{HashMap, HashSet};
+
+// Byte-range table for POSIX (fcntl) locks, arbitrated by the lock owner the
+// kernel assigns each process. flock locks are not kept here: fuser drops the
+// FUSE_LK_FLOCK flag that tells the two apart, so init leaves flock to the
+// kernel's own table, which keeps the two kinds independent as Linux does.
+// Ranges are inclusive, as they come from the kernel.
+//
+// A blocking request that would close a cycle of owners waiting on each other
+// fails with EDEADLK instead of parking. A parked request is answered with
+// EINTR when its owner drops its locks on the file, or when it is interrupted
+// (see dispatch.rs).
+
+// How a parked request is answered; ReplyEmpty outside of tests
+pub(crate) trait LockReply {
+    fn ok(self);
+    fn error(self, err: i32);
+}
+
+impl LockReply for ReplyEmpty {
+    fn ok(self) {
+        ReplyEmpty::ok(self)
+    }
+
+    fn error(self, err: i32) {
+        ReplyEmpty::error(self, err)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct LockRange {
+    owner: u64,
+    start: u64,
+    end: u64,
+    typ: i32,
+    pid: u32,
+}
+
+impl LockRange {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts_with(&self, owner: u64, start: u64, end: u64, typ: i32) -> bool {
+        self.owner != owner
+            && self.overlaps(start, end)
+            && (self.typ == libc::F_WRLCK || typ == libc::F_WRLCK)
+    }
+}
+
+struct Waiter<R> {
+    ino: u64,
+    owner: u64,
+    start: u64,
+    end: u64,
+    typ: i32,
+    pid: u32,
+    reply: R,
+}
+
+pub(crate) struct LockTable<R = ReplyEmpty> {
+    locks: HashMap<u64, Vec<LockRange>>,
+    waiters: Vec<Waiter<R>>,
+}
+
+impl<R> Default for LockTable<R> {
+    fn default() -> Self {
+        Self { locks: HashMap::new(), waiters: Vec::new() }
+    }
+}
+
+impl<R: LockReply> LockTable<R> {
+    // Returns (start, end, typ, pid) of the first lock that would block the request
+    pub(crate) fn find_conflict(&self, ino: u64, owner: u64, start: u64, end: u64, typ: i32) -> Option<(u64, u64, i32, u32)> {
+        self.conflicts(ino, owner, start, end, typ)
+            .next()
+            .map(|l| (l.start, l.end, l.typ, l.pid))
+    }
+
+    // Applies a setlk request. Blocking requests that conflict are parked and
+    // answered once the conflicting lock goes away.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn set_lock(&mut self, ino: u64, owner: u64, start: u64, end: u64, typ: i32, pid: u32, sleep: bool, reply: R) {
+        if self.find_conflict(ino, owner, start, end, typ).is_some() {
+            if !sleep {
+                reply.error(libc::EAGAIN);
+            } else if self.would_deadlock(ino, owner, start, end, typ) {
+                debug!("Lock on ino {} [{}, {}] by owner {} would deadlock", ino, start, end, owner);
+                reply.error(libc::EDEADLK);
+            } else {
+                debug!("Lock on ino {} [{}, {}] by owner {} is waiting", ino, start, end, owner);
+                self.waiters.push(Waiter { ino, owner, start, end, typ, pid, reply });
+            }
+            return;
+        }
+
+        self.apply(ino, owner, start, end, typ, pid);
+        reply.ok();
+        self.wake_waiters();
+    }
+
+    // Drops every lock held by owner on ino, e.g. when that owner closes the file.
+    // Its requests still parked on ino fail with EINTR, as nothing would wake them.
+    pub(crate) fn release_owner(&mut self, ino: u64, owner: u64) {
+        let removed = match self.locks.get_mut(&ino) {
+            Some(ranges) => {
+                let before = ranges.len();
+                ranges.retain(|l| l.owner != owner);
+                before != ranges.len()
+            }
+            None => false,
+        };
+        if self.locks.get(&ino).is_some_and(|r| r.is_empty()) {
+            self.locks.remove(&ino);
+        }
+        self.cancel_waiters(|w| w.owner == owner && w.ino == ino);
+        if removed {
+            self.wake_waiters();
+        }
+    }
+
+    // Answers EINTR to parked requests whose caller pid is interrupted
+    pub(crate) fn interrupt_waiters(&mut self, interrupted: impl Fn(u32) -> bool) {
+        self.cancel_waiters(|w| interrupted(w.pid));
+    }
+
+    pub(crate) fn has_waiters(&self) -> bool {
+        !self.waiters.is_empty()
+    }
+
+    fn cancel_waiters(&mut self, cancel: impl Fn(&Waiter<R>) -> bool) {
+        let (cancelled, kept) = std::mem::take(&mut self.waiters).into_iter().partition(|w| cancel(w));
+        self.waiters = kept;
+        for w in cancelled {
+            debug!("Cancelling waiting lock on ino {} [{}, {}] by owner {}", w.ino, w.start, w.end, w.owner);
+            w.reply.error(libc::EINTR);
+        }
+    }
+
+    fn conflicts(&self, ino: u64, owner: u64, start: u64, end: u64, typ: i32) -> impl Iterator<Item = &LockRange> {
+        self.locks
+            .get(&ino)
+            .into_iter()
+            .flatten()
+            .filter(move |l| typ != libc::F_UNLCK && l.conflicts_with(owner, start, end, typ))
+    }
+
+    // Follows owners holding conflicting locks, then whatever their own parked
+    // requests wait on, looking for a path back to owner
+    fn would_deadlock(&self, ino: u64, owner: u64, start: u64, end: u64, typ: i32) -> bool {
+        let mut pending: Vec<u64> = self.conflicts(ino, owner, start, end, typ).map(|l| l.owner).collect();
+        let mut seen = HashSet::new();
+        while let Some(blocker) = pending.pop() {
+            if blocker == owner {
+                return true;
+            }
+            if !seen.insert(blocker) {
+                continue;
+            }
+            for w in self.waiters.iter().filter(|w| w.owner == blocker) {
+                pending.extend(self.conflicts(w.ino, w.owner, w.start, w.end, w.typ).map(|l| l.owner));
+            }
+        }
+        false
+    }
+
+    fn apply(&mut self, ino: u64, owner: u64, start: u64, end: u64, typ: i32, pid: u32) {
+        let ranges = self.locks.entry(ino).or_default();
+
+        // Carve [start, end] out of whatever this owner already holds
+        let mut kept = Vec::with_capacity(ranges.len() + 1);
+        for l in ranges.drain(..) {
+            if l.owner != owner || !l.overlaps(start, end) {
+                kept.push(l);
+                continue;
+            }
+            if l.start < start {
+                kept.push(LockRange { end: start - 1, ..l });
+            }
+            if l.end > end {
+                kept.push(LockRange { start: end + 1, ..l });
+            }
+        }
+        if typ != libc::F_UNLCK {
+            kept.push(LockRange { owner, start, end, typ, pid });
+        }
+        *ranges = kept;
+
+        if ranges.is_empty() {
+            self.locks.remove(&ino);
+        }
+    }
+
+    fn wake_waiters(&mut self) {
+        loop {
+            let ready = self.waiters.iter().position(|w| {
+                self.find_conflict(w.ino, w.owner, w.start, w.end, w.typ).is_none()
+            });
+            let Some(idx) = ready else {
+                break;
+            };
+            let w = self.waiters.remove(idx);
+            debug!("Granting waiting lock on ino {} [{}, {}] to owner {}", w.ino, w.start, w.end, w.owner);
+            self.apply(w.ino, w.owner, w.start, w.end, w.typ, w.pid);
+            w.reply.ok();
+        }
+    }
+}
+
+// Whether the process behind a parked request has exited or has SIGKILL pending.
+// Its lock request can then never be answered to anyone who is listening. Only
+// what /proc confirms counts: FUSE reports pid 0 for callers in other pid
+// namespaces, and those, like any status that can't be read, are left waiting.
+pub(crate) fn caller_interrupted(pid: u32) -> bool {
+    if pid == 0 {
+        return false;
+    }
+    let status = match std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(status) => status,
+        // Gone, as long as /proc is there to say so
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return std::path::Path::new("/proc/self/status").exists();
+        }
+        Err(_) => return false,
+    };
+    let sigkill = 1u64 << (libc::SIGKILL - 1);
+    status.lines().any(|line| {
+        let pending = line.strip_prefix("SigPnd:").or_else(|| line.strip_prefix("ShdPnd:"));
+        pending.and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok()).is_some_and(|mask| mask & sigkill != 0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const RD: i32 = libc::F_RDLCK;
+    const WR: i32 = libc::F_WRLCK;
+    const UN: i32 = libc::F_UNLCK;
+    const EOF: u64 = i64::MAX as u64;
+
+    // Records the answer a request got: Some(0) for ok, Some(errno) otherwise
+    #[derive(Clone, Default)]
+    struct Answer(Rc<RefCell<Option<i32>>>);
+
+    impl Answer {
+        fn get(&self) -> Option<i32> {
+            *self.0.borrow()
+        }
+    }
+
+    impl LockReply for Answer {
+        fn ok(self) {
+            *self.0.borrow_mut() = Some(0);
+        }
+
+        fn error(self, err: i32) {
+            *self.0.borrow_mut() = Some(err);
+        }
+    }
+
+    fn set(table: &mut LockTable<Answer>, owner: u64, start: u64, end: u64, typ: i32, sleep: bool) -> Answer {
+        let answer = Answer::default();
+        table.set_lock(1, owner, start, end, typ, owner as u32, sleep, answer.clone());
+        answer
+    }
+
+    fn held(table: &LockTable<Answer>, owner: u64) -> Vec<(u64, u64, i32)> {
+        let mut ranges: Vec<_> = table.locks.get(&1).into_iter().flatten()
+            .filter(|l| l.owner == owner)
+            .map(|l| (l.start, l.end, l.typ))
+            .collect();
+        ranges.sort();
+        ranges
+    }
+
+    #[test]
+    fn unlocking_the_middle_splits_a_range() {
+        let mut table = LockTable::default();
+        assert_eq!(set(&mut table, 1, 0, 99, WR, false).get(), Some(0));
+        assert_eq!(set(&mut table, 1, 40, 59, UN, false).get(), Some(0));
+        assert_eq!(held(&table, 1), vec![(0, 39, WR), (60, 99, WR)]);
+    }
+
+    #[test]
+    fn relocking_replaces_the_owners_overlapping_ranges() {
+        let mut table = LockTable::default();
+        set(&mut table, 1, 0, 9, RD, false);
+        set(&mut table, 1, 20, 29, RD, false);
+        set(&mut table, 1, 5, 24, WR, false);
+        assert_eq!(held(&table, 1), vec![(0, 4, RD), (5, 24, WR), (25, 29, RD)]);
+
+        set(&mut table, 1, 0, EOF, UN, false);
+        assert!(table.locks.is_empty());
+    }
+
+    #[test]
+    fn only_writers_conflict_between_owners() {
+        let mut table = LockTable::default();
+        set(&mut table, 1, 0, 9, RD, false);
+        assert_eq!(set(&mut table, 2, 5, 14, RD, false).get(), Some(0));
+        assert_eq!(set(&mut table, 3, 9, 9, WR, false).get(), Some(libc::EAGAIN));
+        assert_eq!(set(&mut table, 3, 15, 20, WR, false).get(), Some(0));
+        assert_eq!(table.find_conflict(1, 3, 0, EOF, RD), None);
+        assert_eq!(table.find_conflict(1, 1, 0, EOF, WR).map(|c| c.3), Some(2));
+        // Unlocks never conflict, and an owner never conflicts with itself
+        assert_eq!(table.find_conflict(1, 3, 0, EOF, UN), None);
+        assert_eq!(set(&mut table, 1, 0, 9, WR, false).get(), Some(libc::EAGAIN));
+        assert_eq!(set(&mut table, 2, 5, 14, WR, false).get(), Some(libc::EAGAIN));
+    }
+
+    #[test]
+    fn waiters_wake_once_the_conflict_is_gone() {
+        let mut table = LockTable::default();
+        set(&mut table, 1, 0, 9, WR, false);
+        let waiting = set(&mut table, 2, 5, 5, WR, true);
+        assert_eq!(waiting.get(), None);
+
+        // Still overlaps what owner 1 keeps
+        set(&mut table, 1, 0, 4, UN, false);
+        assert_eq!(waiting.get(), None);
+
+        set(&mut table, 1, 5, 9, UN, false);
+        assert_eq!(waiting.get(), Some(0));
+        assert_eq!(held(&table, 2), vec![(5, 5, WR)]);
+        assert!(!table.has_waiters());
+    }
+
+    #[test]
+    fn releasing_an_owner_wakes_others_and_cancels_its_own_waits() {
+        let mut table = LockTable::default();
+        set(&mut table, 1, 0, 9, WR, false);
+        set(&mut table, 2, 20, 29, WR, false);
+        let blocked_on_1 = set(&mut table, 3, 0, 0, RD, true);
+        let own = set(&mut table, 1, 20, 20, WR, true);
+
+        table.release_owner(1, 1);
+        assert_eq!(own.get(), Some(libc::EINTR));
+        assert_eq!(blocked_on_1.get(), Some(0));
+        assert!(held(&table, 1).is_empty());
+        assert!(!table.has_waiters());
+    }
+
+    #[test]
+    fn interrupted_waiters_get_eintr() {
+        let mut table = LockTable::default();
+        set(&mut table, 1, 0, 9, WR, false);
+        let a = set(&mut table, 2, 0, 9, WR, true);
+        let b = set(&mut table, 3, 0, 9, WR, true);
+
+        table.interrupt_waiters(|pid| pid == 2);
+        assert_eq!(a.get(), Some(libc::EINTR));
+        assert_eq!(b.get(), None);
+
+        table.release_owner(1, 1);
+        assert_eq!(b.get(), Some(0));
+    }
+
+    #[test]
+    fn waiting_in_a_cycle_is_a_deadlock() {
+        let mut table = LockTable::default();
+        set(&mut table, 1, 0, 0, WR, false);
+        set(&mut table, 2, 1, 1, WR, false);
+        set(&mut table, 3, 2, 2, WR, false);
+        // 1 waits on 2, 2 waits on 3; 3 waiting on 1 would close the cycle
+        assert_eq!(set(&mut table, 1, 1, 1, WR, true).get(), None);
+        assert_eq!(set(&mut table, 2, 2, 2, WR, true).get(), None);
+        assert_eq!(set(&mut table, 3, 0, 0, WR, true).get(), Some(libc::EDEADLK));
+        // Waiting on an owner outside the cycle is fine
+        set(&mut table, 4, 3, 3, WR, false);
+        assert_eq!(set(&mut table, 3, 3, 3, WR, true).get(), None);
+    }
+
+    #[test]
+    fn gone_callers_count_as_interrupted() {
+        assert!(!caller_interrupted(std::process::id()));
+        assert!(caller_interrupted(u32::MAX));
+        assert!(!caller_interrupted(0));
+    }
+}
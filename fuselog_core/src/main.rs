@@ -1,7 +1,8 @@
 use fuser::MountOption;
 use fuselog_core::socket::start_listener;
+use fuselog_core::dispatch::Dispatcher;
 use fuselog_core::FuseLogFS;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::env;
 use std::sync::mpsc;
 use std::thread;
@@ -19,28 +20,24 @@ fn main() {
         .filter(|arg| arg != "-f" && arg != "--foreground")
         .collect();
     
-    if filtered_args.len() != 2 {
-        eprintln!("Usage: {} [-f|--foreground] <directory>", filtered_args[0]);
-        std::process::exit(1);
-    }
-
-    let root_dir = PathBuf::from(&filtered_args[1]);
-
-    if !root_dir.exists() {
-        if let Err(e) = std::fs::create_dir_all(&root_dir) {
-            eprintln!("Failed to create directory '{}': {}", root_dir.display(), e);
+    // With a single directory, FUSE is mounted over the files it serves
+    let (source_dir, mount_dir) = match filtered_args.len() {
+        2 => (PathBuf::from(&filtered_args[1]), PathBuf::from(&filtered_args[1])),
+        3 => (PathBuf::from(&filtered_args[1]), PathBuf::from(&filtered_args[2])),
+        _ => {
+            eprintln!("Usage: {} [-f|--foreground] <directory>", filtered_args[0]);
+            eprintln!("       {} [-f|--foreground] <source> <mountpoint>", filtered_args[0]);
             std::process::exit(1);
         }
-        println!("Created directory: {}", root_dir.display());
-    } else if !root_dir.is_dir() {
-        eprintln!("Path '{}' exists but is not a directory", root_dir.display());
-        std::process::exit(1);
-    }
+    };
+
+    let source_dir = prepare_dir(&source_dir);
+    let mount_dir = prepare_dir(&mount_dir);
 
     if foreground {
         env_logger::init();
-        log::info!("Starting Fuselog in foreground mode on directory: '{}'", root_dir.display());
-        let exit_code = run_fuse_logic(root_dir);
+        log::info!("Starting Fuselog in foreground mode on directory: '{}'", mount_dir.display());
+        let exit_code = run_fuse_logic(source_dir, mount_dir);
         std::process::exit(exit_code);
     } else {
         // Check if daemon logs are enabled (default: false)
@@ -90,15 +87,15 @@ fn main() {
         let daemonize = Daemonize::new()
             // .pid_file(pid_file)
             // .chown_pid_file(true)
-            .working_directory(&root_dir)
+            .working_directory(&source_dir)
             .stdout(stdout)
             .stderr(stderr);
 
         match daemonize.start() {
             Ok(_) => {
                 env_logger::init();
-                log::info!("Successfully daemonized fuselog for directory: '{}'", root_dir.display());
-                let exit_code = run_fuse_logic(root_dir);
+                log::info!("Successfully daemonized fuselog for directory: '{}'", mount_dir.display());
+                let exit_code = run_fuse_logic(source_dir, mount_dir);
                 std::process::exit(exit_code);
             }
             Err(e) => {
@@ -109,8 +106,30 @@ fn main() {
     }
 }
 
-fn run_fuse_logic(root_dir: PathBuf) -> i32 {
-    log::info!("Starting Fuselog on directory: '{}'", root_dir.display());
+// Creates dir if needed and returns its absolute path, so it survives the daemon's chdir
+fn prepare_dir(dir: &Path) -> PathBuf {
+    if !dir.exists() {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            eprintln!("Failed to create directory '{}': {}", dir.display(), e);
+            std::process::exit(1);
+        }
+        println!("Created directory: {}", dir.display());
+    } else if !dir.is_dir() {
+        eprintln!("Path '{}' exists but is not a directory", dir.display());
+        std::process::exit(1);
+    }
+
+    match dir.canonicalize() {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("Failed to resolve directory '{}': {}", dir.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_fuse_logic(source_dir: PathBuf, mount_dir: PathBuf) -> i32 {
+    log::info!("Starting Fuselog on '{}' backed by '{}'", mount_dir.display(), source_dir.display());
     let (shutdown_tx, shutdown_rx) = mpsc::channel();
 
     let socket_file = env::var("FUSELOG_SOCKET_FILE").unwrap_or_else(|_| SOCKET_PATH.to_string());
@@ -125,21 +144,33 @@ fn run_fuse_logic(root_dir: PathBuf) -> i32 {
         }
     });
 
-    if let Err(e) = std::env::set_current_dir(&root_dir) {
-        log::error!("Failed to change directory to '{}': {}", root_dir.display(), e);
-        std::process::exit(1);
-    }
+    // One worker per core unless told otherwise
+    let threads = env::var("FUSELOG_THREADS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
 
-    let options = vec![
+    // Opens the source before mounting, in case the mount hides it
+    let fs = match FuseLogFS::new(source_dir.clone()) {
+        Ok(fs) => fs,
+        Err(e) => {
+            log::error!("Failed to open source directory '{}': {}", source_dir.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut options = vec![
         MountOption::FSName("fuselog".to_string()),
         MountOption::AutoUnmount,
-        MountOption::AllowOther,
         MountOption::DefaultPermissions,
     ];
+    // fusermount only allows this to unprivileged users with user_allow_other set
+    if !fs.is_rootless() {
+        options.push(MountOption::AllowOther);
+    }
+    let fs = Dispatcher::new(fs, threads);
 
-    let fs = FuseLogFS::new(root_dir.clone());
-
-    let exit_code = match fuser::mount2(fs, &root_dir, &options) {
+    let exit_code = match fuser::mount2(fs, &mount_dir, &options) {
         Ok(_) => {
             log::info!("FUSE filesystem has been unmounted.");
             0
@@ -0,0 +1,555 @@
+use crate::backing::{BackingDir, BackingDirEntry};
+use log::{debug, warn};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Overlay of a writable upper directory on a read-only lower one. Entries in upper
+// shadow those in lower; anything else falls through to lower. Deleting something
+// lower still has leaves a whiteout file next to it in upper, and a directory made
+// over a deleted lower one is marked opaque so lower's contents stay hidden. Both
+// markers, and copy-ups in progress, use aufs-style ".wh." names that are never
+// shown through the mount, whichever layer they turn up in.
+//
+// A regular file's data is copied up in two steps: stage_copy_up copies it to a
+// hidden file in upper's root without any of the mount's locks, as lower never
+// changes, and copy_up renames it into place under them.
+
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+const COPYUP_PREFIX: &str = ".wh..wh.copyup.";
+
+pub(crate) struct Overlay {
+    upper: Arc<BackingDir>,
+    lower: BackingDir,
+    next_stage: AtomicU64,
+}
+
+// A lower file's data and attributes copied into upper, waiting to be moved into
+// place by copy_up. The copy is removed if it never is.
+pub(crate) struct StagedCopy {
+    upper: Arc<BackingDir>,
+    // The merged path it is a copy of
+    path: PathBuf,
+    tmp: Option<PathBuf>,
+}
+
+impl Drop for StagedCopy {
+    fn drop(&mut self) {
+        if let Some(tmp) = self.tmp.take() {
+            let _ = self.upper.remove_file(&tmp);
+        }
+    }
+}
+
+pub(crate) fn is_marker(name: &[u8]) -> bool {
+    name.starts_with(WHITEOUT_PREFIX)
+}
+
+fn errno(code: i32) -> io::Error {
+    io::Error::from_raw_os_error(code)
+}
+
+fn not_found() -> io::Error {
+    errno(libc::ENOENT)
+}
+
+// Paths name their parent the way lookup builds them: "./a/b" for "b" under "./a"
+fn parent_of(path: &Path) -> Option<&Path> {
+    path.parent().filter(|p| !p.as_os_str().is_empty())
+}
+
+fn marker_path(path: &Path, prefix: &[u8]) -> Option<PathBuf> {
+    let parent = parent_of(path)?;
+    let mut name = OsString::from(std::ffi::OsStr::from_bytes(prefix));
+    name.push(path.file_name()?);
+    Some(parent.join(name))
+}
+
+impl Overlay {
+    pub(crate) fn new(upper: Arc<BackingDir>, lower: BackingDir) -> Self {
+        Self { upper, lower, next_stage: AtomicU64::new(0) }
+    }
+
+    fn in_upper(&self, path: &Path) -> bool {
+        self.upper.symlink_metadata(path).is_ok()
+    }
+
+    fn is_opaque(&self, dir: &Path) -> bool {
+        self.upper.symlink_metadata(&dir.join(OPAQUE_MARKER)).is_ok()
+    }
+
+    fn is_whited_out(&self, path: &Path) -> bool {
+        marker_path(path, WHITEOUT_PREFIX).is_some_and(|wh| self.upper.symlink_metadata(&wh).is_ok())
+    }
+
+    // Whether lower's entry at path (if any) shows through: neither it nor an
+    // ancestor is whited out, and no ancestor in upper is opaque
+    fn lower_visible(&self, path: &Path) -> bool {
+        let mut prefix = PathBuf::from(".");
+        for component in path.strip_prefix(".").unwrap_or(path).components() {
+            if self.is_opaque(&prefix) {
+                return false;
+            }
+            prefix.push(component);
+            if self.is_whited_out(&prefix) {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub(crate) fn lower_has(&self, path: &Path) -> bool {
+        self.lower_visible(path) && self.lower.symlink_metadata(path).is_ok()
+    }
+
+    // The layer reads of path go to. Paths in neither layer resolve to upper, so
+    // callers get upper's ENOENT.
+    pub(crate) fn layer(&self, path: &Path) -> &BackingDir {
+        if !self.in_upper(path) && self.lower_has(path) { &self.lower } else { &self.upper }
+    }
+
+    // Copies the regular file lower shows at path into upper ahead of copy_up.
+    // None if path needs no data copied up.
+    pub(crate) fn stage_copy_up(&self, path: &Path) -> io::Result<Option<StagedCopy>> {
+        if self.in_upper(path) || !self.lower_has(path) {
+            return Ok(None);
+        }
+        let meta = self.lower.symlink_metadata(path)?;
+        if !meta.is_file() {
+            return Ok(None);
+        }
+        self.stage(path, &meta).map(Some)
+    }
+
+    fn stage(&self, path: &Path, meta: &std::fs::Metadata) -> io::Result<StagedCopy> {
+        let mut name = OsString::from(COPYUP_PREFIX);
+        name.push(self.next_stage.fetch_add(1, Ordering::Relaxed).to_string());
+        let tmp = Path::new(".").join(name);
+        // Left over from a crash
+        let _ = self.upper.remove_file(&tmp);
+
+        let mut dst = self.upper.open_file(&tmp, libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL, 0o600)?;
+        let staged = StagedCopy { upper: Arc::clone(&self.upper), path: path.to_path_buf(), tmp: Some(tmp.clone()) };
+        let mut src = self.lower.open_file(path, libc::O_RDONLY, 0)?;
+        io::copy(&mut src, &mut dst)?;
+        self.copy_attributes(&tmp, meta)?;
+        Ok(staged)
+    }
+
+    // Brings path into upper so it can be modified, using staged if it was copied
+    // from path. Returns the size copied when a regular file's data had to be
+    // copied, for the log's copy-up reference.
+    pub(crate) fn copy_up(&self, path: &Path, staged: Option<StagedCopy>) -> io::Result<Option<u64>> {
+        if self.in_upper(path) {
+            return Ok(None);
+        }
+        if !self.lower_has(path) {
+            return Err(not_found());
+        }
+        self.copy_up_parent(path)?;
+
+        let meta = self.lower.symlink_metadata(path)?;
+        let file_type = meta.file_type();
+
+        if file_type.is_file() {
+            // Moved into place whole, so a failed copy-up never shadows the lower
+            // file with a partial one
+            let mut staged = match staged {
+                Some(staged) if staged.path == path => staged,
+                _ => self.stage(path, &meta)?,
+            };
+            let tmp = staged.tmp.take().ok_or_else(not_found)?;
+            if let Err(e) = self.upper.rename(&tmp, path, libc::RENAME_NOREPLACE) {
+                staged.tmp = Some(tmp);
+                return Err(e);
+            }
+            debug!("Copied up {:?}", path);
+            return Ok(Some(meta.size()));
+        }
+
+        if file_type.is_dir() {
+            match self.upper.create_dir(path, meta.mode() & 0o7777) {
+                Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+                _ => {}
+            }
+        } else if file_type.is_symlink() {
+            self.upper.symlink(&self.lower.read_link(path)?, path)?;
+        } else {
+            self.upper.mknod(path, meta.mode(), meta.rdev() as u32)?;
+        }
+        self.copy_attributes(path, &meta)?;
+
+        debug!("Copied up {:?}", path);
+        Ok(None)
+    }
+
+    // Gives upper's copy at path the owner, mode and times of lower's
+    fn copy_attributes(&self, path: &Path, meta: &std::fs::Metadata) -> io::Result<()> {
+        if let Err(e) = self.upper.chown(path, Some(meta.uid()), Some(meta.gid()), false) {
+            warn!("Copy-up of {:?} could not keep its owner: {}", path, e);
+        }
+        if !meta.file_type().is_symlink() {
+            self.upper.set_permissions(path, meta.mode() & 0o7777)?;
+        }
+        let times = [
+            libc::timespec { tv_sec: meta.atime(), tv_nsec: meta.atime_nsec() },
+            libc::timespec { tv_sec: meta.mtime(), tv_nsec: meta.mtime_nsec() },
+        ];
+        self.upper.set_times(path, &times)
+    }
+
+    fn copy_up_parent(&self, path: &Path) -> io::Result<()> {
+        match parent_of(path) {
+            Some(parent) if !self.in_upper(parent) => self.copy_up(parent, None).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    // Makes room in upper for a new entry at path: its parent is copied up, and a
+    // name lower still shows is refused as existing
+    pub(crate) fn prepare_create(&self, path: &Path) -> io::Result<()> {
+        if path.file_name().is_some_and(|name| is_marker(name.as_bytes())) {
+            return Err(errno(libc::EPERM));
+        }
+        if !self.in_upper(path) && self.lower_has(path) {
+            return Err(errno(libc::EEXIST));
+        }
+        self.copy_up_parent(path)
+    }
+
+    // Called once the new entry exists in upper. A whiteout it replaces is dropped,
+    // and a directory made over a deleted lower one is kept from merging with it.
+    pub(crate) fn finish_create(&self, path: &Path, is_dir: bool) -> io::Result<()> {
+        let Some(wh) = marker_path(path, WHITEOUT_PREFIX) else {
+            return Ok(());
+        };
+        if self.upper.symlink_metadata(&wh).is_err() {
+            return Ok(());
+        }
+        self.upper.remove_file(&wh)?;
+        if is_dir && self.lower.symlink_metadata(path).is_ok() {
+            self.make_opaque(path)?;
+        }
+        Ok(())
+    }
+
+    fn make_opaque(&self, dir: &Path) -> io::Result<()> {
+        self.upper.open_file(&dir.join(OPAQUE_MARKER), libc::O_WRONLY | libc::O_CREAT, 0o600).map(|_| ())
+    }
+
+    // Hides lower's entry at path, if it has one, after upper's was removed
+    fn whiteout(&self, path: &Path) -> io::Result<()> {
+        if !self.lower_has(path) {
+            return Ok(());
+        }
+        self.copy_up_parent(path)?;
+        let wh = marker_path(path, WHITEOUT_PREFIX).ok_or_else(not_found)?;
+        self.upper.open_file(&wh, libc::O_WRONLY | libc::O_CREAT, 0o600).map(|_| ())
+    }
+
+    // Removes the markers upper keeps inside dir, so the directory itself can go
+    fn clear_markers(&self, dir: &Path) -> io::Result<()> {
+        for entry in self.upper.read_dir(dir)? {
+            if is_marker(entry.name.as_bytes()) {
+                self.upper.remove_file(&dir.join(&entry.name))?;
+            }
+        }
+        Ok(())
+    }
+
+    // unlink or rmdir of the merged entry at path
+    pub(crate) fn remove(&self, path: &Path, is_dir: bool) -> io::Result<()> {
+        let meta = self.layer(path).symlink_metadata(path)?;
+        match (is_dir, meta.is_dir()) {
+            (true, false) => return Err(errno(libc::ENOTDIR)),
+            (false, true) => return Err(errno(libc::EISDIR)),
+            (true, true) if !self.read_dir(path)?.is_empty() => return Err(errno(libc::ENOTEMPTY)),
+            _ => {}
+        }
+
+        if self.in_upper(path) {
+            if is_dir {
+                self.clear_markers(path)?;
+                self.upper.remove_dir(path)?;
+            } else {
+                self.upper.remove_file(path)?;
+            }
+        }
+        self.whiteout(path)
+    }
+
+    // Checks a rename against what only lower has. Directories lower has would need
+    // copying up whole, so like overlayfs they get EXDEV and callers fall back to
+    // copy and delete; exchanges are limited to entries only upper has.
+    pub(crate) fn check_rename(&self, from: &Path, to: &Path, flags: u32) -> io::Result<()> {
+        if to.file_name().is_some_and(|name| is_marker(name.as_bytes())) {
+            return Err(errno(libc::EPERM));
+        }
+        let from_meta = self.layer(from).symlink_metadata(from)?;
+        if from_meta.is_dir() && self.lower_has(from) {
+            return Err(errno(libc::EXDEV));
+        }
+        if (flags & libc::RENAME_EXCHANGE) != 0 {
+            if self.lower_has(from) || self.lower_has(to) {
+                return Err(errno(libc::EXDEV));
+            }
+            return Ok(());
+        }
+
+        let Ok(to_meta) = self.layer(to).symlink_metadata(to) else {
+            return Ok(());
+        };
+        if !self.in_upper(to) && (flags & libc::RENAME_NOREPLACE) != 0 {
+            return Err(errno(libc::EEXIST));
+        }
+        match (from_meta.is_dir(), to_meta.is_dir()) {
+            (false, true) => Err(errno(libc::EISDIR)),
+            (true, false) => Err(errno(libc::ENOTDIR)),
+            (true, true) if !self.read_dir(to)?.is_empty() => Err(errno(libc::ENOTEMPTY)),
+            _ => Ok(()),
+        }
+    }
+
+    // Renames within upper once check_rename has passed and from has been copied up
+    pub(crate) fn rename(&self, from: &Path, to: &Path, flags: u32) -> io::Result<()> {
+        let from_is_dir = self.upper.symlink_metadata(from)?.is_dir();
+        self.copy_up_parent(to)?;
+        if from_is_dir && self.upper.symlink_metadata(to).is_ok_and(|m| m.is_dir()) {
+            // Only markers can be left in it; they would make the rename fail
+            self.clear_markers(to)?;
+        }
+        self.upper.rename(from, to, flags)?;
+
+        if (flags & libc::RENAME_EXCHANGE) == 0 {
+            if let Some(wh) = marker_path(to, WHITEOUT_PREFIX)
+                && self.upper.symlink_metadata(&wh).is_ok()
+            {
+                self.upper.remove_file(&wh)?;
+            }
+            if from_is_dir && self.lower.symlink_metadata(to).is_ok() {
+                self.make_opaque(to)?;
+            }
+            self.whiteout(from)?;
+        }
+        Ok(())
+    }
+
+    // The merged listing of dir: upper's entries, then lower's that upper neither
+    // shadows nor whites out
+    pub(crate) fn read_dir(&self, dir: &Path) -> io::Result<Vec<BackingDirEntry>> {
+        let in_upper = self.in_upper(dir);
+        let merge_lower = self.lower_has(dir) && !(in_upper && self.is_opaque(dir));
+        if !in_upper && !merge_lower {
+            return Err(not_found());
+        }
+
+        let mut entries = if in_upper { self.upper.read_dir(dir)? } else { Vec::new() };
+        let whited_out: HashSet<OsString> = entries
+            .iter()
+            .filter_map(|e| e.name.as_bytes().strip_prefix(WHITEOUT_PREFIX))
+            .map(|name| std::ffi::OsStr::from_bytes(name).to_os_string())
+            .collect();
+        entries.retain(|e| !is_marker(e.name.as_bytes()));
+
+        if merge_lower {
+            let shadowed: HashSet<OsString> = entries.iter().map(|e| e.name.clone()).collect();
+            for entry in self.lower.read_dir(dir)? {
+                if !is_marker(entry.name.as_bytes()) && !shadowed.contains(&entry.name) && !whited_out.contains(&entry.name) {
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    static NEXT_FIXTURE: AtomicU64 = AtomicU64::new(0);
+
+    // An overlay over fresh upper and lower directories, removed on drop
+    struct Fixture {
+        root: PathBuf,
+        overlay: Overlay,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let id = NEXT_FIXTURE.fetch_add(1, Ordering::Relaxed);
+            let root = std::env::temp_dir().join(format!("fuselog-overlay-{}-{}", std::process::id(), id));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("upper")).unwrap();
+            fs::create_dir_all(root.join("lower")).unwrap();
+            let upper = Arc::new(BackingDir::open(&root.join("upper")).unwrap());
+            let lower = BackingDir::open(&root.join("lower")).unwrap();
+            Self { overlay: Overlay::new(upper, lower), root }
+        }
+
+        fn upper(&self, rel: &str) -> PathBuf {
+            self.root.join("upper").join(rel)
+        }
+
+        fn lower(&self, rel: &str) -> PathBuf {
+            self.root.join("lower").join(rel)
+        }
+
+        // The merged listing, sorted
+        fn names(&self, dir: &str) -> Vec<String> {
+            let mut names: Vec<String> = self.overlay.read_dir(Path::new(dir)).unwrap()
+                .into_iter()
+                .map(|e| e.name.to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        }
+
+        // What upper really holds at its root, markers included
+        fn upper_root(&self) -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir(self.upper("")).unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    fn errno_of<T: std::fmt::Debug>(result: io::Result<T>) -> Option<i32> {
+        result.unwrap_err().raw_os_error()
+    }
+
+    #[test]
+    fn unlinking_a_lower_file_whites_it_out() {
+        let fx = Fixture::new();
+        fs::write(fx.lower("f"), b"base").unwrap();
+        fs::write(fx.lower("g"), b"base").unwrap();
+
+        fx.overlay.remove(Path::new("./f"), false).unwrap();
+        assert!(fx.upper(".wh.f").exists());
+        assert!(fx.lower("f").exists());
+        assert!(!fx.overlay.lower_has(Path::new("./f")));
+        assert_eq!(fx.names("."), vec!["g"]);
+    }
+
+    #[test]
+    fn markers_in_either_layer_stay_hidden() {
+        let fx = Fixture::new();
+        fs::write(fx.lower(".wh.stray"), b"").unwrap();
+        fs::write(fx.lower("y"), b"").unwrap();
+        fs::write(fx.upper(".wh..wh.copyup.7"), b"").unwrap();
+        assert_eq!(fx.names("."), vec!["y"]);
+    }
+
+    #[test]
+    fn mkdir_over_a_deleted_lower_dir_is_opaque() {
+        let fx = Fixture::new();
+        fs::create_dir(fx.lower("d")).unwrap();
+        fs::write(fx.lower("d/x"), b"").unwrap();
+
+        fx.overlay.remove(Path::new("./d/x"), false).unwrap();
+        fx.overlay.remove(Path::new("./d"), true).unwrap();
+        assert!(fx.upper(".wh.d").exists());
+
+        let d = Path::new("./d");
+        fx.overlay.prepare_create(d).unwrap();
+        fs::create_dir(fx.upper("d")).unwrap();
+        fx.overlay.finish_create(d, true).unwrap();
+
+        assert!(!fx.upper(".wh.d").exists());
+        assert!(fx.upper("d/.wh..wh..opq").exists());
+        assert_eq!(fx.names("."), vec!["d"]);
+        assert!(fx.names("./d").is_empty());
+        assert!(!fx.overlay.lower_has(Path::new("./d/x")));
+    }
+
+    #[test]
+    fn check_rename_refuses_what_upper_cant_do() {
+        let fx = Fixture::new();
+        fs::create_dir(fx.lower("ld")).unwrap();
+        fs::write(fx.lower("lf"), b"").unwrap();
+        fs::write(fx.upper("uf"), b"").unwrap();
+        fs::write(fx.upper("ug"), b"").unwrap();
+        let (ld, lf, uf, ug) = (Path::new("./ld"), Path::new("./lf"), Path::new("./uf"), Path::new("./ug"));
+
+        // Lower directories would have to be copied up whole
+        assert_eq!(errno_of(fx.overlay.check_rename(ld, Path::new("./new"), 0)), Some(libc::EXDEV));
+        assert_eq!(errno_of(fx.overlay.check_rename(uf, lf, libc::RENAME_EXCHANGE)), Some(libc::EXDEV));
+        // A name only lower has still exists
+        assert_eq!(errno_of(fx.overlay.check_rename(uf, lf, libc::RENAME_NOREPLACE)), Some(libc::EEXIST));
+        assert_eq!(errno_of(fx.overlay.check_rename(uf, Path::new("./.wh.x"), 0)), Some(libc::EPERM));
+
+        fx.overlay.check_rename(uf, lf, 0).unwrap();
+        fx.overlay.check_rename(lf, Path::new("./new"), libc::RENAME_NOREPLACE).unwrap();
+        fx.overlay.check_rename(uf, ug, libc::RENAME_EXCHANGE).unwrap();
+    }
+
+    #[test]
+    fn copy_up_keeps_data_and_attributes() {
+        let fx = Fixture::new();
+        fs::write(fx.lower("f"), b"base data").unwrap();
+        fs::set_permissions(fx.lower("f"), std::os::unix::fs::PermissionsExt::from_mode(0o640)).unwrap();
+        let times = fs::FileTimes::new().set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000));
+        fs::File::options().write(true).open(fx.lower("f")).unwrap().set_times(times).unwrap();
+
+        let f = Path::new("./f");
+        let staged = fx.overlay.stage_copy_up(f).unwrap();
+        assert!(staged.is_some());
+        assert_eq!(fx.overlay.copy_up(f, staged).unwrap(), Some(9));
+
+        let (lower, upper) = (fs::metadata(fx.lower("f")).unwrap(), fs::metadata(fx.upper("f")).unwrap());
+        assert_eq!(fs::read(fx.upper("f")).unwrap(), b"base data");
+        assert_eq!(upper.mode(), lower.mode());
+        assert_eq!(upper.mtime(), lower.mtime());
+        assert_eq!(fx.upper_root(), vec!["f"]);
+        // Already up
+        assert!(fx.overlay.stage_copy_up(f).unwrap().is_none());
+        assert_eq!(fx.overlay.copy_up(f, None).unwrap(), None);
+    }
+
+    #[test]
+    fn copy_up_ignores_a_copy_staged_for_another_path() {
+        let fx = Fixture::new();
+        fs::write(fx.lower("a"), b"a").unwrap();
+        fs::write(fx.lower("b"), b"bb").unwrap();
+
+        let staged = fx.overlay.stage_copy_up(Path::new("./a")).unwrap();
+        assert_eq!(fx.overlay.copy_up(Path::new("./b"), staged).unwrap(), Some(2));
+        assert_eq!(fs::read(fx.upper("b")).unwrap(), b"bb");
+        assert_eq!(fx.upper_root(), vec!["b"]);
+    }
+
+    #[test]
+    fn failed_copy_up_leaves_nothing_behind() {
+        let fx = Fixture::new();
+        fs::create_dir(fx.lower("d")).unwrap();
+        fs::write(fx.lower("d/f"), b"base").unwrap();
+        // Upper has a file where lower has the directory, so the final rename fails
+        // after the data was copied
+        fs::write(fx.upper("d"), b"").unwrap();
+
+        let f = Path::new("./d/f");
+        let staged = fx.overlay.stage_copy_up(f).unwrap();
+        assert!(fx.overlay.copy_up(f, staged).is_err());
+        assert_eq!(fx.upper_root(), vec!["d"]);
+        assert!(fs::metadata(fx.upper("d")).unwrap().is_file());
+
+        // Dropping an unused staged copy cleans it up too
+        fs::write(fx.lower("g"), b"").unwrap();
+        drop(fx.overlay.stage_copy_up(Path::new("./g")).unwrap());
+        assert_eq!(fx.upper_root(), vec!["d"]);
+    }
+}
@@ -0,0 +1,353 @@
+use crate::backing::BackingDir;
+use crate::state::{Journal, Record};
+use crate::to_cstring;
+use fuser::FileAttr;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+// Rootless mode. Without CAP_CHOWN the daemon can't give files to other users, and
+// without CAP_FSETID a setgid bit may be dropped on a file whose group it isn't in.
+// Whatever couldn't be applied is kept on the file in the user.fuselog.stat xattr
+// and reported through getattr in place of what the backing file says. Symlinks and
+// special files can't carry user xattrs, so theirs are kept by backing inode in a
+// journal under the state directory.
+//
+// The daemon must still be able to use what it stores, so chmod always leaves the
+// owner rw on backing files and rwx on backing directories, and records the mode
+// that was asked for.
+
+const XATTR_PREFIX: &[u8] = b"user.fuselog.";
+const STAT_XATTR: &str = "user.fuselog.stat";
+// Stored in place of an id or mode that isn't overridden
+const UNSET: u32 = u32::MAX;
+// Overrides of files without xattrs, as [st_dev, st_ino, uid << 32 | gid, mode].
+// A record with nothing overridden drops the file.
+const OWNERSHIP_JOURNAL: &str = "ownership";
+// Kept on backing files whatever their mode says
+const OWNER_FILE_BITS: u32 = 0o600;
+const OWNER_DIR_BITS: u32 = 0o700;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Override {
+    uid: u32,
+    gid: u32,
+    mode: u32,
+}
+
+impl Default for Override {
+    fn default() -> Self {
+        Self { uid: UNSET, gid: UNSET, mode: UNSET }
+    }
+}
+
+impl Override {
+    fn encode(&self) -> [u8; 12] {
+        let mut buf = [0u8; 12];
+        buf[0..4].copy_from_slice(&self.uid.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.gid.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.mode.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let field = |i: usize| Some(u32::from_le_bytes(buf.get(i..i + 4)?.try_into().ok()?));
+        Some(Self { uid: field(0)?, gid: field(4)?, mode: field(8)? })
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn to_record(self, (dev, ino): (u64, u64)) -> Record {
+        [dev, ino, ((self.uid as u64) << 32) | self.gid as u64, self.mode as u64]
+    }
+
+    fn from_record([dev, ino, ids, mode]: Record) -> ((u64, u64), Self) {
+        ((dev, ino), Self { uid: (ids >> 32) as u32, gid: ids as u32, mode: mode as u32 })
+    }
+}
+
+pub(crate) struct OwnershipStore {
+    rootless: bool,
+    fallback: Mutex<HashMap<(u64, u64), Override>>,
+    journal: Option<Journal>,
+}
+
+impl OwnershipStore {
+    pub(crate) fn new(rootless: bool, state_dir: &Path) -> Self {
+        let mut store = Self { rootless, fallback: Mutex::new(HashMap::new()), journal: None };
+        if rootless {
+            store.load_state(state_dir);
+        }
+        store
+    }
+
+    // Picks up the overrides of symlinks and special files from earlier mounts
+    fn load_state(&mut self, state_dir: &Path) {
+        let (journal, records) = match Journal::open(state_dir, OWNERSHIP_JOURNAL) {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!("Can't open ownership state in {:?}, so overrides of symlinks and special files won't survive a remount: {}", state_dir, e);
+                return;
+            }
+        };
+        let fallback = self.fallback.get_mut().unwrap();
+        for record in records {
+            let (key, ov) = Override::from_record(record);
+            if ov.is_empty() {
+                fallback.remove(&key);
+            } else {
+                fallback.insert(key, ov);
+            }
+        }
+        info!("Loaded {} ownership overrides from {:?}", fallback.len(), state_dir);
+
+        let live: Vec<Record> = fallback.iter().map(|(&key, ov)| ov.to_record(key)).collect();
+        if let Err(e) = journal.compact(&live) {
+            warn!("Failed to compact ownership state in {:?}: {}", state_dir, e);
+        }
+        self.journal = Some(journal);
+    }
+
+    fn set_fallback(&self, key: (u64, u64), ov: Override) {
+        let mut fallback = self.fallback.lock().unwrap();
+        let changed = if ov.is_empty() { fallback.remove(&key).is_some() } else { fallback.insert(key, ov) != Some(ov) };
+        if changed && let Some(journal) = &self.journal {
+            journal.append(ov.to_record(key));
+        }
+    }
+
+    pub(crate) fn rootless(&self) -> bool {
+        self.rootless
+    }
+
+    fn load(&self, backing: &BackingDir, path: &Path, metadata: &Metadata) -> Override {
+        let (Some(c_path), Some(c_name)) = (to_cstring(backing.proc_path(path).as_os_str()), to_cstring(STAT_XATTR.as_ref())) else {
+            return Override::default();
+        };
+        let mut buf = [0u8; 12];
+        let len = unsafe { libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if len >= 0 {
+            return Override::decode(&buf[..len as usize]).unwrap_or_default();
+        }
+        self.fallback.lock().unwrap().get(&(metadata.dev(), metadata.ino())).copied().unwrap_or_default()
+    }
+
+    fn store(&self, backing: &BackingDir, path: &Path, metadata: &Metadata, ov: Override) -> io::Result<()> {
+        let key = (metadata.dev(), metadata.ino());
+        let (Some(c_path), Some(c_name)) = (to_cstring(backing.proc_path(path).as_os_str()), to_cstring(STAT_XATTR.as_ref())) else {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        };
+
+        if ov.is_empty() {
+            unsafe { libc::lremovexattr(c_path.as_ptr(), c_name.as_ptr()) };
+            self.set_fallback(key, ov);
+            return Ok(());
+        }
+
+        debug!("Recording ownership override for {:?}: {:?}", path, ov);
+        let value = ov.encode();
+        let res = unsafe {
+            libc::lsetxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0)
+        };
+        if res == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            // No user xattrs on this file type or filesystem
+            Some(libc::EPERM) | Some(libc::EOPNOTSUPP) => {
+                self.set_fallback(key, ov);
+                Ok(())
+            }
+            _ => Err(err),
+        }
+    }
+
+    // chown, or lchown with follow unset. In rootless mode an owner the daemon may
+    // not give away is recorded instead of refused.
+    pub(crate) fn chown(&self, backing: &BackingDir, path: &Path, uid: Option<u32>, gid: Option<u32>, follow: bool) -> io::Result<()> {
+        let result = backing.chown(path, uid, gid, follow);
+        if !self.rootless {
+            return result;
+        }
+        if let Err(e) = &result
+            && e.raw_os_error() != Some(libc::EPERM)
+        {
+            return result;
+        }
+
+        let metadata = backing.symlink_metadata(path)?;
+        let mut ov = self.load(backing, path, &metadata);
+        // What chown applied for real no longer needs overriding
+        let recorded = |id: Option<u32>, current: u32| match id {
+            Some(id) if result.is_err() => id,
+            Some(_) => UNSET,
+            None => current,
+        };
+        ov.uid = recorded(uid, ov.uid);
+        ov.gid = recorded(gid, ov.gid);
+        self.store(backing, path, &metadata, ov)
+    }
+
+    // chmod. In rootless mode the backing file keeps the owner bits the daemon
+    // needs, and the mode is recorded wherever the backing file ends up differing.
+    pub(crate) fn chmod(&self, backing: &BackingDir, path: &Path, mode: u32) -> io::Result<()> {
+        if !self.rootless {
+            return backing.set_permissions(path, mode);
+        }
+        let owner_bits = match backing.symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => OWNER_DIR_BITS,
+            Ok(_) => OWNER_FILE_BITS,
+            Err(e) => return Err(e),
+        };
+        let result = backing.set_permissions(path, mode | owner_bits);
+        if let Err(e) = &result
+            && e.raw_os_error() != Some(libc::EPERM)
+        {
+            return result;
+        }
+
+        let metadata = backing.symlink_metadata(path)?;
+        let mut ov = self.load(backing, path, &metadata);
+        ov.mode = if (metadata.mode() & 0o7777) == (mode & 0o7777) { UNSET } else { mode & 0o7777 };
+        self.store(backing, path, &metadata, ov)
+    }
+
+    // Reports recorded ownership and mode in place of the backing file's
+    pub(crate) fn apply(&self, backing: &BackingDir, path: &Path, metadata: &Metadata, attrs: &mut FileAttr) {
+        if !self.rootless {
+            return;
+        }
+        let ov = self.load(backing, path, metadata);
+        if ov.uid != UNSET {
+            attrs.uid = ov.uid;
+        }
+        if ov.gid != UNSET {
+            attrs.gid = ov.gid;
+        }
+        if ov.mode != UNSET {
+            attrs.perm = ov.mode as u16;
+        }
+    }
+
+    // Drops the record of a file that no longer exists
+    pub(crate) fn forget(&self, metadata: &Metadata) {
+        if self.rootless {
+            self.set_fallback((metadata.dev(), metadata.ino()), Override::default());
+        }
+    }
+
+    // Carries the record of a file that turned up under a new backing inode, as
+    // after a copy-up. Otherwise records follow their inode through renames.
+    pub(crate) fn moved(&self, from: &Metadata, to: &Metadata) {
+        let (old, new) = ((from.dev(), from.ino()), (to.dev(), to.ino()));
+        if !self.rootless || old == new {
+            return;
+        }
+        let ov = self.fallback.lock().unwrap().get(&old).copied();
+        if let Some(ov) = ov {
+            self.set_fallback(old, Override::default());
+            self.set_fallback(new, ov);
+        }
+    }
+
+    // Whether an xattr name is ours and hidden from callers
+    pub(crate) fn is_reserved(&self, name: &[u8]) -> bool {
+        self.rootless && name.starts_with(XATTR_PREFIX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> (PathBuf, BackingDir) {
+        let root = std::env::temp_dir().join(format!("fuselog-ownership-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("data")).unwrap();
+        let backing = BackingDir::open(&root.join("data")).unwrap();
+        (root, backing)
+    }
+
+    #[test]
+    fn rootless_chmod_keeps_the_backing_file_usable() {
+        let (root, backing) = fixture("chmod");
+        let store = OwnershipStore::new(true, &root.join("state"));
+        fs::write(root.join("data/f"), b"").unwrap();
+        fs::create_dir(root.join("data/d")).unwrap();
+        let (f, d) = (Path::new("./f"), Path::new("./d"));
+
+        store.chmod(&backing, f, 0o444).unwrap();
+        store.chmod(&backing, d, 0o500).unwrap();
+        let (f_meta, d_meta) = (backing.symlink_metadata(f).unwrap(), backing.symlink_metadata(d).unwrap());
+        assert_eq!(f_meta.permissions().mode() & 0o7777, 0o644);
+        assert_eq!(d_meta.permissions().mode() & 0o7777, 0o700);
+        assert_eq!(store.load(&backing, f, &f_meta).mode, 0o444);
+        assert_eq!(store.load(&backing, d, &d_meta).mode, 0o500);
+
+        // A mode the backing file can hold as is needs no override
+        store.chmod(&backing, f, 0o640).unwrap();
+        let f_meta = backing.symlink_metadata(f).unwrap();
+        assert_eq!(f_meta.permissions().mode() & 0o7777, 0o640);
+        assert!(store.load(&backing, f, &f_meta).is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn overrides_without_xattrs_survive_a_restart() {
+        let (root, backing) = fixture("fallback");
+        let state_dir = root.join("state");
+        let link = Path::new("./link");
+        std::os::unix::fs::symlink("target", root.join("data/link")).unwrap();
+        let metadata = backing.symlink_metadata(link).unwrap();
+        let key = (metadata.dev(), metadata.ino());
+
+        let store = OwnershipStore::new(true, &state_dir);
+        let ov = Override { uid: 1234, gid: 5678, mode: UNSET };
+        store.store(&backing, link, &metadata, ov).unwrap();
+        assert_eq!(store.load(&backing, link, &metadata), ov);
+        drop(store);
+
+        let store = OwnershipStore::new(true, &state_dir);
+        assert_eq!(store.fallback.lock().unwrap().get(&key), Some(&ov));
+        store.forget(&metadata);
+        drop(store);
+
+        let store = OwnershipStore::new(true, &state_dir);
+        assert!(store.fallback.lock().unwrap().is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn records_follow_a_copied_up_file() {
+        let (root, backing) = fixture("moved");
+        let store = OwnershipStore::new(true, &root.join("state"));
+        let (old, new) = (Path::new("./old"), Path::new("./new"));
+        std::os::unix::fs::symlink("target", root.join("data/old")).unwrap();
+        std::os::unix::fs::symlink("target", root.join("data/new")).unwrap();
+        let (old_meta, new_meta) = (backing.symlink_metadata(old).unwrap(), backing.symlink_metadata(new).unwrap());
+
+        let ov = Override { uid: 1234, gid: UNSET, mode: UNSET };
+        store.store(&backing, old, &old_meta, ov).unwrap();
+        store.moved(&old_meta, &new_meta);
+        assert!(store.load(&backing, old, &old_meta).is_empty());
+        assert_eq!(store.load(&backing, new, &new_meta), ov);
+
+        // A file replaced by a rename leaves nothing for the next one at its inode
+        store.forget(&new_meta);
+        assert!(store.load(&backing, new, &new_meta).is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
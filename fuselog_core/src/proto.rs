@@ -0,0 +1,455 @@
+use crate::statediff::{LoggedAction, StateDiffAction, StateDiffLog};
+use std::collections::HashMap;
+use std::fmt;
+
+// Protocol Buffers encoding of StateDiffLog, following proto/statediff.proto. It is
+// written out by hand to keep protoc and a code generator out of the build; any
+// change here must be made to the schema too.
+//
+// As proto3 does, scalar fields holding their default are left out, and readers
+// skip fields they don't know.
+
+const VARINT: u8 = 0;
+const FIXED64: u8 = 1;
+const LEN: u8 = 2;
+const FIXED32: u8 = 5;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProtoError {
+    Truncated,
+    VarintTooLong,
+    UnsupportedWireType(u8),
+    // A known field sent with a wire type its schema type doesn't use
+    WrongWireType(u32),
+    // A LoggedAction with no action, or only ones this build doesn't know
+    MissingAction(u64),
+}
+
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "protobuf message truncated"),
+            Self::VarintTooLong => write!(f, "protobuf varint longer than 10 bytes"),
+            Self::UnsupportedWireType(wire_type) => write!(f, "unsupported protobuf wire type {}", wire_type),
+            Self::WrongWireType(field) => write!(f, "field {} has the wrong wire type", field),
+            Self::MissingAction(seq) => write!(f, "action {} has no action this build knows", seq),
+        }
+    }
+}
+
+impl std::error::Error for ProtoError {}
+
+pub fn encode_log(log: &StateDiffLog) -> Vec<u8> {
+    let mut buf = Vec::new();
+    put_uint(&mut buf, 1, log.epoch);
+    put_uint(&mut buf, 2, log.diff_seq);
+    put_uint(&mut buf, 3, log.first_seq);
+    put_uint(&mut buf, 4, log.next_seq);
+    put_uint(&mut buf, 5, log.sent_wall_time_ns);
+
+    // Sorted so the same log always encodes the same way
+    let mut fids: Vec<_> = log.fid_map.iter().collect();
+    fids.sort_unstable_by_key(|(fid, _)| **fid);
+    for (fid, path) in fids {
+        let mut entry = Vec::new();
+        put_uint(&mut entry, 1, *fid);
+        put_bytes(&mut entry, 2, path);
+        put_message(&mut buf, 6, &entry);
+    }
+
+    if !log.retired_fids.is_empty() {
+        let mut packed = Vec::new();
+        for fid in &log.retired_fids {
+            put_varint(&mut packed, *fid);
+        }
+        put_message(&mut buf, 7, &packed);
+    }
+
+    for entry in &log.actions {
+        let mut logged = Vec::new();
+        put_uint(&mut logged, 1, entry.seq);
+        put_uint(&mut logged, 2, entry.wall_time_ns);
+        put_uint(&mut logged, 3, entry.mono_time_ns);
+        let (field, body) = encode_action(&entry.action);
+        let mut action = Vec::new();
+        put_message(&mut action, field, &body);
+        put_message(&mut logged, 4, &action);
+        put_message(&mut buf, 8, &logged);
+    }
+    buf
+}
+
+// The action's field number in the StateDiffAction oneof, and its message
+fn encode_action(action: &StateDiffAction) -> (u32, Vec<u8>) {
+    let mut m = Vec::new();
+    let field = match action {
+        StateDiffAction::Create { fid, uid, gid, mode } => {
+            put_uint(&mut m, 1, *fid);
+            put_uint(&mut m, 2, *uid as u64);
+            put_uint(&mut m, 3, *gid as u64);
+            put_uint(&mut m, 4, *mode as u64);
+            1
+        }
+        StateDiffAction::Write { fid, offset, data } => {
+            put_uint(&mut m, 1, *fid);
+            put_uint(&mut m, 2, *offset);
+            put_bytes(&mut m, 3, data);
+            2
+        }
+        StateDiffAction::Unlink { fid } => {
+            put_uint(&mut m, 1, *fid);
+            3
+        }
+        StateDiffAction::Rename { from_fid, to_fid, noreplace } => {
+            put_uint(&mut m, 1, *from_fid);
+            put_uint(&mut m, 2, *to_fid);
+            put_uint(&mut m, 3, *noreplace as u64);
+            4
+        }
+        StateDiffAction::Exchange { a_fid, b_fid } => {
+            put_uint(&mut m, 1, *a_fid);
+            put_uint(&mut m, 2, *b_fid);
+            5
+        }
+        StateDiffAction::Truncate { fid, size } => {
+            put_uint(&mut m, 1, *fid);
+            put_uint(&mut m, 2, *size);
+            6
+        }
+        StateDiffAction::Link { source_fid, new_link_fid } => {
+            put_uint(&mut m, 1, *source_fid);
+            put_uint(&mut m, 2, *new_link_fid);
+            7
+        }
+        StateDiffAction::Chown { fid, uid, gid } => {
+            put_uint(&mut m, 1, *fid);
+            put_uint(&mut m, 2, *uid as u64);
+            put_uint(&mut m, 3, *gid as u64);
+            8
+        }
+        StateDiffAction::Chmod { fid, mode } => {
+            put_uint(&mut m, 1, *fid);
+            put_uint(&mut m, 2, *mode as u64);
+            9
+        }
+        StateDiffAction::Mkdir { fid } => {
+            put_uint(&mut m, 1, *fid);
+            10
+        }
+        StateDiffAction::Rmdir { fid } => {
+            put_uint(&mut m, 1, *fid);
+            11
+        }
+        StateDiffAction::Symlink { link_fid, target_path, uid, gid } => {
+            put_uint(&mut m, 1, *link_fid);
+            put_bytes(&mut m, 2, target_path);
+            put_uint(&mut m, 3, *uid as u64);
+            put_uint(&mut m, 4, *gid as u64);
+            12
+        }
+        StateDiffAction::SetXattr { fid, name, value } => {
+            put_uint(&mut m, 1, *fid);
+            put_bytes(&mut m, 2, name);
+            put_bytes(&mut m, 3, value);
+            13
+        }
+        StateDiffAction::RemoveXattr { fid, name } => {
+            put_uint(&mut m, 1, *fid);
+            put_bytes(&mut m, 2, name);
+            14
+        }
+        StateDiffAction::SetTimes { fid, atime, mtime } => {
+            put_uint(&mut m, 1, *fid);
+            for (field, time) in [(2, atime), (3, mtime)] {
+                if let Some((sec, nsec)) = time {
+                    let mut timespec = Vec::new();
+                    // int64 is sent as the two's complement varint
+                    put_uint(&mut timespec, 1, *sec as u64);
+                    put_uint(&mut timespec, 2, *nsec as u64);
+                    put_message(&mut m, field, &timespec);
+                }
+            }
+            15
+        }
+        StateDiffAction::Fallocate { fid, mode, offset, length } => {
+            put_uint(&mut m, 1, *fid);
+            // int32 is sign extended to 64 bits first
+            put_uint(&mut m, 2, *mode as i64 as u64);
+            put_uint(&mut m, 3, *offset);
+            put_uint(&mut m, 4, *length);
+            16
+        }
+        StateDiffAction::PunchHole { fid, offset, length } => {
+            put_uint(&mut m, 1, *fid);
+            put_uint(&mut m, 2, *offset);
+            put_uint(&mut m, 3, *length);
+            17
+        }
+        StateDiffAction::CopyRange { src_fid, src_off, dst_fid, dst_off, len } => {
+            put_uint(&mut m, 1, *src_fid);
+            put_uint(&mut m, 2, *src_off);
+            put_uint(&mut m, 3, *dst_fid);
+            put_uint(&mut m, 4, *dst_off);
+            put_uint(&mut m, 5, *len);
+            18
+        }
+        StateDiffAction::Mknod { fid, mode, rdev, uid, gid } => {
+            put_uint(&mut m, 1, *fid);
+            put_uint(&mut m, 2, *mode as u64);
+            put_uint(&mut m, 3, *rdev as u64);
+            put_uint(&mut m, 4, *uid as u64);
+            put_uint(&mut m, 5, *gid as u64);
+            19
+        }
+        StateDiffAction::CopyUp { fid, size } => {
+            put_uint(&mut m, 1, *fid);
+            put_uint(&mut m, 2, *size);
+            20
+        }
+    };
+    (field, m)
+}
+
+pub(crate) fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_key(buf: &mut Vec<u8>, field: u32, wire_type: u8) {
+    put_varint(buf, ((field as u64) << 3) | wire_type as u64);
+}
+
+fn put_uint(buf: &mut Vec<u8>, field: u32, value: u64) {
+    if value != 0 {
+        put_key(buf, field, VARINT);
+        put_varint(buf, value);
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u32, value: &[u8]) {
+    if !value.is_empty() {
+        put_message(buf, field, value);
+    }
+}
+
+// Sent even when empty, since a present message differs from an absent one
+fn put_message(buf: &mut Vec<u8>, field: u32, value: &[u8]) {
+    put_key(buf, field, LEN);
+    put_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+#[derive(Clone, Copy)]
+enum Value<'a> {
+    Varint(u64),
+    // fixed32 and fixed64, which no field here uses
+    Fixed,
+    Len(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    fn uint(self, field: u32) -> Result<u64, ProtoError> {
+        match self {
+            Value::Varint(value) => Ok(value),
+            _ => Err(ProtoError::WrongWireType(field)),
+        }
+    }
+
+    fn bytes(self, field: u32) -> Result<&'a [u8], ProtoError> {
+        match self {
+            Value::Len(bytes) => Ok(bytes),
+            _ => Err(ProtoError::WrongWireType(field)),
+        }
+    }
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], ProtoError> {
+    if buf.len() < len {
+        return Err(ProtoError::Truncated);
+    }
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(head)
+}
+
+fn get_varint(buf: &mut &[u8]) -> Result<u64, ProtoError> {
+    let mut value = 0u64;
+    for i in 0..10 {
+        let byte = *take(buf, 1)?.first().unwrap();
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(ProtoError::VarintTooLong)
+}
+
+// Calls f with each field of a message in order
+fn read_fields<'a>(
+    mut buf: &'a [u8],
+    mut f: impl FnMut(u32, Value<'a>) -> Result<(), ProtoError>,
+) -> Result<(), ProtoError> {
+    while !buf.is_empty() {
+        let key = get_varint(&mut buf)?;
+        let field = (key >> 3) as u32;
+        let value = match (key & 7) as u8 {
+            VARINT => Value::Varint(get_varint(&mut buf)?),
+            FIXED64 => {
+                take(&mut buf, 8)?;
+                Value::Fixed
+            }
+            LEN => {
+                let len = get_varint(&mut buf)?;
+                Value::Len(take(&mut buf, usize::try_from(len).map_err(|_| ProtoError::Truncated)?)?)
+            }
+            FIXED32 => {
+                take(&mut buf, 4)?;
+                Value::Fixed
+            }
+            wire_type => return Err(ProtoError::UnsupportedWireType(wire_type)),
+        };
+        f(field, value)?;
+    }
+    Ok(())
+}
+
+// A message's fields by number, the last one winning as the format requires.
+// Absent fields read as their default.
+struct Fields<'a>(HashMap<u32, Value<'a>>);
+
+impl<'a> Fields<'a> {
+    fn read(buf: &'a [u8]) -> Result<Self, ProtoError> {
+        let mut fields = HashMap::new();
+        read_fields(buf, |field, value| {
+            fields.insert(field, value);
+            Ok(())
+        })?;
+        Ok(Self(fields))
+    }
+
+    fn uint(&self, field: u32) -> Result<u64, ProtoError> {
+        self.0.get(&field).map_or(Ok(0), |value| value.uint(field))
+    }
+
+    // uint32 fields keep the low 32 bits, as other decoders do
+    fn uint32(&self, field: u32) -> Result<u32, ProtoError> {
+        Ok(self.uint(field)? as u32)
+    }
+
+    fn bytes(&self, field: u32) -> Result<&'a [u8], ProtoError> {
+        self.0.get(&field).map_or(Ok(&[][..]), |value| value.bytes(field))
+    }
+
+    fn message(&self, field: u32) -> Result<Option<Fields<'a>>, ProtoError> {
+        self.0.get(&field).map(|value| Fields::read(value.bytes(field)?)).transpose()
+    }
+}
+
+pub fn decode_log(buf: &[u8]) -> Result<StateDiffLog, ProtoError> {
+    let mut log = StateDiffLog::default();
+    read_fields(buf, |field, value| {
+        match field {
+            1 => log.epoch = value.uint(field)?,
+            2 => log.diff_seq = value.uint(field)?,
+            3 => log.first_seq = value.uint(field)?,
+            4 => log.next_seq = value.uint(field)?,
+            5 => log.sent_wall_time_ns = value.uint(field)?,
+            6 => {
+                let entry = Fields::read(value.bytes(field)?)?;
+                log.fid_map.insert(entry.uint(1)?, entry.bytes(2)?.to_vec());
+            }
+            // Packed, though writers may also send the values one by one
+            7 => match value {
+                Value::Varint(fid) => log.retired_fids.push(fid),
+                Value::Len(mut packed) => {
+                    while !packed.is_empty() {
+                        log.retired_fids.push(get_varint(&mut packed)?);
+                    }
+                }
+                Value::Fixed => return Err(ProtoError::WrongWireType(field)),
+            },
+            8 => log.actions.push(decode_logged_action(value.bytes(field)?)?),
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(log)
+}
+
+fn decode_logged_action(buf: &[u8]) -> Result<LoggedAction, ProtoError> {
+    let fields = Fields::read(buf)?;
+    let seq = fields.uint(1)?;
+    let mut action = None;
+    // Of several actions set, the last known one wins
+    read_fields(fields.bytes(4)?, |field, value| {
+        if let Value::Len(body) = value
+            && let Some(decoded) = decode_action(field, &Fields::read(body)?)?
+        {
+            action = Some(decoded);
+        }
+        Ok(())
+    })?;
+    Ok(LoggedAction {
+        seq,
+        wall_time_ns: fields.uint(2)?,
+        mono_time_ns: fields.uint(3)?,
+        action: action.ok_or(ProtoError::MissingAction(seq))?,
+    })
+}
+
+fn decode_action(field: u32, m: &Fields) -> Result<Option<StateDiffAction>, ProtoError> {
+    let timespec = |field: u32| -> Result<Option<(i64, u32)>, ProtoError> {
+        Ok(match m.message(field)? {
+            Some(time) => Some((time.uint(1)? as i64, time.uint32(2)?)),
+            None => None,
+        })
+    };
+    Ok(Some(match field {
+        1 => StateDiffAction::Create { fid: m.uint(1)?, uid: m.uint32(2)?, gid: m.uint32(3)?, mode: m.uint32(4)? },
+        2 => StateDiffAction::Write { fid: m.uint(1)?, offset: m.uint(2)?, data: m.bytes(3)?.to_vec() },
+        3 => StateDiffAction::Unlink { fid: m.uint(1)? },
+        4 => StateDiffAction::Rename { from_fid: m.uint(1)?, to_fid: m.uint(2)?, noreplace: m.uint(3)? != 0 },
+        5 => StateDiffAction::Exchange { a_fid: m.uint(1)?, b_fid: m.uint(2)? },
+        6 => StateDiffAction::Truncate { fid: m.uint(1)?, size: m.uint(2)? },
+        7 => StateDiffAction::Link { source_fid: m.uint(1)?, new_link_fid: m.uint(2)? },
+        8 => StateDiffAction::Chown { fid: m.uint(1)?, uid: m.uint32(2)?, gid: m.uint32(3)? },
+        9 => StateDiffAction::Chmod { fid: m.uint(1)?, mode: m.uint32(2)? },
+        10 => StateDiffAction::Mkdir { fid: m.uint(1)? },
+        11 => StateDiffAction::Rmdir { fid: m.uint(1)? },
+        12 => StateDiffAction::Symlink {
+            link_fid: m.uint(1)?,
+            target_path: m.bytes(2)?.to_vec(),
+            uid: m.uint32(3)?,
+            gid: m.uint32(4)?,
+        },
+        13 => StateDiffAction::SetXattr { fid: m.uint(1)?, name: m.bytes(2)?.to_vec(), value: m.bytes(3)?.to_vec() },
+        14 => StateDiffAction::RemoveXattr { fid: m.uint(1)?, name: m.bytes(2)?.to_vec() },
+        15 => StateDiffAction::SetTimes { fid: m.uint(1)?, atime: timespec(2)?, mtime: timespec(3)? },
+        16 => StateDiffAction::Fallocate {
+            fid: m.uint(1)?,
+            mode: m.uint(2)? as i32,
+            offset: m.uint(3)?,
+            length: m.uint(4)?,
+        },
+        17 => StateDiffAction::PunchHole { fid: m.uint(1)?, offset: m.uint(2)?, length: m.uint(3)? },
+        18 => StateDiffAction::CopyRange {
+            src_fid: m.uint(1)?,
+            src_off: m.uint(2)?,
+            dst_fid: m.uint(3)?,
+            dst_off: m.uint(4)?,
+            len: m.uint(5)?,
+        },
+        19 => StateDiffAction::Mknod {
+            fid: m.uint(1)?,
+            mode: m.uint32(2)?,
+            rdev: m.uint32(3)?,
+            uid: m.uint32(4)?,
+            gid: m.uint32(5)?,
+        },
+        20 => StateDiffAction::CopyUp { fid: m.uint(1)?, size: m.uint(2)? },
+        _ => return Ok(None),
+    }))
+}
@@ -1,10 +1,11 @@
-use crate::statediff::{StateDiffAction, StateDiffLog};
-use crate::STATEDIFF_LOG;
-use bincode::config;
+use crate::statediff::{FidTable, LoggedAction, SentDiff, StateDiffAction, StateDiffLog};
+use crate::wire::{self, Encoding};
+use crate::{columnar, live_inode_count, proto, set_read_only, FID_TABLE, STATEDIFF_LOG};
 use log::{error, info, warn};
 use std::collections::{HashMap, HashSet};
 use std::io::{ErrorKind, Read, Write};
 use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
 use std::env;
@@ -35,6 +36,7 @@ struct PruneState {
     last_chown_idx: Option<usize>,
 }
 
+#[derive(Default)]
 struct AdaptiveState {
     first_statediff_seen: bool,
     training_buffer: Vec<Vec<u8>>,
@@ -43,20 +45,11 @@ struct AdaptiveState {
     new_dict_needs_sending: bool,
 }
 
-impl Default for AdaptiveState {
-    fn default() -> Self {
-        Self {
-            first_statediff_seen: false,
-            training_buffer: Vec::new(),
-            encoder_dict: None,
-            training_in_progress: false,
-            new_dict_needs_sending: false,
-        }
-    }
-}
-
 static ADAPTIVE_STATE: once_cell::sync::Lazy<Mutex<AdaptiveState>> = once_cell::sync::Lazy::new(|| Mutex::new(AdaptiveState::default()));
 
+// Set by 'f' until a diff carrying the whole fid table has been sent
+static RESYNC_PENDING: AtomicBool = AtomicBool::new(false);
+
 fn load_existing_dictionary() {
     if let Ok(dict_data) = std::fs::read(DICT_PATH) {
         let mut state = ADAPTIVE_STATE.lock().unwrap();
@@ -81,8 +74,24 @@ fn handle_client(mut stream: UnixStream) -> Result<(), Box<dyn std::error::Error
         match stream.read_exact(&mut buffer) {
             Ok(_) => {
                 let result = match buffer[0] {
-                    b'g' => send_statediff(stream.try_clone()?),
+                    b'g' => send_statediff(stream.try_clone()?, Encoding::Bincode),
+                    b'p' => send_statediff(stream.try_clone()?, Encoding::Protobuf),
+                    b's' => send_statediff(stream.try_clone()?, Encoding::Columnar),
                     b'c' => clear_statediff(),
+                    b'f' => {
+                        info!("Socket: Received 'resync' command; the next diff carries the whole fid table");
+                        RESYNC_PENDING.store(true, Ordering::Relaxed);
+                        Ok(())
+                    }
+                    b'i' => send_inode_count(stream.try_clone()?),
+                    b'r' => {
+                        set_read_only(true);
+                        Ok(())
+                    }
+                    b'w' => {
+                        set_read_only(false);
+                        Ok(())
+                    }
                     b'm' => {
                         println!("[]==========[] CHECKPOINT []==========[] ");
                         Ok(())
@@ -156,19 +165,22 @@ fn prune_log(log: &mut StateDiffLog) {
     let original_action_count = log.actions.len();
     let original_fid_count = log.fid_map.len();
 
-    let mut actions: Vec<Option<StateDiffAction>> = log.actions.drain(..).map(Some).collect();
+    let mut actions: Vec<Option<LoggedAction>> = log.actions.drain(..).map(Some).collect();
     let mut file_states: HashMap<u64, PruneState> = HashMap::new();
     let mut fids_to_purge: HashSet<u64> = HashSet::new();
+    // Files other actions read from on the replica must not be purged
+    let mut pinned_fids: HashSet<u64> = HashSet::new();
 
     for i in 0..actions.len() {
         let action = match &actions[i] {
-            Some(a) => a,
+            Some(a) => &a.action,
             None => continue,
         };
 
         match action {
             StateDiffAction::Create { fid, .. }
             | StateDiffAction::Mkdir { fid }
+            | StateDiffAction::Mknod { fid, .. }
             | StateDiffAction::Symlink { link_fid: fid, .. } => {
                 file_states.entry(*fid).or_default().creation_idx = Some(i);
             }
@@ -185,58 +197,44 @@ fn prune_log(log: &mut StateDiffLog) {
                 }
             }
             StateDiffAction::Unlink { fid } | StateDiffAction::Rmdir { fid } => {
-                if let Some(state) = file_states.get(fid) {
-                    if state.creation_idx.is_some() {
-                        fids_to_purge.insert(*fid);
-                    }
+                if let Some(state) = file_states.get(fid)
+                    && state.creation_idx.is_some()
+                {
+                    fids_to_purge.insert(*fid);
                 }
             }
+            StateDiffAction::CopyRange { src_fid, dst_fid, .. } if src_fid != dst_fid => {
+                pinned_fids.insert(*src_fid);
+            }
             _ => {}
         }
     }
 
+    fids_to_purge.retain(|fid| !pinned_fids.contains(fid));
+
     let mut final_actions = Vec::new();
     let mut used_fids = HashSet::new();
 
-    for action_opt in actions.into_iter() {
-        if let Some(action) = action_opt {
-            let mut action_fids = Vec::new();
-            match &action {
-                StateDiffAction::Create { fid, .. }
-                | StateDiffAction::Write { fid, .. }
-                | StateDiffAction::Unlink { fid }
-                | StateDiffAction::Truncate { fid, .. }
-                | StateDiffAction::Chown { fid, .. }
-                | StateDiffAction::Chmod { fid, .. }
-                | StateDiffAction::Mkdir { fid }
-                | StateDiffAction::Rmdir { fid } => action_fids.push(*fid),
-                StateDiffAction::Symlink { link_fid, .. } => action_fids.push(*link_fid),
-                StateDiffAction::Rename { from_fid, to_fid } => {
-                    action_fids.push(*from_fid);
-                    action_fids.push(*to_fid);
-                }
-                StateDiffAction::Link {
-                    source_fid,
-                    new_link_fid,
-                } => {
-                    action_fids.push(*source_fid);
-                    action_fids.push(*new_link_fid);
-                }
-            };
-
-            if action_fids.iter().any(|fid| fids_to_purge.contains(fid)) {
-                continue;
-            }
+    for action in actions.into_iter().flatten() {
+        let action_fids = action.action.fids();
+        if action_fids.iter().any(|fid| fids_to_purge.contains(fid)) {
+            continue;
+        }
 
-            for fid in action_fids {
-                used_fids.insert(fid);
-            }
-            final_actions.push(action);
+        for fid in action_fids {
+            used_fids.insert(fid);
         }
+        final_actions.push(action);
     }
 
     log.actions = final_actions;
-    log.fid_map.retain(|fid, _| used_fids.contains(fid));
+    // Fids retired in the diff that introduced them only need sending if an action
+    // still uses them. The rest must reach the replica for later diffs.
+    let retired: HashSet<u64> = log.retired_fids.iter().copied().collect();
+    let unused: HashSet<u64> =
+        log.fid_map.keys().filter(|fid| retired.contains(fid) && !used_fids.contains(fid)).copied().collect();
+    log.fid_map.retain(|fid, _| !unused.contains(fid));
+    log.retired_fids.retain(|fid| !unused.contains(fid));
 
     if log.actions.len() < original_action_count {
         info!(
@@ -267,10 +265,10 @@ fn try_train_dictionary_async(samples: Vec<Vec<u8>>) {
                 );
 
                 // Save dictionary to disk
-                if let Some(parent) = std::path::Path::new(DICT_PATH).parent() {
-                    if let Err(e) = fs::create_dir_all(parent) {
-                        error!("Failed to create dictionary directory: {}", e);
-                    }
+                if let Some(parent) = std::path::Path::new(DICT_PATH).parent()
+                    && let Err(e) = fs::create_dir_all(parent)
+                {
+                    error!("Failed to create dictionary directory: {}", e);
                 }
 
                 if let Err(e) = fs::write(DICT_PATH, &dict_content) {
@@ -299,21 +297,31 @@ fn try_train_dictionary_async(samples: Vec<Vec<u8>>) {
     });
 }
 
-fn send_statediff(mut stream: UnixStream) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Socket: Received 'get' command");
+fn send_statediff(mut stream: UnixStream, encoding: Encoding) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Socket: Received 'get' command ({:?})", encoding);
 
-    let serialized_data = {
+    let (serialized_data, snapshot, sent_dict, sent) = {
         let mut log = STATEDIFF_LOG.lock().map_err(|e| {
             error!("Socket: Failed to lock statediff log: {}", e);
-            std::io::Error::new(std::io::ErrorKind::Other, "Lock poisoned")
+            std::io::Error::other("Lock poisoned")
         })?;
 
         let original_action_count = log.actions.len();
         let original_fid_count = log.fid_map.len();
+        log.begin_diff();
+        // Every binding the replica may need, those this diff retires included
+        let snapshot = RESYNC_PENDING.load(Ordering::Relaxed);
+        if snapshot {
+            let fids = FID_TABLE.lock().unwrap();
+            log.fid_map.extend(fids.bindings().map(|(fid, path)| (fid, path.to_vec())));
+        }
+        // Before pruning, which may drop the actions that removed a path
+        let removed_fids = log.removed_fids();
+        log.retired_fids = removed_fids.clone();
 
         // Pruning is disabled by default
         let is_prune_enabled = env::var("FUSELOG_PRUNE")
-            .map_or(false, |val| val.to_lowercase() == "true" || val == "1");
+            .is_ok_and(|val| val.to_lowercase() == "true" || val == "1");
 
         if is_prune_enabled {
             info!("=========================================");
@@ -324,16 +332,29 @@ fn send_statediff(mut stream: UnixStream) -> Result<(), Box<dyn std::error::Erro
             info!("Pruning is disabled. Skipping pruning of statediff log.");
         }
 
-        let bincode_data = bincode::encode_to_vec(&*log, config::standard()).map_err(|e| {
-            error!("Socket: Failed to serialize statediff log: {}", e);
-            std::io::Error::new(std::io::ErrorKind::Other, format!("Serialization failed: {}", e))
-        })?;
+        let compression_enabled = env::var("FUSELOG_COMPRESSION")
+            .is_ok_and(|val| val.to_lowercase() == "true" || val == "1");
+
+        let log_data = match encoding {
+            Encoding::Bincode => wire::encode_log(&log).map_err(|e| {
+                error!("Socket: Failed to serialize statediff log: {}", e);
+                std::io::Error::other(format!("Serialization failed: {}", e))
+            })?,
+            Encoding::Protobuf => proto::encode_log(&log),
+            // Compresses its streams itself
+            Encoding::Columnar => columnar::encode_log(&log, compression_enabled.then_some(COMPRESSION_LEVEL))?,
+        };
 
-        // Adaptive compression is disabled by default
-        let adaptive_enabled = env::var("ADAPTIVE_COMPRESSION")
-            .map_or(false, |val| val.to_lowercase() == "true" || val == "1");
+        // Adaptive compression is disabled by default. The dictionary is trained on
+        // bincode payloads, so other encodings get plain zstd.
+        let adaptive_requested = env::var("ADAPTIVE_COMPRESSION")
+            .is_ok_and(|val| val.to_lowercase() == "true" || val == "1");
+        let adaptive_enabled = adaptive_requested && encoding == Encoding::Bincode;
+        if adaptive_requested && !adaptive_enabled {
+            info!("Adaptive compression only applies to bincode payloads; not using the dictionary for {:?}", encoding);
+        }
 
-        if adaptive_enabled && !bincode_data.is_empty() {
+        if adaptive_enabled && !log_data.is_empty() {
             let mut state = ADAPTIVE_STATE.lock().unwrap();
 
             if !state.first_statediff_seen {
@@ -342,15 +363,15 @@ fn send_statediff(mut stream: UnixStream) -> Result<(), Box<dyn std::error::Erro
                 info!("Skipping first statediff for dictionary training (initialization data)");
             } else {
                 let dev_mode = env::var("ADAPTIVE_DEV_MODE")
-                    .map_or(false, |val| val.to_lowercase() == "true" || val == "1");
+                    .is_ok_and(|val| val.to_lowercase() == "true" || val == "1");
 
                 let min_sample_size = if dev_mode { DEV_MIN_SAMPLE_SIZE } else { MIN_SAMPLE_SIZE };
 
-                if bincode_data.len() >= min_sample_size {
+                if log_data.len() >= min_sample_size {
                     // Collect sample for training
-                    state.training_buffer.push(bincode_data.clone());
+                    state.training_buffer.push(log_data.clone());
                     info!("Collected sample for dictionary training: {} bytes (total samples: {})",
-                          bincode_data.len(), state.training_buffer.len());
+                          log_data.len(), state.training_buffer.len());
 
                     // Let's check if we should train
                     let total_bytes: usize = state.training_buffer.iter().map(|v| v.len()).sum();
@@ -371,25 +392,26 @@ fn send_statediff(mut stream: UnixStream) -> Result<(), Box<dyn std::error::Erro
                         try_train_dictionary_async(samples);
                     }
                 } else {
-                    info!("Sample too small ({} bytes), skipping collection", bincode_data.len());
+                    info!("Sample too small ({} bytes), skipping collection", log_data.len());
                 }
             }
         }
 
-        let compression_enabled = env::var("FUSELOG_COMPRESSION")
-            .map_or(false, |val| val.to_lowercase() == "true" || val == "1");
-
-        let final_payload = if compression_enabled && !bincode_data.is_empty() {
+        let (flags, body) = if encoding == Encoding::Columnar {
+            info!("Columnar payload of {} bytes, {}.", log_data.len(),
+                  if compression_enabled { "streams compressed separately" } else { "uncompressed" });
+            (if compression_enabled { wire::FLAG_ZSTD } else { 0 }, log_data)
+        } else if compression_enabled && !log_data.is_empty() {
             if adaptive_enabled {
                 let state = ADAPTIVE_STATE.lock().unwrap();
 
                 if let Some(dict_arc) = state.encoder_dict.as_ref().map(Arc::clone) {
                     drop(state);
 
-                    let normal_compressed = zstd::encode_all(&bincode_data[..], COMPRESSION_LEVEL)?;
+                    let normal_compressed = zstd::encode_all(&log_data[..], COMPRESSION_LEVEL)?;
                     let dict_compressed = {
                         let mut compressor = zstd::bulk::Compressor::with_dictionary(COMPRESSION_LEVEL, &dict_arc)?;
-                        compressor.compress(&bincode_data)?
+                        compressor.compress(&log_data)?
                     };
 
                     if dict_compressed.len() < normal_compressed.len() {
@@ -402,67 +424,77 @@ fn send_statediff(mut stream: UnixStream) -> Result<(), Box<dyn std::error::Erro
                         if should_include_dict {
                             info!("Including dictionary in payload (first time after training)");
                             state.new_dict_needs_sending = false;
-                            let mut payload = Vec::new();
-                            payload.push(b'd');
-                            payload.extend_from_slice(&(dict_arc.len() as u32).to_le_bytes());
-                            payload.extend_from_slice(&dict_arc);
-                            payload.push(b'z');
-                            payload.extend(dict_compressed);
-                            payload
+                            let mut body = Vec::with_capacity(4 + dict_arc.len() + dict_compressed.len());
+                            body.extend_from_slice(&(dict_arc.len() as u32).to_le_bytes());
+                            body.extend_from_slice(&dict_arc);
+                            body.extend(dict_compressed);
+                            (wire::FLAG_ZSTD | wire::FLAG_ZSTD_DICT | wire::FLAG_DICT_INCLUDED, body)
                         } else {
                             info!("Not including dictionary in payload (must have been sent before)");
-                            let mut payload = Vec::with_capacity(1 + dict_compressed.len());
-                            payload.push(b'z');
-                            payload.extend(dict_compressed);
-                            payload
+                            (wire::FLAG_ZSTD | wire::FLAG_ZSTD_DICT, dict_compressed)
                         }
                     } else {
                         info!("Normal compression chosen: {} bytes vs {} bytes (dictionary)",
                               normal_compressed.len(), dict_compressed.len());
-                        let mut payload = Vec::with_capacity(1 + normal_compressed.len());
-                        payload.push(b'z');
-                        payload.extend(normal_compressed);
-                        payload
+                        (wire::FLAG_ZSTD, normal_compressed)
                     }
                 } else {
                     info!("Adaptive mode enabled but no dictionary trained yet. Using normal compression.");
-                    let compressed_data = zstd::encode_all(&bincode_data[..], COMPRESSION_LEVEL)?;
-                    let mut payload = Vec::with_capacity(1 + compressed_data.len());
-                    payload.push(b'z');
-                    payload.extend(compressed_data);
-                    payload
+                    (wire::FLAG_ZSTD, zstd::encode_all(&log_data[..], COMPRESSION_LEVEL)?)
                 }
             } else {
                 info!("Standard compression enabled.");
-                let compressed_data = zstd::encode_all(&bincode_data[..], COMPRESSION_LEVEL)?;
-                info!("Data compressed from {} to {} bytes.", bincode_data.len(), compressed_data.len());
-                let mut payload = Vec::with_capacity(1 + compressed_data.len());
-                payload.push(b'z');
-                payload.extend(compressed_data);
-                payload
+                let compressed_data = zstd::encode_all(&log_data[..], COMPRESSION_LEVEL)?;
+                info!("Data compressed from {} to {} bytes.", log_data.len(), compressed_data.len());
+                (wire::FLAG_ZSTD, compressed_data)
             }
         } else {
             info!("Compression is disabled or data is empty. Sending raw data.");
-            let mut payload = Vec::with_capacity(1 + bincode_data.len());
-            payload.push(b'n');
-            payload.extend(bincode_data);
-            payload
+            (0, log_data)
         };
+        let snapshot_flag = if snapshot { wire::FLAG_FID_SNAPSHOT } else { 0 };
+        let final_payload = wire::encode_frame(flags | encoding.flags() | snapshot_flag, &body);
 
         let action_count = log.actions.len();
         let fid_count = log.fid_map.len();
-        log.actions.clear();
-        log.fid_map.clear();
-
+        info!("Socket: Sending diff {} covering actions {}..{}", log.diff_seq, log.first_seq, log.next_seq);
         info!("Socket: Original statediff log had {} actions, {} fids. Pruned to {} actions, {} fids.",
             original_action_count, original_fid_count, action_count, fid_count);
 
-        final_payload
+        // The log keeps the diff until it is out
+        let sent = SentDiff {
+            epoch: log.epoch,
+            next_seq: log.next_seq,
+            fids: log.fid_map.keys().copied().collect(),
+            removed_fids,
+        };
+        (final_payload, snapshot, (flags & wire::FLAG_DICT_INCLUDED) != 0, sent)
     };
 
-    stream.write_all(&serialized_data.len().to_le_bytes())?;
+    let result = stream
+        .write_all(&serialized_data.len().to_le_bytes())
+        .and_then(|_| stream.write_all(&serialized_data));
+    if let Err(e) = result {
+        warn!("Socket: Failed to send the diff; it goes out with the next one");
+        if sent_dict {
+            ADAPTIVE_STATE.lock().unwrap().new_dict_needs_sending = true;
+        }
+        return Err(e.into());
+    }
 
-    stream.write_all(&serialized_data)?;
+    let mut log = STATEDIFF_LOG.lock().map_err(|e| {
+        error!("Socket: Failed to lock statediff log: {}", e);
+        std::io::Error::other("Lock poisoned")
+    })?;
+    // A clear while it was being sent has dropped it already
+    if log.epoch == sent.epoch {
+        let mut fids = FID_TABLE.lock().unwrap();
+        log.complete_diff(&sent, &mut fids);
+        info!("Socket: Retired {} fids, {} remain in use", sent.removed_fids.len(), fids.len());
+    }
+    if snapshot {
+        RESYNC_PENDING.store(false, Ordering::Relaxed);
+    }
     info!("Socket: Successfully sent data to client");
     Ok(())
 }
@@ -472,16 +504,23 @@ fn clear_statediff() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut log = STATEDIFF_LOG.lock().map_err(|e| {
         error!("Socket: Failed to lock statediff log: {}", e);
-        std::io::Error::new(std::io::ErrorKind::Other, "Lock poisoned")
+        std::io::Error::other("Lock poisoned")
     })?;
 
+    // A replica would refuse every later diff for the gap a plain clear leaves, so
+    // the log and fids start a new epoch instead
     let action_count = log.actions.len();
-    let fid_count = log.fid_map.len();
-    log.actions.clear();
-    log.fid_map.clear();
+    log.new_epoch();
+    *FID_TABLE.lock().unwrap() = FidTable::default();
 
-    info!("Socket: Cleared statediff log (had {} actions, {} fids).", 
-        action_count, fid_count);
+    info!("Socket: Cleared statediff log (had {} actions); starting epoch {}", action_count, log.epoch);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+fn send_inode_count(mut stream: UnixStream) -> Result<(), Box<dyn std::error::Error>> {
+    let count = live_inode_count();
+    info!("Socket: Received 'inodes' command, {} live inodes", count);
+    stream.write_all(&count.to_le_bytes())?;
+    Ok(())
+}
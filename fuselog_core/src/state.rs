@@ -0,0 +1,116 @@
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// State that has to outlive a mount: the inode numbers and generations handed out
+// in backing inode mode, and rootless ownership of files that can't carry xattrs.
+// Each kind is a journal of fixed-size records under FUSELOG_STATE_DIR. Records
+// are appended as the state changes, replayed at startup and the file rewritten
+// with only what is still live.
+
+pub(crate) type Record = [u64; 4];
+
+const RECORD_LEN: usize = 32;
+
+// FUSELOG_STATE_DIR, or a directory under /var/tmp named after the source, which
+// is kept across reboots and writable without root
+pub(crate) fn state_dir(source: &Path) -> PathBuf {
+    if let Some(dir) = std::env::var_os("FUSELOG_STATE_DIR") {
+        return PathBuf::from(dir);
+    }
+    let source = source.canonicalize().unwrap_or_else(|_| source.to_path_buf());
+    let name = source.to_string_lossy().replace('/', "_");
+    PathBuf::from(format!("/var/tmp/fuselog{}", name))
+}
+
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    // Opens dir/name for appending, returning it with the records it holds. A
+    // record cut short by a crash is cut off, so later ones line up again.
+    pub(crate) fn open(dir: &Path, name: &str) -> io::Result<(Self, Vec<Record>)> {
+        std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        let path = dir.join(name);
+
+        let mut bytes = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut bytes)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let records = bytes
+            .chunks_exact(RECORD_LEN)
+            .map(|chunk| std::array::from_fn(|i| u64::from_le_bytes(chunk[i * 8..i * 8 + 8].try_into().unwrap())))
+            .collect();
+
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        if bytes.len() % RECORD_LEN != 0 {
+            file.set_len((bytes.len() - bytes.len() % RECORD_LEN) as u64)?;
+        }
+        Ok((Self { path, file: Mutex::new(file) }, records))
+    }
+
+    pub(crate) fn append(&self, record: Record) {
+        if let Err(e) = self.file.lock().unwrap().write_all(&encode(&[record])) {
+            warn!("Failed to append to {:?}: {}", self.path, e);
+        }
+    }
+
+    // Replaces the journal with records, written to a temporary file and renamed
+    // over it so a crash leaves one or the other
+    pub(crate) fn compact(&self, records: &[Record]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let tmp = self.path.with_extension("tmp");
+        let mut out = File::create(&tmp)?;
+        out.write_all(&encode(records))?;
+        out.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        *file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+fn encode(records: &[Record]) -> Vec<u8> {
+    records.iter().flatten().flat_map(|field| field.to_le_bytes()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_replays_and_compacts() {
+        let dir = std::env::temp_dir().join(format!("fuselog-journal-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let (journal, records) = Journal::open(&dir, "test").unwrap();
+        assert!(records.is_empty());
+        journal.append([1, 2, 3, 4]);
+        journal.append([5, 6, 7, u64::MAX]);
+        drop(journal);
+
+        // A record cut short is dropped
+        OpenOptions::new().append(true).open(dir.join("test")).unwrap().write_all(&[9; 10]).unwrap();
+        let (journal, records) = Journal::open(&dir, "test").unwrap();
+        assert_eq!(records, vec![[1, 2, 3, 4], [5, 6, 7, u64::MAX]]);
+        journal.append([8, 8, 8, 8]);
+        let (journal, records) = Journal::open(&dir, "test").unwrap();
+        assert_eq!(records.last(), Some(&[8, 8, 8, 8]));
+
+        journal.compact(&[[5, 6, 7, 8]]).unwrap();
+        journal.append([9, 9, 9, 9]);
+        drop(journal);
+        let (_, records) = Journal::open(&dir, "test").unwrap();
+        assert_eq!(records, vec![[5, 6, 7, 8], [9, 9, 9, 9]]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
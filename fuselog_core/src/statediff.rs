@@ -1,11 +1,12 @@
 use bincode::{Decode, Encode};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-// Make sure it schema based serialization
-// Maybe try cap and proto  
-// Only capture operation that change the state  
+// Only capture operation that change the state
+// proto.rs and proto/statediff.proto give these a Protocol Buffers form for
+// consumers that can't read bincode; keep them in step with changes here.
 
-#[derive(Encode, Decode, Debug)]
+#[derive(Encode, Decode, Debug, PartialEq)]
 pub enum StateDiffAction {
     Create {
         fid: u64,
@@ -24,6 +25,11 @@ pub enum StateDiffAction {
     Rename {
         from_fid: u64,
         to_fid: u64,
+        noreplace: bool,
+    },
+    Exchange {
+        a_fid: u64,
+        b_fid: u64,
     },
     Truncate {
         fid: u64,
@@ -50,14 +56,278 @@ pub enum StateDiffAction {
     },
     Symlink {
         link_fid: u64,
-        target_path: String,
+        // Raw bytes, as symlink targets need not be UTF-8
+        target_path: Vec<u8>,
+        uid: u32,
+        gid: u32,
+    },
+    // Raw bytes, as xattr names need not be UTF-8 either
+    SetXattr {
+        fid: u64,
+        name: Vec<u8>,
+        value: Vec<u8>,
+    },
+    RemoveXattr {
+        fid: u64,
+        name: Vec<u8>,
+    },
+    // Times are (seconds, nanoseconds) since the epoch; None leaves it untouched
+    SetTimes {
+        fid: u64,
+        atime: Option<(i64, u32)>,
+        mtime: Option<(i64, u32)>,
+    },
+    // Preallocation and ZERO_RANGE; mode carries the raw FALLOC_FL_* bits
+    Fallocate {
+        fid: u64,
+        mode: i32,
+        offset: u64,
+        length: u64,
+    },
+    PunchHole {
+        fid: u64,
+        offset: u64,
+        length: u64,
+    },
+    // Replayed from the replica's own copy of the source, so no data is shipped
+    CopyRange {
+        src_fid: u64,
+        src_off: u64,
+        dst_fid: u64,
+        dst_off: u64,
+        len: u64,
+    },
+    // FIFOs, sockets and device nodes; mode includes the S_IFMT type bits
+    Mknod {
+        fid: u64,
+        mode: u32,
+        rdev: u32,
         uid: u32,
         gid: u32,
     },
+    // Overlay mode: the file now differs from the shared base, which the replica
+    // holds too; size is the base file's, for checking it is the same one
+    CopyUp {
+        fid: u64,
+        size: u64,
+    },
+}
+
+impl StateDiffAction {
+    // Every fid the action refers to
+    pub fn fids(&self) -> Vec<u64> {
+        match self {
+            Self::Create { fid, .. }
+            | Self::Write { fid, .. }
+            | Self::Unlink { fid }
+            | Self::Truncate { fid, .. }
+            | Self::Chown { fid, .. }
+            | Self::Chmod { fid, .. }
+            | Self::Mkdir { fid }
+            | Self::Rmdir { fid }
+            | Self::SetXattr { fid, .. }
+            | Self::RemoveXattr { fid, .. }
+            | Self::SetTimes { fid, .. }
+            | Self::Fallocate { fid, .. }
+            | Self::PunchHole { fid, .. }
+            | Self::Mknod { fid, .. }
+            | Self::CopyUp { fid, .. } => vec![*fid],
+            Self::Symlink { link_fid, .. } => vec![*link_fid],
+            Self::Rename { from_fid, to_fid, .. } => vec![*from_fid, *to_fid],
+            Self::Exchange { a_fid, b_fid } => vec![*a_fid, *b_fid],
+            Self::Link { source_fid, new_link_fid } => vec![*source_fid, *new_link_fid],
+            Self::CopyRange { src_fid, dst_fid, .. } => vec![*src_fid, *dst_fid],
+        }
+    }
+
+    // The fid whose path no longer exists once the action is applied
+    pub fn removed_fid(&self) -> Option<u64> {
+        match self {
+            Self::Unlink { fid } | Self::Rmdir { fid } => Some(*fid),
+            Self::Rename { from_fid, to_fid, .. } if from_fid != to_fid => Some(*from_fid),
+            _ => None,
+        }
+    }
+}
+
+// An action with its place in the primary's history
+#[derive(Encode, Decode, Debug, PartialEq)]
+pub struct LoggedAction {
+    // Assigned without gaps, though pruning may drop some from a diff
+    pub seq: u64,
+    // When it was logged: nanoseconds since the Unix epoch, and on the primary's
+    // CLOCK_MONOTONIC, which keeps its order even if the wall clock steps
+    pub wall_time_ns: u64,
+    pub mono_time_ns: u64,
+    pub action: StateDiffAction,
 }
 
-#[derive(Encode, Decode, Debug, Default)]
+#[derive(Encode, Decode, Debug, Default, PartialEq)]
 pub struct StateDiffLog {
-    pub fid_map: HashMap<u64, String>,
-    pub actions: Vec<StateDiffAction>,
+    // Identifies one run of the primary; sequence numbers start over with it
+    pub epoch: u64,
+    // Counts the diffs taken in this epoch, from 1
+    pub diff_seq: u64,
+    // The diff covers actions first_seq..next_seq, pruned or cleared ones included
+    pub first_seq: u64,
+    pub next_seq: u64,
+    // When the diff was taken, in nanoseconds since the Unix epoch
+    pub sent_wall_time_ns: u64,
+    // Fids first used since the last diff. Paths are relative to the mount root,
+    // as raw bytes so any Linux file name survives.
+    pub fid_map: HashMap<u64, Vec<u8>>,
+    // Fids whose path the diff leaves removed; they are dropped after it is applied
+    pub retired_fids: Vec<u64>,
+    pub actions: Vec<LoggedAction>,
+}
+
+impl StateDiffLog {
+    pub fn new() -> Self {
+        Self { epoch: wall_time_ns(), ..Default::default() }
+    }
+
+    pub fn push(&mut self, action: StateDiffAction) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.actions.push(LoggedAction { seq, wall_time_ns: wall_time_ns(), mono_time_ns: mono_time_ns(), action });
+    }
+
+    // Stamps the pending actions as the next diff, before it is encoded
+    pub fn begin_diff(&mut self) {
+        self.diff_seq += 1;
+        self.sent_wall_time_ns = wall_time_ns();
+    }
+
+    // The fids whose path the pending actions leave removed. They are retired on
+    // the primary once the diff is out, and on the replica once it has applied it.
+    pub fn removed_fids(&self) -> Vec<u64> {
+        // Whether each fid's last use removed its path
+        let mut removed_last: HashMap<u64, bool> = HashMap::new();
+        for entry in &self.actions {
+            let removed = entry.action.removed_fid();
+            for fid in entry.action.fids() {
+                removed_last.insert(fid, removed == Some(fid));
+            }
+        }
+        let mut removed: Vec<u64> = removed_last.into_iter().filter(|(_, removed)| *removed).map(|(fid, _)| fid).collect();
+        removed.sort_unstable();
+        removed
+    }
+
+    // Drops what a diff carried once it is out, and retires its removed fids in
+    // fids. Whatever was logged while it was being sent stays for the next diff,
+    // and a diff that never got out goes again as part of it, its fids still
+    // bound to the same paths.
+    pub fn complete_diff(&mut self, sent: &SentDiff, fids: &mut FidTable) {
+        self.actions.retain(|entry| entry.seq >= sent.next_seq);
+        for fid in &sent.fids {
+            self.fid_map.remove(fid);
+        }
+        // A removed fid used again while the diff was out stays bound here, but the
+        // replica drops it after applying the diff, so its binding goes again
+        let used: HashSet<u64> = self.actions.iter().flat_map(|entry| entry.action.fids()).collect();
+        for &fid in &sent.removed_fids {
+            match fids.path(fid) {
+                Some(path) if used.contains(&fid) => {
+                    self.fid_map.insert(fid, path.to_vec());
+                }
+                _ => fids.retire(fid),
+            }
+        }
+        self.retired_fids.clear();
+        self.first_seq = sent.next_seq;
+    }
+
+    // Starts a new epoch with nothing pending. Diff and sequence numbers start
+    // over, so a replica takes the next diff as coming from a restarted primary
+    // rather than refusing it for the actions dropped here. The caller starts a
+    // new FidTable along with it.
+    pub fn new_epoch(&mut self) {
+        let epoch = wall_time_ns().max(self.epoch + 1);
+        *self = Self { epoch, ..Default::default() };
+    }
+}
+
+// What a diff took from the log, to drop once it is out
+#[derive(Debug, Default)]
+pub struct SentDiff {
+    pub epoch: u64,
+    pub next_seq: u64,
+    // The bindings it carried
+    pub fids: Vec<u64>,
+    // removed_fids() when it was taken, including any pruning left out of it
+    pub removed_fids: Vec<u64>,
+}
+
+// Binds paths to fids. The primary and each replica keep one for the life of an
+// epoch, so a fid keeps its path across diffs and a diff only carries the
+// bindings it adds or retires. Fids are never reused within an epoch.
+#[derive(Debug)]
+pub struct FidTable {
+    paths: HashMap<u64, Vec<u8>>,
+    fids: HashMap<Vec<u8>, u64>,
+    next_fid: u64,
+}
+
+impl Default for FidTable {
+    fn default() -> Self {
+        Self { paths: HashMap::new(), fids: HashMap::new(), next_fid: 1 }
+    }
+}
+
+impl FidTable {
+    // The path's fid, and whether it was assigned just now
+    pub fn intern(&mut self, path: &[u8]) -> (u64, bool) {
+        if let Some(fid) = self.fids.get(path) {
+            return (*fid, false);
+        }
+        let fid = self.next_fid;
+        self.next_fid += 1;
+        self.paths.insert(fid, path.to_vec());
+        self.fids.insert(path.to_vec(), fid);
+        (fid, true)
+    }
+
+    // Adds a binding made elsewhere, replacing any older one for the fid or path
+    pub fn insert(&mut self, fid: u64, path: Vec<u8>) {
+        self.retire(fid);
+        if let Some(old_fid) = self.fids.remove(&path) {
+            self.paths.remove(&old_fid);
+        }
+        self.paths.insert(fid, path.clone());
+        self.fids.insert(path, fid);
+        self.next_fid = self.next_fid.max(fid + 1);
+    }
+
+    pub fn path(&self, fid: u64) -> Option<&[u8]> {
+        self.paths.get(&fid).map(Vec::as_slice)
+    }
+
+    pub fn bindings(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.paths.iter().map(|(fid, path)| (*fid, path.as_slice()))
+    }
+
+    pub fn retire(&mut self, fid: u64) {
+        if let Some(path) = self.paths.remove(&fid) {
+            self.fids.remove(&path);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+}
+
+pub fn wall_time_ns() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+
+pub fn mono_time_ns() -> u64 {
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
 }
\ No newline at end of file
@@ -0,0 +1,179 @@
+use crate::statediff::StateDiffLog;
+use bincode::config;
+use std::fmt;
+
+// Envelope around every StateDiffLog payload sent to replicas:
+//
+//   magic    4 bytes  "FLSD"
+//   version  u16 LE   FORMAT_VERSION of the encoded log
+//   flags    u16 LE   FLAG_* bits saying how the body is packed
+//   length   u64 LE   body length in bytes
+//   checksum u32 LE   CRC-32C of the body
+//   body     length bytes
+//
+// Readers refuse versions and flags they don't know rather than misreading them.
+// Any change to how StateDiffLog encodes must bump FORMAT_VERSION; the golden
+// files under tests/golden pin the current encoding.
+
+pub const MAGIC: [u8; 4] = *b"FLSD";
+pub const FORMAT_VERSION: u16 = 3;
+pub const HEADER_LEN: usize = 20;
+
+// Body is zstd compressed
+pub const FLAG_ZSTD: u16 = 1 << 0;
+// Compressed with the trained dictionary rather than plain zstd
+pub const FLAG_ZSTD_DICT: u16 = 1 << 1;
+// Body starts with that dictionary: a u32 LE length, then its bytes
+pub const FLAG_DICT_INCLUDED: u16 = 1 << 2;
+// The log is in the Protocol Buffers form of proto/statediff.proto, not bincode
+pub const FLAG_PROTOBUF: u16 = 1 << 3;
+// The log is in columnar.rs's stream layout; with FLAG_ZSTD its streams are
+// compressed one by one rather than the body as a whole
+pub const FLAG_COLUMNAR: u16 = 1 << 4;
+
+// fid_map holds every binding of the epoch, not only new ones; the replica
+// replaces its table with it
+pub const FLAG_FID_SNAPSHOT: u16 = 1 << 5;
+
+const KNOWN_FLAGS: u16 =
+    FLAG_ZSTD | FLAG_ZSTD_DICT | FLAG_DICT_INCLUDED | FLAG_PROTOBUF | FLAG_COLUMNAR | FLAG_FID_SNAPSHOT;
+
+// How a frame's body encodes the log, once decompressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Bincode,
+    Protobuf,
+    Columnar,
+}
+
+impl Encoding {
+    pub fn from_flags(flags: u16) -> Self {
+        if (flags & FLAG_COLUMNAR) != 0 {
+            Self::Columnar
+        } else if (flags & FLAG_PROTOBUF) != 0 {
+            Self::Protobuf
+        } else {
+            Self::Bincode
+        }
+    }
+
+    pub fn flags(self) -> u16 {
+        match self {
+            Self::Bincode => 0,
+            Self::Protobuf => FLAG_PROTOBUF,
+            Self::Columnar => FLAG_COLUMNAR,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameError {
+    Truncated { needed: usize, got: usize },
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u16),
+    UnknownFlags(u16),
+    LengthMismatch { header: u64, actual: usize },
+    ChecksumMismatch { header: u32, actual: u32 },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated { needed, got } => write!(f, "frame truncated: need {} bytes, got {}", needed, got),
+            Self::BadMagic(magic) => write!(f, "not a statediff frame (magic {:02x?})", magic),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "statediff format version {} is not supported (this build reads {})", version, FORMAT_VERSION)
+            }
+            Self::UnknownFlags(flags) => write!(f, "unknown frame flags 0x{:04x}", flags),
+            Self::LengthMismatch { header, actual } => {
+                write!(f, "frame says the body is {} bytes but {} followed", header, actual)
+            }
+            Self::ChecksumMismatch { header, actual } => {
+                write!(f, "body checksum 0x{:08x} does not match the header's 0x{:08x}", actual, header)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+#[derive(Debug)]
+pub struct Frame<'a> {
+    pub version: u16,
+    pub flags: u16,
+    pub body: &'a [u8],
+}
+
+pub fn encode_frame(flags: u16, body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    frame.extend_from_slice(&flags.to_le_bytes());
+    frame.extend_from_slice(&(body.len() as u64).to_le_bytes());
+    frame.extend_from_slice(&crc32c(body).to_le_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+pub fn decode_frame(buf: &[u8]) -> Result<Frame<'_>, FrameError> {
+    if buf.len() < HEADER_LEN {
+        return Err(FrameError::Truncated { needed: HEADER_LEN, got: buf.len() });
+    }
+    let magic: [u8; 4] = buf[0..4].try_into().unwrap();
+    if magic != MAGIC {
+        return Err(FrameError::BadMagic(magic));
+    }
+    let version = u16::from_le_bytes(buf[4..6].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(FrameError::UnsupportedVersion(version));
+    }
+    let flags = u16::from_le_bytes(buf[6..8].try_into().unwrap());
+    if (flags & !KNOWN_FLAGS) != 0 {
+        return Err(FrameError::UnknownFlags(flags & !KNOWN_FLAGS));
+    }
+    let length = u64::from_le_bytes(buf[8..16].try_into().unwrap());
+    let checksum = u32::from_le_bytes(buf[16..20].try_into().unwrap());
+
+    let body = &buf[HEADER_LEN..];
+    if length != body.len() as u64 {
+        return Err(FrameError::LengthMismatch { header: length, actual: body.len() });
+    }
+    let actual = crc32c(body);
+    if actual != checksum {
+        return Err(FrameError::ChecksumMismatch { header: checksum, actual });
+    }
+    Ok(Frame { version, flags, body })
+}
+
+pub fn encode_log(log: &StateDiffLog) -> Result<Vec<u8>, bincode::error::EncodeError> {
+    bincode::encode_to_vec(log, config::standard())
+}
+
+pub fn decode_log(bytes: &[u8]) -> Result<StateDiffLog, bincode::error::DecodeError> {
+    bincode::decode_from_slice(bytes, config::standard()).map(|(log, _)| log)
+}
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// CRC-32C (Castagnoli), as used by iSCSI and ext4
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
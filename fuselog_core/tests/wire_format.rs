@@ -0,0 +1,444 @@
+use fuselog_core::columnar::{self, ColumnarError};
+use fuselog_core::proto::{self, ProtoError};
+use fuselog_core::statediff::{FidTable, LoggedAction, SentDiff, StateDiffAction, StateDiffLog};
+use fuselog_core::wire::{self, Encoding, FrameError};
+use std::path::PathBuf;
+
+// Golden files pin the bytes a replica receives. When a change to StateDiffLog is
+// intended, bump wire::FORMAT_VERSION and regenerate them with
+// FUSELOG_UPDATE_GOLDEN=1 cargo test -p fuselog_core --test wire_format
+
+// One action of every kind, with fixed sequence numbers and times. A single fid
+// keeps the encoding deterministic, since fid_map is a HashMap.
+fn sample_log() -> StateDiffLog {
+    let mut log = StateDiffLog {
+        epoch: 1_700_000_000_000_000_000,
+        diff_seq: 3,
+        first_seq: 100,
+        sent_wall_time_ns: 1_700_000_001_000_000_000,
+        ..Default::default()
+    };
+    log.fid_map.insert(1, b"dir/file".to_vec());
+    log.retired_fids = vec![1];
+    let actions = vec![
+        StateDiffAction::Create { fid: 1, uid: 1000, gid: 1000, mode: 0o100644 },
+        StateDiffAction::Write { fid: 1, offset: 4096, data: b"hello".to_vec() },
+        StateDiffAction::Unlink { fid: 1 },
+        StateDiffAction::Rename { from_fid: 1, to_fid: 1, noreplace: true },
+        StateDiffAction::Exchange { a_fid: 1, b_fid: 1 },
+        StateDiffAction::Truncate { fid: 1, size: 123 },
+        StateDiffAction::Link { source_fid: 1, new_link_fid: 1 },
+        StateDiffAction::Chown { fid: 1, uid: 0, gid: 0 },
+        StateDiffAction::Chmod { fid: 1, mode: 0o4755 },
+        StateDiffAction::Mkdir { fid: 1 },
+        StateDiffAction::Rmdir { fid: 1 },
+        StateDiffAction::Symlink { link_fid: 1, target_path: b"../target".to_vec(), uid: 1, gid: 2 },
+        StateDiffAction::SetXattr { fid: 1, name: b"user.test".to_vec(), value: vec![0, 1, 2] },
+        StateDiffAction::RemoveXattr { fid: 1, name: b"user.test".to_vec() },
+        StateDiffAction::SetTimes { fid: 1, atime: Some((1_700_000_000, 5)), mtime: None },
+        StateDiffAction::Fallocate { fid: 1, mode: 0, offset: 0, length: 1 << 20 },
+        StateDiffAction::PunchHole { fid: 1, offset: 512, length: 512 },
+        StateDiffAction::CopyRange { src_fid: 1, src_off: 0, dst_fid: 1, dst_off: 8192, len: 100 },
+        StateDiffAction::Mknod { fid: 1, mode: 0o010644, rdev: 0, uid: 0, gid: 0 },
+        StateDiffAction::CopyUp { fid: 1, size: 42 },
+    ];
+    for (i, action) in actions.into_iter().enumerate() {
+        let seq = 100 + i as u64;
+        let (wall_time_ns, mono_time_ns) = (1_700_000_000_500_000_000 + seq, 5_000 + seq);
+        log.actions.push(LoggedAction { seq, wall_time_ns, mono_time_ns, action });
+    }
+    // One action was pruned from the end
+    log.next_seq = 100 + log.actions.len() as u64 + 1;
+    log
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name)
+}
+
+fn golden_name() -> String {
+    format!("statediff_v{}.bin", wire::FORMAT_VERSION)
+}
+
+fn protobuf_golden_name() -> String {
+    format!("statediff_v{}.pb", wire::FORMAT_VERSION)
+}
+
+fn columnar_golden_name() -> String {
+    format!("statediff_v{}.col", wire::FORMAT_VERSION)
+}
+
+fn check_golden(name: &str, bytes: &[u8]) {
+    let path = golden_path(name);
+    if std::env::var("FUSELOG_UPDATE_GOLDEN").is_ok_and(|val| val == "1") {
+        std::fs::write(&path, bytes).unwrap();
+        return;
+    }
+    let golden = std::fs::read(&path).unwrap_or_else(|e| panic!("reading {:?}: {}", path, e));
+    assert!(
+        golden == bytes,
+        "encoding of {} changed; bump FORMAT_VERSION and regenerate the golden files if that is intended",
+        name
+    );
+}
+
+fn raw_frame() -> Vec<u8> {
+    wire::encode_frame(0, &wire::encode_log(&sample_log()).unwrap())
+}
+
+#[test]
+fn raw_frame_matches_golden() {
+    check_golden(&golden_name(), &raw_frame());
+}
+
+fn protobuf_frame() -> Vec<u8> {
+    wire::encode_frame(wire::FLAG_PROTOBUF, &proto::encode_log(&sample_log()))
+}
+
+#[test]
+fn protobuf_frame_matches_golden() {
+    check_golden(&protobuf_golden_name(), &protobuf_frame());
+}
+
+fn columnar_frame() -> Vec<u8> {
+    wire::encode_frame(wire::FLAG_COLUMNAR, &columnar::encode_log(&sample_log(), None).unwrap())
+}
+
+#[test]
+fn columnar_frame_matches_golden() {
+    check_golden(&columnar_golden_name(), &columnar_frame());
+}
+
+#[test]
+fn golden_frame_decodes_to_sample_log() {
+    let golden = std::fs::read(golden_path(&golden_name())).unwrap();
+    let frame = wire::decode_frame(&golden).unwrap();
+    assert_eq!(frame.version, wire::FORMAT_VERSION);
+    assert_eq!(frame.flags, 0);
+    assert_eq!(wire::decode_log(frame.body).unwrap(), sample_log());
+}
+
+#[test]
+fn header_layout() {
+    let body = b"some body";
+    let frame = wire::encode_frame(wire::FLAG_ZSTD, body);
+    assert_eq!(frame.len(), wire::HEADER_LEN + body.len());
+    assert_eq!(&frame[0..4], b"FLSD");
+    assert_eq!(&frame[4..6], &wire::FORMAT_VERSION.to_le_bytes());
+    assert_eq!(&frame[6..8], &wire::FLAG_ZSTD.to_le_bytes());
+    assert_eq!(&frame[8..16], &(body.len() as u64).to_le_bytes());
+    assert_eq!(&frame[16..20], &wire::crc32c(body).to_le_bytes());
+    assert_eq!(&frame[20..], body);
+}
+
+#[test]
+fn crc32c_check_value() {
+    assert_eq!(wire::crc32c(b"123456789"), 0xe306_9283);
+    assert_eq!(wire::crc32c(b""), 0);
+}
+
+#[test]
+fn compressed_frame_round_trips() {
+    let bincode_data = wire::encode_log(&sample_log()).unwrap();
+    let frame = wire::encode_frame(wire::FLAG_ZSTD, &zstd::encode_all(&bincode_data[..], 3).unwrap());
+    let decoded = wire::decode_frame(&frame).unwrap();
+    assert_eq!(decoded.flags, wire::FLAG_ZSTD);
+    let log = wire::decode_log(&zstd::decode_all(decoded.body).unwrap()).unwrap();
+    assert_eq!(log, sample_log());
+}
+
+#[test]
+fn rejects_bad_magic() {
+    let mut frame = raw_frame();
+    frame[0] = b'X';
+    assert!(matches!(wire::decode_frame(&frame), Err(FrameError::BadMagic(_))));
+    // The old one-byte headers are not frames
+    assert!(matches!(wire::decode_frame(b"n\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00"), Err(FrameError::BadMagic(_))));
+}
+
+#[test]
+fn rejects_other_versions() {
+    let mut frame = raw_frame();
+    frame[4..6].copy_from_slice(&(wire::FORMAT_VERSION + 1).to_le_bytes());
+    assert_eq!(wire::decode_frame(&frame).unwrap_err(), FrameError::UnsupportedVersion(wire::FORMAT_VERSION + 1));
+}
+
+#[test]
+fn rejects_unknown_flags() {
+    let mut frame = raw_frame();
+    frame[6..8].copy_from_slice(&0x8000u16.to_le_bytes());
+    assert_eq!(wire::decode_frame(&frame).unwrap_err(), FrameError::UnknownFlags(0x8000));
+}
+
+#[test]
+fn accepts_fid_snapshots() {
+    let frame = wire::encode_frame(wire::FLAG_FID_SNAPSHOT | wire::FLAG_COLUMNAR, &columnar::encode_log(&sample_log(), None).unwrap());
+    let decoded = wire::decode_frame(&frame).unwrap();
+    assert_ne!(decoded.flags & wire::FLAG_FID_SNAPSHOT, 0);
+    assert_eq!(Encoding::from_flags(decoded.flags), Encoding::Columnar);
+}
+
+// Logs an action the way the primary does, binding new fids in the diff
+fn log_action(log: &mut StateDiffLog, fids: &mut FidTable, path: &[u8], action: impl Fn(u64) -> StateDiffAction) -> u64 {
+    let (fid, is_new) = fids.intern(path);
+    if is_new {
+        log.fid_map.insert(fid, path.to_vec());
+    }
+    log.push(action(fid));
+    fid
+}
+
+// Takes the pending actions as a diff, the way send_statediff does
+fn take_diff(log: &mut StateDiffLog) -> SentDiff {
+    log.begin_diff();
+    let removed_fids = log.removed_fids();
+    log.retired_fids = removed_fids.clone();
+    SentDiff { epoch: log.epoch, next_seq: log.next_seq, fids: log.fid_map.keys().copied().collect(), removed_fids }
+}
+
+// Applies a diff's bindings and retirements the way fuselog_apply does, checking
+// every fid its actions use resolves
+fn replicate(log: &StateDiffLog, replica: &mut FidTable) {
+    for (fid, path) in &log.fid_map {
+        replica.insert(*fid, path.clone());
+    }
+    for entry in &log.actions {
+        for fid in entry.action.fids() {
+            assert!(replica.path(fid).is_some(), "fid {} of seq {} is unbound", fid, entry.seq);
+        }
+    }
+    for fid in &log.retired_fids {
+        replica.retire(*fid);
+    }
+}
+
+// A diff leaves the log only once sent, and takes nothing logged meanwhile
+#[test]
+fn completed_diff_keeps_later_actions() {
+    let (mut log, mut fids) = (StateDiffLog::new(), FidTable::default());
+    log_action(&mut log, &mut fids, b"a", |fid| StateDiffAction::Mkdir { fid });
+    let sent = take_diff(&mut log);
+
+    log_action(&mut log, &mut fids, b"a/b", |fid| StateDiffAction::Mkdir { fid });
+    log.complete_diff(&sent, &mut fids);
+
+    assert_eq!(log.first_seq, 1);
+    assert_eq!(log.actions.len(), 1);
+    assert_eq!(log.actions[0].seq, 1);
+    assert_eq!(log.fid_map.keys().collect::<Vec<_>>(), vec![&2]);
+}
+
+// A path removed in a diff that failed to go out, then used again before the
+// retry, keeps its fid, so the retry binds the path once
+#[test]
+fn failed_send_keeps_removed_fids_bound() {
+    let (mut log, mut fids, mut replica) = (StateDiffLog::new(), FidTable::default(), FidTable::default());
+    let fid = log_action(&mut log, &mut fids, b"p", |fid| StateDiffAction::Create { fid, uid: 0, gid: 0, mode: 0o644 });
+    log_action(&mut log, &mut fids, b"p", |fid| StateDiffAction::Unlink { fid });
+    take_diff(&mut log);
+
+    // The send failed; p comes back before the retry
+    let reused = log_action(&mut log, &mut fids, b"p", |fid| StateDiffAction::Create { fid, uid: 0, gid: 0, mode: 0o600 });
+    assert_eq!(reused, fid);
+
+    let sent = take_diff(&mut log);
+    assert_eq!(log.fid_map.len(), 1);
+    assert!(log.retired_fids.is_empty());
+    replicate(&log, &mut replica);
+    log.complete_diff(&sent, &mut fids);
+    assert_eq!(fids.path(fid), Some(&b"p"[..]));
+    assert_eq!(replica.path(fid), Some(&b"p"[..]));
+}
+
+// A path removed in a diff and used again while it was being sent goes out bound
+// again in the next, since the replica retires it with the first
+#[test]
+fn fid_reused_during_send_is_bound_again() {
+    let (mut log, mut fids, mut replica) = (StateDiffLog::new(), FidTable::default(), FidTable::default());
+    let fid = log_action(&mut log, &mut fids, b"p", |fid| StateDiffAction::Create { fid, uid: 0, gid: 0, mode: 0o644 });
+    log_action(&mut log, &mut fids, b"p", |fid| StateDiffAction::Unlink { fid });
+    let sent = take_diff(&mut log);
+    replicate(&log, &mut replica);
+    assert_eq!(replica.path(fid), None);
+
+    log_action(&mut log, &mut fids, b"p", |fid| StateDiffAction::Mkdir { fid });
+    log.complete_diff(&sent, &mut fids);
+    take_diff(&mut log);
+    assert_eq!(log.fid_map.get(&fid), Some(&b"p".to_vec()));
+    replicate(&log, &mut replica);
+
+    // Once unused it is retired like any other
+    let (mut log, mut fids) = (StateDiffLog::new(), FidTable::default());
+    let fid = log_action(&mut log, &mut fids, b"q", |fid| StateDiffAction::Mkdir { fid });
+    log_action(&mut log, &mut fids, b"q", |fid| StateDiffAction::Rmdir { fid });
+    let sent = take_diff(&mut log);
+    log.complete_diff(&sent, &mut fids);
+    assert_eq!(fids.path(fid), None);
+    assert_ne!(fids.intern(b"q").0, fid);
+}
+
+#[test]
+fn rejects_truncation() {
+    let frame = raw_frame();
+    assert!(matches!(wire::decode_frame(&frame[..10]), Err(FrameError::Truncated { .. })));
+    assert!(matches!(wire::decode_frame(&frame[..frame.len() - 1]), Err(FrameError::LengthMismatch { .. })));
+}
+
+#[test]
+fn rejects_corrupted_body() {
+    let mut frame = raw_frame();
+    let last = frame.len() - 1;
+    frame[last] ^= 0x01;
+    assert!(matches!(wire::decode_frame(&frame), Err(FrameError::ChecksumMismatch { .. })));
+}
+
+// Round trips through every encoding must give back the same log
+fn assert_encodings_agree(log: &StateDiffLog) {
+    let from_bincode = wire::decode_log(&wire::encode_log(log).unwrap()).unwrap();
+    assert_eq!(&from_bincode, log);
+    assert_eq!(proto::decode_log(&proto::encode_log(log)).unwrap(), from_bincode);
+    assert_eq!(columnar::decode_log(&columnar::encode_log(log, None).unwrap(), false).unwrap(), from_bincode);
+    assert_eq!(columnar::decode_log(&columnar::encode_log(log, Some(3)).unwrap(), true).unwrap(), from_bincode);
+}
+
+// Extremes of every kind of field
+fn edge_log() -> StateDiffLog {
+    let mut log = StateDiffLog { epoch: u64::MAX, next_seq: 3, ..Default::default() };
+    log.fid_map.insert(u64::MAX, vec![0xff, b'/', 0x80]);
+    log.fid_map.insert(2, Vec::new());
+    log.retired_fids = vec![0, 1 << 35, u64::MAX];
+    let actions = vec![
+        // Negative fields go out sign extended
+        StateDiffAction::Fallocate { fid: u64::MAX, mode: -1, offset: 0, length: 0 },
+        // Present but zero times differ from absent ones
+        StateDiffAction::SetTimes { fid: 0, atime: Some((0, 0)), mtime: Some((-1, 999_999_999)) },
+        StateDiffAction::SetTimes { fid: 0, atime: None, mtime: Some((i64::MIN, u32::MAX)) },
+        StateDiffAction::SetXattr { fid: 2, name: Vec::new(), value: Vec::new() },
+        // Xattr names are bytes, not UTF-8 strings
+        StateDiffAction::RemoveXattr { fid: 2, name: b"user.\xff\x80".to_vec() },
+        StateDiffAction::Chown { fid: 2, uid: u32::MAX, gid: 0 },
+        StateDiffAction::Rename { from_fid: 2, to_fid: 2, noreplace: false },
+    ];
+    for (seq, action) in actions.into_iter().enumerate() {
+        log.actions.push(LoggedAction { seq: seq as u64, wall_time_ns: u64::MAX, mono_time_ns: 0, action });
+    }
+    log
+}
+
+#[test]
+fn encodings_round_trip_like_bincode() {
+    assert_encodings_agree(&sample_log());
+    assert_encodings_agree(&edge_log());
+    assert_encodings_agree(&StateDiffLog::default());
+}
+
+#[test]
+fn protobuf_wire_layout() {
+    let log = StateDiffLog {
+        epoch: 1,
+        actions: vec![LoggedAction { seq: 0, wall_time_ns: 0, mono_time_ns: 0, action: StateDiffAction::Unlink { fid: 5 } }],
+        ..Default::default()
+    };
+    // epoch = 1; actions { action { unlink { fid: 5 } } }
+    assert_eq!(proto::encode_log(&log), [0x08, 0x01, 0x42, 0x06, 0x22, 0x04, 0x1a, 0x02, 0x08, 0x05]);
+}
+
+#[test]
+fn protobuf_skips_unknown_fields() {
+    let mut encoded = proto::encode_log(&sample_log());
+    // A varint field 99, a fixed64 field 100 and a bytes field 101
+    encoded.extend_from_slice(&[0x98, 0x06, 0x2a]);
+    encoded.extend_from_slice(&[0xa1, 0x06, 1, 2, 3, 4, 5, 6, 7, 8]);
+    encoded.extend_from_slice(&[0xaa, 0x06, 0x02, b'h', b'i']);
+    assert_eq!(proto::decode_log(&encoded).unwrap(), sample_log());
+}
+
+#[test]
+fn protobuf_rejects_malformed_input() {
+    let encoded = proto::encode_log(&sample_log());
+    assert_eq!(proto::decode_log(&encoded[..encoded.len() - 1]).unwrap_err(), ProtoError::Truncated);
+    let mut long_varint = vec![0x08];
+    long_varint.extend_from_slice(&[0xff; 10]);
+    long_varint.push(0x01);
+    assert_eq!(proto::decode_log(&long_varint).unwrap_err(), ProtoError::VarintTooLong);
+    // Wire type 3, the deprecated group start
+    assert_eq!(proto::decode_log(&[0x0b]).unwrap_err(), ProtoError::UnsupportedWireType(3));
+    // epoch sent as bytes
+    assert_eq!(proto::decode_log(&[0x0a, 0x00]).unwrap_err(), ProtoError::WrongWireType(1));
+    // Action 7, whose oneof holds only an unknown case (field 63)
+    assert_eq!(proto::decode_log(&[0x42, 0x07, 0x08, 0x07, 0x22, 0x03, 0xfa, 0x03, 0x00]).unwrap_err(), ProtoError::MissingAction(7));
+}
+
+#[test]
+fn protobuf_frame_is_flagged() {
+    let frame = protobuf_frame();
+    let decoded = wire::decode_frame(&frame).unwrap();
+    assert_eq!(Encoding::from_flags(decoded.flags), Encoding::Protobuf);
+    assert_eq!(proto::decode_log(decoded.body).unwrap(), sample_log());
+    assert_eq!(Encoding::from_flags(wire::decode_frame(&raw_frame()).unwrap().flags), Encoding::Bincode);
+}
+
+#[test]
+fn columnar_ignores_fid_map_order() {
+    let mut forward = StateDiffLog::default();
+    let mut backward = StateDiffLog::default();
+    for fid in 1..=64 {
+        forward.fid_map.insert(fid, format!("dir/{}", fid).into_bytes());
+    }
+    for fid in (1..=64).rev() {
+        backward.fid_map.insert(fid, format!("dir/{}", fid).into_bytes());
+    }
+    assert_eq!(columnar::encode_log(&forward, None).unwrap(), columnar::encode_log(&backward, None).unwrap());
+    assert_eq!(proto::encode_log(&forward), proto::encode_log(&backward));
+}
+
+#[test]
+fn columnar_keeps_write_data_apart() {
+    let log = StateDiffLog {
+        actions: (0..4)
+            .map(|i| LoggedAction {
+                seq: i,
+                wall_time_ns: 0,
+                mono_time_ns: 0,
+                action: StateDiffAction::Write { fid: 1, offset: i * 4, data: b"abcd".to_vec() },
+            })
+            .collect(),
+        ..Default::default()
+    };
+    let body = columnar::encode_log(&log, None).unwrap();
+    // The four writes' data ends the body back to back
+    assert!(body.ends_with(b"abcdabcdabcdabcd"));
+}
+
+#[test]
+fn columnar_rejects_malformed_input() {
+    let body = columnar::encode_log(&sample_log(), None).unwrap();
+    assert!(matches!(columnar::decode_log(&body[..body.len() - 1], false), Err(ColumnarError::Truncated(_))));
+    assert!(matches!(columnar::decode_log(&[2, 0, 0], false), Err(ColumnarError::TooFewStreams(2))));
+
+    let mut extra = body.clone();
+    extra.push(0);
+    assert!(matches!(columnar::decode_log(&extra, false), Err(ColumnarError::TrailingBytes(_))));
+
+    // One unlink with opcode 99 in place of 3
+    let log = StateDiffLog {
+        actions: vec![LoggedAction { seq: 0, wall_time_ns: 0, mono_time_ns: 0, action: StateDiffAction::Unlink { fid: 1 } }],
+        ..Default::default()
+    };
+    let mut body = columnar::encode_log(&log, None).unwrap();
+    // After the stream count, nine one-byte lengths and eight header varints
+    assert_eq!(body[18], 3);
+    body[18] = 99;
+    assert!(matches!(columnar::decode_log(&body, false), Err(ColumnarError::UnknownOpcode(99))));
+
+    let compressed = columnar::encode_log(&sample_log(), Some(3)).unwrap();
+    assert!(columnar::decode_log(&compressed, false).is_err());
+}
+
+#[test]
+fn columnar_frame_is_flagged() {
+    let frame = columnar_frame();
+    let decoded = wire::decode_frame(&frame).unwrap();
+    assert_eq!(Encoding::from_flags(decoded.flags), Encoding::Columnar);
+    assert_eq!(columnar::decode_log(decoded.body, false).unwrap(), sample_log());
+}
@@ -15,7 +15,21 @@ fn run() -> Result<(), Box<dyn std::error::Error>> {
     let mut stream = UnixStream::connect(SOCKET_PATH)
         .map_err(|e| format!("Failed to connect to socket: {}", e))?;
 
-    stream.write_all(&[b'g'])?;
+    // --resync makes the diff carry the whole fid table, for a replica that has
+    // restarted or skipped a gap
+    if std::env::args().any(|arg| arg == "--resync") {
+        stream.write_all(b"f")?;
+    }
+
+    // --protobuf and --columnar ask for those encodings instead of bincode
+    let command = if std::env::args().any(|arg| arg == "--protobuf") {
+        b"p"
+    } else if std::env::args().any(|arg| arg == "--columnar") {
+        b"s"
+    } else {
+        b"g"
+    };
+    stream.write_all(command)?;
 
     let mut size_buf = [0u8; 8];
     stream.read_exact(&mut size_buf)?;